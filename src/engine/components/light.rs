@@ -0,0 +1,74 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    /// Shines in every direction from the entity's position
+    Point,
+
+    /// Shines along the entity's -Z axis from infinitely far away, like a sun
+    Directional,
+
+    /// A cone along the entity's -Z axis. Angles are in radians from the axis, and the
+    /// light fades out between the inner and the outer angle.
+    Spot {
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+}
+
+/// A light placed by the entity's `TransformComponent`. Point and spot lights fade out
+/// with distance and reach nothing past `range`.
+#[derive(Debug, Clone)]
+pub struct LightComponent {
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    pub visible: bool,
+
+    /// Whether meshes block this light. Only directional and point lights cast shadows.
+    pub casts_shadows: bool,
+}
+
+impl LightComponent {
+    pub fn point(color: [f32; 3], intensity: f32, range: f32) -> LightComponent {
+        LightComponent {
+            kind: LightKind::Point,
+            color,
+            intensity,
+            range,
+            visible: true,
+            casts_shadows: false,
+        }
+    }
+
+    pub fn directional(color: [f32; 3], intensity: f32) -> LightComponent {
+        LightComponent {
+            kind: LightKind::Directional,
+            color,
+            intensity,
+            range: std::f32::INFINITY,
+            visible: true,
+            casts_shadows: false,
+        }
+    }
+
+    pub fn spot(color: [f32; 3], intensity: f32, range: f32, inner_angle: f32, outer_angle: f32)
+    -> LightComponent {
+        LightComponent {
+            kind: LightKind::Spot { inner_angle, outer_angle },
+            color,
+            intensity,
+            range,
+            visible: true,
+            casts_shadows: false,
+        }
+    }
+
+    pub fn with_shadows(mut self) -> LightComponent {
+        self.casts_shadows = true;
+        self
+    }
+}
+
+impl specs::Component for LightComponent {
+    type Storage = specs::VecStorage<Self>;
+}
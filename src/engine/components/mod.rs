@@ -10,3 +10,7 @@ mod scripting;
 pub use scripting::ScriptingComponent;
 mod keyboard;
 pub use keyboard::KeyboardComponent;
+mod light;
+pub use light::{LightComponent, LightKind};
+mod text;
+pub use text::{TextComponent, TextAnchor, TextAlign, TextStyle};
@@ -2,6 +2,14 @@ pub struct ScriptingComponent {
     pub path: String,
     pub object_self: rhai::Map,
     pub initialized: bool,
+
+    // Failed calls so far. The script stops running once this hits SCRIPT_MAX_FAILURES.
+    pub failures: u32,
+    pub disabled: bool,
+    pub last_error: Option<String>,
+
+    // Which compilation of the script the failures were counted against. A reload starts over.
+    pub revision: u32,
 }
 
 impl ScriptingComponent {
@@ -10,6 +18,10 @@ impl ScriptingComponent {
             path: String::from(path),
             object_self: rhai::Map::new(),
             initialized: false,
+            failures: 0,
+            disabled: false,
+            last_error: None,
+            revision: 0,
         }
     }
 }
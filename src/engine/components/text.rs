@@ -0,0 +1,87 @@
+use crate::engine::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+}
+
+/// Where a label is drawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextAnchor {
+    /// In pixels from the top left of the window
+    Screen([f32; 2]),
+
+    /// Relative to the entity's `TransformComponent`, and follows it on screen
+    World([f32; 3]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextStyle {
+    /// Height of a line in pixels
+    pub size: f32,
+    pub color: [f32; 4],
+
+    /// Lines are aligned against the anchor: left aligned text starts at it
+    pub align: TextAlign,
+
+    /// Lines longer than this many pixels are broken at spaces
+    pub wrap_width: Option<f32>,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        TextStyle {
+            size: consts::DEFAULT_TEXT_SIZE,
+            color: [1.0, 1.0, 1.0, 1.0],
+            align: TextAlign::Left,
+            wrap_width: None,
+        }
+    }
+}
+
+/// A label, drawn over the scene in the bundled font
+#[derive(Debug, Clone)]
+pub struct TextComponent {
+    pub text: String,
+
+    /// `text` is a localization key, translated to the current language every frame
+    pub localized: bool,
+    pub anchor: TextAnchor,
+    pub style: TextStyle,
+    pub visible: bool,
+}
+
+impl TextComponent {
+    pub fn screen(text: &str, position: [f32; 2]) -> TextComponent {
+        TextComponent {
+            text: String::from(text),
+            localized: false,
+            anchor: TextAnchor::Screen(position),
+            style: TextStyle::default(),
+            visible: true,
+        }
+    }
+
+    pub fn world(text: &str, offset: [f32; 3]) -> TextComponent {
+        TextComponent {
+            anchor: TextAnchor::World(offset),
+            ..TextComponent::screen(text, [0.0, 0.0])
+        }
+    }
+
+    pub fn localized(mut self) -> TextComponent {
+        self.localized = true;
+        self
+    }
+
+    pub fn with_style(mut self, style: TextStyle) -> TextComponent {
+        self.style = style;
+        self
+    }
+}
+
+impl specs::Component for TextComponent {
+    type Storage = specs::VecStorage<Self>;
+}
@@ -1,5 +1,7 @@
 use crate::engine::prelude::*;
 use std::collections::BTreeMap;
+use crate::engine::graphics::{PostProcessSettings, ShadowQuality, ShadowSettings, Tonemapper, WindowMode,
+    WindowSettings};
 
 // Define a struct that can be created at runtime from a string.
 macro_rules! deserializable_struct {
@@ -47,10 +49,64 @@ deserializable_struct! {
     pub struct Config {
         resolution_x: u32 = consts::DEFAULT_RESOLUTION[0],
         resolution_y: u32 = consts::DEFAULT_RESOLUTION[1],
+        window_mode: WindowMode = WindowMode::Windowed,
+        monitor: usize = 0,
+        resizable: bool = true,
+        render_scale: f32 = 1.0,
+        trace_game_events: bool = false,
+        language: String = String::from(consts::DEFAULT_LANGUAGE),
+        cache_dir: String = String::new(),
+        max_lights: usize = consts::DEFAULT_MAX_LIGHTS,
+        shadow_quality: ShadowQuality = ShadowQuality::Medium,
+        shadow_bias: f32 = consts::DEFAULT_SHADOW_BIAS,
+        shadow_normal_bias: f32 = consts::DEFAULT_SHADOW_NORMAL_BIAS,
+        bloom: bool = true,
+        bloom_intensity: f32 = consts::DEFAULT_BLOOM_INTENSITY,
+        tonemapper: Tonemapper = Tonemapper::Aces,
+        gamma: f32 = consts::DEFAULT_GAMMA,
+        auto_exposure: bool = true,
+        exposure: f32 = 1.0,
     }
 }
 
 impl Config {
+    pub fn window_settings(&self) -> WindowSettings {
+        WindowSettings {
+            mode: self.window_mode,
+            resolution: [self.resolution_x, self.resolution_y],
+            monitor: self.monitor,
+            resizable: self.resizable,
+        }
+    }
+
+    pub fn post_process(&self) -> PostProcessSettings {
+        PostProcessSettings {
+            bloom: self.bloom,
+            bloom_intensity: self.bloom_intensity,
+            tonemapper: self.tonemapper,
+            gamma: self.gamma,
+            auto_exposure: self.auto_exposure,
+            exposure: self.exposure,
+        }
+    }
+
+    pub fn shadows(&self) -> ShadowSettings {
+        ShadowSettings {
+            quality: self.shadow_quality,
+            bias: self.shadow_bias,
+            normal_bias: self.shadow_normal_bias,
+        }
+    }
+
+    /// Empty keeps the caches next to the assets they're built from
+    pub fn cache_dir(&self) -> Option<std::path::PathBuf> {
+        if self.cache_dir.is_empty() {
+            None
+        } else {
+            Some(std::path::PathBuf::from(&self.cache_dir))
+        }
+    }
+
     // Reads the configuration file. If it's invalid, default values are loaded instead.
     pub fn load() -> Config {
         if let Ok(lines) = utils::read_file_lines(consts::CONFIG_FILE_PATH) {
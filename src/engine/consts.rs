@@ -1,4 +1,6 @@
 pub const SCRIPT_FILE_EXTENSION: &str = "rhai";
+pub const SCRIPTS_PATH: &str = "./scripts";
+pub const SCRIPT_TESTS_FOLDER: &str = "tests";
 pub const SUPPORTED_SOUND_EXTENSIONS: &[&str] = &["wav", "ogg", "mp3", "flac"];
 pub const CONFIG_FILE_PATH: &str = "./config.ini";
 pub const ICON_PATH: &str = "./resources/icon.ico";
@@ -8,14 +10,95 @@ pub const WINDOW_NAME: &str = "Space War Supreme!";
 
 pub const DEFAULT_RESOLUTION: [u32; 2] = [1280, 720];
 pub const DEFAULT_ASPECT_RATIO: f32 = DEFAULT_RESOLUTION[0] as f32 / DEFAULT_RESOLUTION[1] as f32;
+
+// The scene's resolution relative to the window's
+pub const MIN_RENDER_SCALE: f32 = 0.25;
+pub const MAX_RENDER_SCALE: f32 = 2.0;
+
 pub const DEFAULT_VERTICAL_FOV_DEG: f32 = 65.0;
 pub const DEFAULT_NEAR_CLIP: f32 = 0.01;
 pub const DEFAULT_FAR_CLIP: f32 = 10000.0;
 pub const DEFAULT_INSTANCE_BUFFER_SIZE: usize = 65536;
-pub const DEFAULT_MAX_LIGHTS: usize = 2;
+pub const MAX_INSTANCE_BUFFER_SIZE: usize = 1 << 20;
+pub const DEFAULT_MAX_LIGHTS: usize = 8;
+pub const MAX_LIGHTS_LIMIT: usize = 64;
+pub const BLOOM_LEVELS: usize = 5;
+pub const BLOOM_THRESHOLD: f32 = 1.0;
+pub const DEFAULT_BLOOM_INTENSITY: f32 = 0.04;
+pub const DEFAULT_GAMMA: f32 = 2.2;
+pub const LUMINANCE_SIZE: u32 = 64;
+pub const EXPOSURE_ADAPTATION_SPEED: f32 = 1.5;
+pub const FONT_PATH: &str = "./resources/fonts/SometypeMono/sometypemono.ttf";
+pub const DEFAULT_TEXT_SIZE: f32 = 24.0;
+pub const TEXT_ATLAS_SIZE: u32 = 512;
+pub const TEXT_ATLAS_MAX_SIZE: u32 = 4096;
+pub const UI_TEXT_SIZE: f32 = 20.0;
+pub const UI_LIST_ROW_HEIGHT: f32 = 28.0;
+pub const UI_CHECKBOX_ON_PATH: &str = "./resources/ui/checkbox_on.png";
+pub const UI_CHECKBOX_OFF_PATH: &str = "./resources/ui/checkbox_off.png";
+pub const IBL_IRRADIANCE_SIZE: u32 = 32;
+pub const IBL_PREFILTERED_SIZE: u32 = 128;
+pub const IBL_PREFILTERED_LEVELS: u32 = 5;
+pub const IBL_CACHE_EXTENSION: &str = ".ibl.cache";
+pub const IBL_CACHE_MAGIC: &[u8; 4] = b"SWSI";
+// Bump when `CachedEnvironment` or the IBL shaders change
+pub const IBL_CACHE_VERSION: u32 = 1;
+pub const BRDF_LUT_SIZE: u32 = 256;
+pub const BRDF_LUT_CACHE_PATH: &str = "./resources/brdf_lut.cache";
+pub const BRDF_LUT_CACHE_MAGIC: &[u8; 4] = b"SWSB";
+pub const BRDF_LUT_CACHE_VERSION: u32 = 1;
+pub const MODEL_CACHE_EXTENSION: &str = ".cache";
+pub const MODEL_CACHE_MAGIC: &[u8; 4] = b"SWSM";
+// Bump when `Vertex` or `CachedModel` change, so old model caches are rebuilt
+pub const MODEL_CACHE_VERSION: u32 = 1;
+pub const TEXTURE_CACHE_PATH: &str = "./resources/texture_cache";
+pub const TEXTURE_CACHE_EXTENSION: &str = ".tex.cache";
+pub const TEXTURE_CACHE_MAGIC: &[u8; 4] = b"SWST";
+pub const TEXTURE_CACHE_VERSION: u32 = 1;
+pub const MAX_SHADOW_CASCADES: usize = 4;
+pub const MAX_SHADOWED_POINT_LIGHTS: usize = 4;
+pub const MAX_SHADOW_VIEWS: usize = MAX_SHADOW_CASCADES + 6 * MAX_SHADOWED_POINT_LIGHTS;
+
+// How far from the camera directional lights cast shadows, and how the cascades split it
+pub const SHADOW_DISTANCE: f32 = 50.0;
+pub const SHADOW_CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+
+// How far behind a cascade meshes still cast shadows into it
+pub const SHADOW_CASTER_DISTANCE: f32 = 100.0;
+pub const POINT_SHADOW_NEAR_CLIP: f32 = 0.01;
+pub const POINT_SHADOW_MAX_RANGE: f32 = 100.0;
+pub const DEFAULT_SHADOW_BIAS: f32 = 0.0005;
+pub const DEFAULT_SHADOW_NORMAL_BIAS: f32 = 0.01;
 
 pub const MULTI_SKYBOX_WARNING_INTERVAL_SECONDS: f32 = 60.0;
 pub const LOCALIZATION_PATH: &str = "./resources/localization";
 pub const LOCALIZATION_EXTENSION: &str = "json";
+pub const DEFAULT_LANGUAGE: &str = "English";
+// Keys the scripts may use without translating them, one per line
+pub const LOCALIZATION_ALLOWLIST_PATH: &str = "./resources/localization/allowlist.txt";
+pub const LOCALIZATION_REPORT_PATH: &str = "./localization_report.txt";
 pub const MAX_LOG_LINES: usize = 1000;
-pub const CRASH_REPORTS_PATH: &str = "./crash_reports";
\ No newline at end of file
+pub const CRASH_REPORTS_PATH: &str = "./crash_reports";
+pub const SCREENSHOTS_PATH: &str = "./screenshots";
+pub const GOLDEN_IMAGES_PATH: &str = "./tests/golden";
+pub const GOLDEN_OUTPUT_PATH: &str = "./target/golden";
+pub const GOLDEN_RESOLUTION: [u32; 2] = [256, 256];
+pub const GOLDEN_CHANNEL_TOLERANCE: u8 = 8;
+pub const GOLDEN_MAX_DIFFERING_PIXELS: f64 = 0.002;
+
+pub const SCRIPT_MAX_OPERATIONS: u64 = 1_000_000;
+pub const SCRIPT_MAX_CALL_DURATION_MS: u64 = 50;
+// Script operations between checks of the call's time limit
+pub const SCRIPT_DEADLINE_CHECK_INTERVAL: u64 = 1024;
+pub const SCRIPT_MAX_CALL_LEVELS: usize = 32;
+pub const SCRIPT_MAX_STRING_SIZE: usize = 64 * 1024;
+pub const SCRIPT_MAX_ARRAY_SIZE: usize = 16 * 1024;
+pub const SCRIPT_MAX_MAP_SIZE: usize = 16 * 1024;
+pub const SCRIPT_MAX_FAILURES: u32 = 5;
+// Events a space dispatches in one tick, so handlers sending events in a loop can't hang it
+pub const SCRIPT_MAX_EVENTS_PER_TICK: usize = 1024;
+// Longest delay a script can wait for, a day
+pub const SCRIPT_MAX_DELAY_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+pub const SCRIPT_HOT_RELOAD_INTERVAL_SECONDS: f32 = 1.0;
+pub const SHADERS_PATH: &str = "./src/engine/graphics/shaders";
+pub const SHADER_HOT_RELOAD_INTERVAL_SECONDS: f32 = 0.5;
@@ -0,0 +1,99 @@
+use nalgebra::{Matrix4, Point3, Vector3, Vector4};
+
+/// A sphere containing every vertex of a model, in model space
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BoundingSphere {
+    pub center: [f32; 3],
+    pub radius: f32,
+}
+
+impl Default for BoundingSphere {
+    fn default() -> Self {
+        BoundingSphere {
+            center: [0.0, 0.0, 0.0],
+            radius: 0.0,
+        }
+    }
+}
+
+impl BoundingSphere {
+    /// Centered on the points' bounding box, which is close enough to the smallest sphere.
+    pub fn from_points<I: Iterator<Item = [f32; 3]> + Clone>(points: I) -> BoundingSphere {
+        let mut min = Vector3::repeat(std::f32::MAX);
+        let mut max = Vector3::repeat(std::f32::MIN);
+        for p in points.clone() {
+            min = min.inf(&Vector3::from(p));
+            max = max.sup(&Vector3::from(p));
+        }
+        if min.x > max.x {
+            return BoundingSphere::default();
+        }
+
+        let center = (min + max) / 2.0;
+        let radius = points
+            .map(|p| (Vector3::from(p) - center).norm())
+            .fold(0.0, f32::max);
+        BoundingSphere {
+            center: center.into(),
+            radius,
+        }
+    }
+
+    /// The sphere around the transformed model. Non-uniform scales use their largest axis.
+    pub fn transformed(&self, m: &Matrix4<f32>) -> BoundingSphere {
+        let center = m.transform_point(&Point3::from(self.center));
+        let scale = (0..3)
+            .map(|i| m.fixed_slice::<nalgebra::U3, nalgebra::U1>(0, i).norm())
+            .fold(0.0, f32::max);
+        BoundingSphere {
+            center: center.coords.into(),
+            radius: self.radius * scale,
+        }
+    }
+}
+
+/// The six planes of a camera's view volume, facing inwards
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from a projection * view matrix (Gribb & Hartmann).
+    pub fn from_matrix(m: &Matrix4<f32>) -> Frustum {
+        let row = |i: usize| m.row(i).transpose();
+        let normalize = |p: Vector4<f32>| p / p.xyz().norm();
+        Frustum {
+            planes: [
+                normalize(row(3) + row(0)),
+                normalize(row(3) - row(0)),
+                normalize(row(3) + row(1)),
+                normalize(row(3) - row(1)),
+                normalize(row(3) + row(2)),
+                normalize(row(3) - row(2)),
+            ],
+        }
+    }
+
+    pub fn intersects(&self, sphere: &BoundingSphere) -> bool {
+        let center = Vector3::from(sphere.center);
+        self.planes.iter().all(|p| p.xyz().dot(&center) + p.w >= -sphere.radius)
+    }
+}
+
+#[test]
+fn frustum_culls_spheres_outside_the_view() {
+    let proj = Matrix4::new_perspective(1.0, std::f32::consts::FRAC_PI_2, 0.1, 100.0);
+    let view = Matrix4::look_at_rh(&Point3::origin(), &Point3::new(0.0, 0.0, -1.0), &Vector3::y());
+    let frustum = Frustum::from_matrix(&(proj * view));
+    let unit = BoundingSphere { center: [0.0, 0.0, 0.0], radius: 1.0 };
+
+    let at = |x: f32, y: f32, z: f32| unit.transformed(&Matrix4::new_translation(&Vector3::new(x, y, z)));
+    assert!(frustum.intersects(&at(0.0, 0.0, -10.0)));
+    assert!(!frustum.intersects(&at(0.0, 0.0, 10.0)), "behind the camera");
+    assert!(!frustum.intersects(&at(0.0, 0.0, -200.0)), "past the far plane");
+    assert!(!frustum.intersects(&at(30.0, 0.0, -10.0)), "off to the side");
+    assert!(frustum.intersects(&at(10.5, 0.0, -10.0)), "partially visible");
+
+    let scaled = unit.transformed(&Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, 4.0, 2.0)));
+    assert_eq!(scaled.radius, 4.0);
+}
@@ -1,8 +1,14 @@
-use crate::engine::systems::MeshInstance;
+use crate::engine::systems::{MeshInstance, LightInstance, TextInstance};
+use crate::engine::ui::UiLayer;
 
 pub struct FrameBuilder {
     pub meshes: std::collections::HashMap<String, Vec<MeshInstance>>,
     pub skybox: Option<String>,
+    pub lights: Vec<LightInstance>,
+    pub texts: Vec<TextInstance>,
+
+    /// Drawn over everything else, one layer after the other
+    pub ui: Vec<UiLayer>,
 }
 
 /// Fully describes a single frame to be rendered
@@ -11,6 +17,9 @@ impl FrameBuilder {
         Self {
             meshes: std::collections::HashMap::new(),
             skybox: None,
+            lights: Vec::new(),
+            texts: Vec::new(),
+            ui: Vec::new(),
         }
     }
 
@@ -24,4 +33,19 @@ impl FrameBuilder {
        self.skybox = skybox;
        self
     }
+
+    pub fn with_lights(&mut self, lights: Vec<LightInstance>) -> &mut Self {
+        self.lights = lights;
+        self
+    }
+
+    pub fn with_texts(&mut self, texts: Vec<TextInstance>) -> &mut Self {
+        self.texts = texts;
+        self
+    }
+
+    pub fn with_ui(&mut self, ui: Vec<UiLayer>) -> &mut Self {
+        self.ui = ui;
+        self
+    }
 }
\ No newline at end of file
@@ -0,0 +1,286 @@
+//! Golden image tests: fixed scenes are rendered offscreen and compared against the
+//! reference PNGs in `tests/golden`. Failing scenes write the rendered frame and a
+//! diff image to `target/golden`.
+//!
+//! To accept intended visual changes, run the tests with `UPDATE_GOLDEN=1`, which
+//! overwrites the references, and review the new PNGs before committing them.
+use crate::engine::prelude::*;
+use crate::engine::camera::Camera;
+use crate::engine::systems::{MeshInstance, LightInstance, TextInstance};
+use crate::engine::components::{LightComponent, TextAnchor, TextAlign, TextStyle};
+use super::{FrameBuilder, Renderer};
+use nalgebra::{Matrix4, Point3, Vector3};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How far apart two images are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageDiff {
+    /// The largest difference of any channel of any pixel
+    pub max_channel_diff: u8,
+
+    /// How many pixels have a channel off by more than the tolerance
+    pub differing_pixels: usize,
+}
+
+impl ImageDiff {
+    pub fn is_within(&self, total_pixels: usize) -> bool {
+        self.differing_pixels as f64 <= total_pixels as f64 * consts::GOLDEN_MAX_DIFFERING_PIXELS
+    }
+}
+
+/// Compares two images of the same size, and paints the pixels that differ by more
+/// than `tolerance` red over a dimmed copy of `actual`.
+pub fn compare(actual: &image::RgbaImage, expected: &image::RgbaImage, tolerance: u8)
+-> (ImageDiff, image::RgbaImage) {
+    let mut diff_image = image::RgbaImage::new(actual.width(), actual.height());
+    let mut diff = ImageDiff {
+        max_channel_diff: 0,
+        differing_pixels: 0,
+    };
+
+    for (x, y, a) in actual.enumerate_pixels() {
+        let e = expected.get_pixel(x, y);
+        let channel_diff = a.0.iter().zip(e.0.iter())
+            .map(|(a, e)| (*a as i16 - *e as i16).abs() as u8)
+            .max()
+            .unwrap_or(0);
+        diff.max_channel_diff = diff.max_channel_diff.max(channel_diff);
+
+        if channel_diff > tolerance {
+            diff.differing_pixels += 1;
+            diff_image.put_pixel(x, y, image::Rgba([255, 0, 0, 255]));
+        } else {
+            diff_image.put_pixel(x, y, image::Rgba([a[0] / 4, a[1] / 4, a[2] / 4, 255]));
+        }
+    }
+
+    (diff, diff_image)
+}
+
+struct Scene {
+    name: &'static str,
+    camera: Camera,
+    meshes: HashMap<String, Vec<MeshInstance>>,
+    skybox: Option<&'static str>,
+    texts: Vec<TextInstance>,
+    lights: Vec<LightInstance>,
+
+    /// Fixed instead of auto exposure, so no scene depends on the ones rendered before it
+    exposure: f32,
+}
+
+fn instance(world_matrix: Matrix4<f32>) -> MeshInstance {
+    MeshInstance {
+        world_matrix: world_matrix.into(),
+        ..Default::default()
+    }
+}
+
+/// Two white lights like the main menu's, above and on the camera's side of the origin
+fn lights() -> Vec<LightInstance> {
+    [1.5f32, -1.5].iter()
+        .map(|x| LightInstance::from_component(
+            &LightComponent::point([1.0, 1.0, 1.0], 400.0, std::f32::INFINITY),
+            &Matrix4::new_translation(&Vector3::new(*x, 1.5, -2.0)),
+        ))
+        .collect()
+}
+
+fn scenes() -> Vec<Scene> {
+    let front = Camera::new(Point3::new(0.0, 0.0, -4.0), Point3::origin(), Vector3::y());
+    let above = Camera::new(Point3::new(6.0, 8.0, -10.0), Point3::origin(), Vector3::y());
+
+    let mut grid = Vec::new();
+    for x in -10..10 {
+        for z in -10..10 {
+            grid.push(instance(
+                Matrix4::new_translation(&Vector3::new(x as f32 * 0.6, 0.0, z as f32 * 0.6))
+                * Matrix4::new_scaling(0.2)
+            ));
+        }
+    }
+
+    // A patch of the galaxy map, with stars as close as the map generator places them.
+    // Their lights are the level's, and should reach the neighbouring stars and the planet.
+    let stars = [(0.0f32, 0.0f32), (-0.05, 0.01), (0.045, -0.02), (0.01, 0.05), (-0.02, -0.05)];
+    let star_lights = stars.iter()
+        .map(|(x, z)| LightInstance::from_component(
+            &LightComponent::point([1.0, 0.85, 0.6], 0.01, 0.3),
+            &Matrix4::new_translation(&Vector3::new(*x, 0.0, *z)),
+        ))
+        .collect();
+    let mut star_meshes: Vec<_> = stars.iter()
+        .map(|(x, z)| instance(
+            Matrix4::new_translation(&Vector3::new(*x, 0.0, *z)) * Matrix4::new_scaling(0.01)
+        ))
+        .collect();
+    star_meshes.push(instance(
+        Matrix4::new_translation(&Vector3::new(0.02, 0.0, 0.0)) * Matrix4::new_scaling(0.004)
+    ));
+
+    vec![
+        Scene {
+            // Unknown models render as the default cube
+            name: "cube",
+            camera: front,
+            meshes: vec![(String::from("cube"), vec![instance(
+                Matrix4::from_euler_angles(0.5, 0.7, 0.0)
+            )])].into_iter().collect(),
+            skybox: None,
+            texts: Vec::new(),
+            lights: lights(),
+            exposure: 0.05,
+        },
+        Scene {
+            name: "sphere",
+            camera: front,
+            meshes: vec![(String::from("sphere.gltf"), vec![instance(Matrix4::identity())])]
+                .into_iter().collect(),
+            skybox: None,
+            texts: Vec::new(),
+            lights: lights(),
+            exposure: 0.05,
+        },
+        Scene {
+            name: "skybox",
+            camera: above,
+            meshes: HashMap::new(),
+            skybox: Some("./resources/skybox/skybox.png"),
+            texts: Vec::new(),
+            lights: lights(),
+            exposure: 1.0,
+        },
+        Scene {
+            name: "many_instances",
+            camera: above,
+            meshes: vec![(String::from("sphere.gltf"), grid)].into_iter().collect(),
+            skybox: Some("./resources/skybox/skybox.png"),
+            texts: Vec::new(),
+            lights: lights(),
+            exposure: 0.02,
+        },
+        Scene {
+            name: "text",
+            camera: front,
+            meshes: vec![(String::from("cube"), vec![instance(Matrix4::new_scaling(0.5))])]
+                .into_iter().collect(),
+            skybox: None,
+            texts: vec![
+                TextInstance {
+                    text: String::from("Wrapped screen text, left aligned"),
+                    anchor: TextAnchor::Screen([8.0, 8.0]),
+                    style: TextStyle {
+                        size: 16.0,
+                        wrap_width: Some(120.0),
+                        ..Default::default()
+                    },
+                },
+                TextInstance {
+                    text: String::from("Cube"),
+                    anchor: TextAnchor::World([0.0, 0.6, 0.0]),
+                    style: TextStyle {
+                        color: [1.0, 0.5, 0.2, 1.0],
+                        align: TextAlign::Center,
+                        ..Default::default()
+                    },
+                },
+            ],
+            lights: lights(),
+            exposure: 0.05,
+        },
+        Scene {
+            name: "star_lights",
+            camera: Camera::new(Point3::new(0.0, 0.1, -0.12), Point3::origin(), Vector3::y()),
+            meshes: vec![(String::from("sphere.gltf"), star_meshes)].into_iter().collect(),
+            skybox: None,
+            texts: Vec::new(),
+            lights: star_lights,
+            exposure: 1.0,
+        },
+    ]
+}
+
+fn render(renderer: &mut Renderer, scene: &Scene) -> anyhow::Result<image::RgbaImage> {
+    for model in scene.meshes.keys().filter(|m| m.ends_with(".gltf")) {
+        renderer.load_model(model)?;
+    }
+    if let Some(skybox) = scene.skybox {
+        renderer.load_cubemap(skybox)?;
+    }
+
+    renderer.set_post_process(super::PostProcessSettings {
+        auto_exposure: false,
+        exposure: scene.exposure,
+        ..Default::default()
+    });
+    let mut framebuilder = FrameBuilder::new();
+    framebuilder
+        .with_meshes(scene.meshes.clone())
+        .with_skybox(scene.skybox.map(String::from))
+        .with_lights(scene.lights.clone())
+        .with_texts(scene.texts.clone());
+    renderer.draw_frame(&framebuilder, &scene.camera, [0, 0]);
+    Ok(renderer.read_frame())
+}
+
+/// Renders a scene and checks it against its reference, or writes the reference when updating.
+fn check_scene(renderer: &mut Renderer, scene: &Scene, update: bool) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let actual = render(renderer, scene)?;
+    let reference = Path::new(consts::GOLDEN_IMAGES_PATH).join(scene.name).with_extension("png");
+
+    if update {
+        std::fs::create_dir_all(consts::GOLDEN_IMAGES_PATH)?;
+        return actual.save(&reference).context(format!("Failed to save {:?}", reference));
+    }
+
+    let expected = utils::load_image(&reference)
+        .context(format!("Missing reference {:?}, run with UPDATE_GOLDEN=1 to create it", reference))?
+        .to_rgba();
+    if expected.dimensions() != actual.dimensions() {
+        return Err(anyhow!("Reference {:?} is {:?} but the render is {:?}",
+            reference, expected.dimensions(), actual.dimensions()));
+    }
+
+    let (diff, diff_image) = compare(&actual, &expected, consts::GOLDEN_CHANNEL_TOLERANCE);
+    if diff.is_within((actual.width() * actual.height()) as usize) {
+        return Ok(())
+    }
+
+    let output = Path::new(consts::GOLDEN_OUTPUT_PATH);
+    std::fs::create_dir_all(output)?;
+    actual.save(output.join(format!("{}_actual.png", scene.name)))?;
+    diff_image.save(output.join(format!("{}_diff.png", scene.name)))?;
+    Err(anyhow!("{} pixels differ (max channel difference {}), see {:?}",
+        diff.differing_pixels, diff.max_channel_diff, output))
+}
+
+#[test]
+fn golden_images() {
+    // Without OpenGL nothing here is checked, so that has to show up as a failure
+    let mut renderer = Renderer::new_headless(consts::GOLDEN_RESOLUTION)
+        .unwrap_or_else(|e| panic!("Golden image tests need a headless OpenGL context: {:#}", e));
+    let update = std::env::var("UPDATE_GOLDEN").map_or(false, |v| v == "1");
+
+    let mut failed = Vec::new();
+    for scene in scenes() {
+        if let Err(e) = check_scene(&mut renderer, &scene, update) {
+            failed.push(format!("{}: {:#}", scene.name, e));
+        }
+    }
+    assert!(failed.is_empty(), "Golden images differ:\n{}", failed.join("\n"));
+}
+
+#[test]
+fn compare_counts_pixels_beyond_tolerance() {
+    let expected = image::RgbaImage::from_pixel(4, 4, image::Rgba([100, 100, 100, 255]));
+    let mut actual = expected.clone();
+    actual.put_pixel(0, 0, image::Rgba([103, 100, 100, 255]));
+    actual.put_pixel(1, 0, image::Rgba([100, 140, 100, 255]));
+
+    let (diff, diff_image) = compare(&actual, &expected, 4);
+    assert_eq!(diff, ImageDiff { max_channel_diff: 40, differing_pixels: 1 });
+    assert_eq!(*diff_image.get_pixel(1, 0), image::Rgba([255, 0, 0, 255]));
+    assert_ne!(*diff_image.get_pixel(0, 0), image::Rgba([255, 0, 0, 255]));
+}
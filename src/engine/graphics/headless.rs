@@ -0,0 +1,132 @@
+use crate::engine::prelude::*;
+use glium::glutin::{ContextBuilder, dpi::PhysicalSize};
+use glium::backend::Facade;
+use glium::HeadlessRenderer;
+
+/// Creates an OpenGL context that isn't tied to any window. On unix a software
+/// (OSMesa) context is tried first, then Mesa's surfaceless EGL platform, so this also
+/// works on machines without a GPU or a display server.
+pub fn make_headless_context(resolution: [u32; 2]) -> anyhow::Result<Box<dyn Facade>> {
+    let size = PhysicalSize::new(resolution[0], resolution[1]);
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        use glium::glutin::platform::unix::HeadlessContextExt;
+        match ContextBuilder::new().with_depth_buffer(24).build_osmesa(size) {
+            Ok(ctx) => {
+                let ctx = unsafe { ctx.make_current() }
+                    .map_err(|(_, e)| anyhow!("Failed to activate software context: {}", e))?;
+                return HeadlessRenderer::new(ctx)
+                    .map(|renderer| Box::new(renderer) as Box<dyn Facade>)
+                    .map_err(|e| anyhow!("Failed to create software renderer: {}", e));
+            },
+            Err(e) => log::warning(&format!("OSMesa unavailable, trying surfaceless EGL: {}", e)),
+        }
+        match surfaceless::make_context(resolution) {
+            Ok(context) => return Ok(Box::new(context)),
+            Err(e) => log::warning(&format!("Surfaceless EGL unavailable, trying the GPU: {:#}", e)),
+        }
+
+        // winit panics instead of failing when there's no display server to connect to
+        if std::env::var_os("DISPLAY").is_none() && std::env::var_os("WAYLAND_DISPLAY").is_none() {
+            return Err(anyhow!("No OpenGL context without a display server"));
+        }
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let eventloop = {
+        use glium::glutin::platform::unix::EventLoopExtUnix;
+        glium::glutin::event_loop::EventLoop::<()>::new_any_thread()
+    };
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    let eventloop = glium::glutin::event_loop::EventLoop::<()>::new();
+
+    let ctx = ContextBuilder::new()
+        .with_depth_buffer(24)
+        .build_headless(&eventloop, size)
+        .map_err(|e| anyhow!("Failed to create headless OpenGL context: {}", e))?;
+    let ctx = unsafe { ctx.make_current() }
+        .map_err(|(_, e)| anyhow!("Failed to activate headless context: {}", e))?;
+    HeadlessRenderer::new(ctx)
+        .map(|renderer| Box::new(renderer) as Box<dyn Facade>)
+        .map_err(|e| anyhow!("Failed to create headless renderer: {}", e))
+}
+
+/// A context on `EGL_MESA_platform_surfaceless`, which Mesa provides even without a GPU.
+/// It has no default framebuffer, which is fine since headless frames go to textures.
+#[cfg(all(unix, not(target_os = "macos")))]
+mod surfaceless {
+    use crate::engine::prelude::*;
+    use khronos_egl as egl;
+    use std::rc::Rc;
+
+    const PLATFORM_SURFACELESS_MESA: egl::Enum = 0x31DD;
+
+    struct Backend {
+        egl: egl::DynamicInstance<egl::EGL1_5>,
+        display: egl::Display,
+        context: egl::Context,
+        size: (u32, u32),
+    }
+
+    unsafe impl glium::backend::Backend for Backend {
+        fn swap_buffers(&self) -> Result<(), glium::SwapBuffersError> {
+            Ok(())
+        }
+
+        unsafe fn get_proc_address(&self, symbol: &str) -> *const std::os::raw::c_void {
+            self.egl.get_proc_address(symbol).map_or(std::ptr::null(), |f| f as *const _)
+        }
+
+        fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+            self.size
+        }
+
+        fn is_current(&self) -> bool {
+            self.egl.get_current_context() == Some(self.context)
+        }
+
+        unsafe fn make_current(&self) {
+            if let Err(e) = self.egl.make_current(self.display, None, None, Some(self.context)) {
+                log::error(&format!("Failed to activate surfaceless context: {}", e));
+            }
+        }
+    }
+
+    impl Drop for Backend {
+        // The display is shared by every context on it, so it's never terminated
+        fn drop(&mut self) {
+            let _ = self.egl.make_current(self.display, None, None, None);
+            let _ = self.egl.destroy_context(self.display, self.context);
+        }
+    }
+
+    pub fn make_context(resolution: [u32; 2]) -> anyhow::Result<Rc<glium::backend::Context>> {
+        let egl = unsafe { egl::DynamicInstance::<egl::EGL1_5>::load_required() }
+            .map_err(|e| anyhow!("Failed to load EGL: {}", e))?;
+        let display = unsafe {
+            egl.get_platform_display(PLATFORM_SURFACELESS_MESA, egl::DEFAULT_DISPLAY, &[egl::ATTRIB_NONE])
+        }.map_err(|e| anyhow!("No surfaceless display: {}", e))?;
+        egl.initialize(display).map_err(|e| anyhow!("Failed to initialize EGL: {}", e))?;
+        egl.bind_api(egl::OPENGL_API).map_err(|e| anyhow!("EGL has no OpenGL: {}", e))?;
+
+        let config = egl.choose_first_config(display, &[
+            egl::SURFACE_TYPE, egl::PBUFFER_BIT,
+            egl::RENDERABLE_TYPE, egl::OPENGL_BIT,
+            egl::NONE,
+        ]).map_err(|e| anyhow!("Failed to choose an EGL config: {}", e))?
+            .ok_or_else(|| anyhow!("No EGL config renders OpenGL"))?;
+        let context = egl.create_context(display, config, None, &[
+            egl::CONTEXT_MAJOR_VERSION, 3,
+            egl::CONTEXT_MINOR_VERSION, 3,
+            egl::CONTEXT_OPENGL_PROFILE_MASK, egl::CONTEXT_OPENGL_CORE_PROFILE_BIT,
+            egl::NONE,
+        ]).map_err(|e| anyhow!("Failed to create an EGL context: {}", e))?;
+        egl.make_current(display, None, None, Some(context))
+            .map_err(|e| anyhow!("Failed to activate the EGL context: {}", e))?;
+
+        let backend = Backend { egl, display, context, size: (resolution[0], resolution[1]) };
+        unsafe { glium::backend::Context::new(backend, true, glium::debug::DebugCallbackBehavior::Ignore) }
+            .map_err(|e| anyhow!("The surfaceless context's OpenGL is too old: {}", e))
+    }
+}
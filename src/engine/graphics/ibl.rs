@@ -0,0 +1,369 @@
+//! Image based lighting: the skybox's light on everything else. Each skybox gets an
+//! irradiance map for the diffuse light and a prefiltered map with a mip level per
+//! roughness for the specular light. Both are generated on the GPU the first time and
+//! cached next to the skybox's images, or under the configured cache directory, like model
+//! `.cache` files. The BRDF lookup table they're combined with doesn't depend on the
+//! skybox, so there's one, cached as well.
+use crate::engine::prelude::*;
+use std::collections::HashMap;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use glium::backend::Facade;
+use glium::texture::{Cubemap, CubeLayer, SrgbCubemap, Texture2d, RawImage2d, ClientFormat, UncompressedFloatFormat,
+    MipmapsOption};
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, Sampler};
+use glium::{Surface, VertexBuffer, uniform};
+use super::shaders::{self, ShaderProgram};
+use super::textures::CUBEMAP_FACES;
+use super::vertex::Vertex2d;
+
+/// The cubemap layers in the order `cube_direction` in ibl.glsl numbers them
+const FACES: [CubeLayer; 6] = [
+    CubeLayer::PositiveX,
+    CubeLayer::NegativeX,
+    CubeLayer::PositiveY,
+    CubeLayer::NegativeY,
+    CubeLayer::PositiveZ,
+    CubeLayer::NegativeZ,
+];
+
+/// The generated maps, as they're cached on disk. Faces are linear RGB, in `FACES` order,
+/// with their rows from the bottom.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CachedEnvironment {
+    pub irradiance_size: u32,
+    pub irradiance: Vec<Vec<f32>>,
+
+    /// The faces of each mip level, from the smoothest
+    pub prefiltered_size: u32,
+    pub prefiltered: Vec<Vec<Vec<f32>>>,
+}
+
+impl CachedEnvironment {
+    /// Caches from other settings, or cut short, are rebuilt
+    fn validate(&self) -> anyhow::Result<()> {
+        let faces_fit = |faces: &[Vec<f32>], size: u32|
+            faces.len() == FACES.len() && faces.iter().all(|f| f.len() == (size * size * 3) as usize);
+
+        if self.irradiance_size != consts::IBL_IRRADIANCE_SIZE
+            || self.prefiltered_size != consts::IBL_PREFILTERED_SIZE
+            || self.prefiltered.len() != consts::IBL_PREFILTERED_LEVELS as usize {
+            return Err(anyhow!("The cached environment was built with other settings"));
+        }
+        if !faces_fit(&self.irradiance, self.irradiance_size) {
+            return Err(anyhow!("The cached irradiance map is incomplete"));
+        }
+        for (level, faces) in self.prefiltered.iter().enumerate() {
+            if !faces_fit(faces, mip_size(self.prefiltered_size, level as u32)) {
+                return Err(anyhow!("Level {} of the cached prefiltered map is incomplete", level));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedBrdfLut {
+    size: u32,
+
+    // Scale and bias pairs, rows from the bottom
+    texels: Vec<f32>,
+}
+
+pub struct Environment {
+    pub irradiance: Cubemap,
+    pub prefiltered: Cubemap,
+    pub prefiltered_levels: u32,
+}
+
+/// What the PBR shader samples the environment with
+pub struct IblBindings<'a> {
+    pub enabled: bool,
+    pub irradiance: &'a Cubemap,
+    pub prefiltered: &'a Cubemap,
+    pub prefiltered_max_lod: f32,
+    pub brdf_lut: &'a Texture2d,
+}
+
+fn mip_size(size: u32, level: u32) -> u32 {
+    (size >> level).max(1)
+}
+
+fn linear(source: &SrgbCubemap) -> Sampler<SrgbCubemap> {
+    source.sampled()
+        .magnify_filter(MagnifySamplerFilter::Linear)
+        .minify_filter(MinifySamplerFilter::Linear)
+}
+
+/// Draws a fullscreen pass into a float texture, and reads back the first `channels`
+/// channels of every texel
+fn render_to_cpu<U: glium::uniforms::Uniforms>(
+    display: &dyn Facade,
+    quad: &VertexBuffer<Vertex2d>,
+    program: &ShaderProgram,
+    size: u32,
+    uniforms: &U,
+    channels: usize,
+) -> anyhow::Result<Vec<f32>> {
+    use anyhow::Context;
+    let target = Texture2d::empty_with_format(
+        display, UncompressedFloatFormat::F32F32F32F32, MipmapsOption::NoMipmap, size, size
+    ).context("Failed to create an image based lighting target")?;
+    SimpleFrameBuffer::new(display, &target)
+        .context("Failed to create an image based lighting framebuffer")?
+        .draw(
+            quad,
+            glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+            program,
+            uniforms,
+            &Default::default()
+        ).map_err(|e| anyhow!("Failed to draw an image based lighting map: {}", e))?;
+
+    let rows: Vec<Vec<(f32, f32, f32, f32)>> = target.main_level().first_layer().into_image(None).unwrap()
+        .raw_read(&glium::Rect { left: 0, bottom: 0, width: size, height: size });
+    let mut texels = Vec::with_capacity((size * size) as usize * channels);
+    for (r, g, b, a) in rows.into_iter().flatten() {
+        texels.extend_from_slice(&[r, g, b, a][..channels]);
+    }
+    Ok(texels)
+}
+
+fn generate(display: &dyn Facade, quad: &VertexBuffer<Vertex2d>, source: &SrgbCubemap)
+-> anyhow::Result<CachedEnvironment> {
+    let irradiance_program = shaders::fullscreen(display, "ibl_irradiance", "ibl_irradiance.frag")?;
+    let prefilter_program = shaders::fullscreen(display, "ibl_prefilter", "ibl_prefilter.frag")?;
+
+    let irradiance = (0..FACES.len())
+        .map(|face| render_to_cpu(display, quad, &irradiance_program, consts::IBL_IRRADIANCE_SIZE, &uniform! {
+            environment: linear(source),
+            face: face as i32,
+        }, 3))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let prefiltered = (0..consts::IBL_PREFILTERED_LEVELS)
+        .map(|level| {
+            let size = mip_size(consts::IBL_PREFILTERED_SIZE, level);
+            let roughness = level as f32 / (consts::IBL_PREFILTERED_LEVELS - 1) as f32;
+            (0..FACES.len())
+                .map(|face| render_to_cpu(display, quad, &prefilter_program, size, &uniform! {
+                    environment: linear(source),
+                    face: face as i32,
+                    roughness: roughness,
+                }, 3))
+                .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(CachedEnvironment {
+        irradiance_size: consts::IBL_IRRADIANCE_SIZE,
+        irradiance,
+        prefiltered_size: consts::IBL_PREFILTERED_SIZE,
+        prefiltered,
+    })
+}
+
+fn upload_face(display: &dyn Facade, cubemap: &Cubemap, level: u32, layer: CubeLayer, size: u32, texels: &[f32])
+-> anyhow::Result<()> {
+    use anyhow::Context;
+    let image = Texture2d::with_format(display, RawImage2d {
+        data: Cow::Borrowed(texels),
+        width: size,
+        height: size,
+        format: ClientFormat::F32F32F32,
+    }, UncompressedFloatFormat::F16F16F16F16, MipmapsOption::NoMipmap)
+        .context("Failed to create an image based lighting face")?;
+    let mipmap = cubemap.mipmap(level).ok_or_else(|| anyhow!("The cubemap has no mip level {}", level))?;
+    let framebuffer = SimpleFrameBuffer::new(display, mipmap.image(layer))
+        .context("Failed to create cubemap fb")?;
+    image.as_surface().blit_whole_color_to(
+        &framebuffer,
+        &glium::BlitTarget {
+            left: 0,
+            bottom: 0,
+            width: size as i32,
+            height: size as i32,
+        },
+        MagnifySamplerFilter::Nearest
+    );
+    Ok(())
+}
+
+fn upload(display: &dyn Facade, cached: &CachedEnvironment) -> anyhow::Result<Environment> {
+    use anyhow::Context;
+    let irradiance = Cubemap::empty_with_format(
+        display, UncompressedFloatFormat::F16F16F16F16, MipmapsOption::NoMipmap, cached.irradiance_size
+    ).context("Failed to create the irradiance map")?;
+    for (layer, texels) in FACES.iter().zip(cached.irradiance.iter()) {
+        upload_face(display, &irradiance, 0, *layer, cached.irradiance_size, texels)?;
+    }
+
+    let levels = cached.prefiltered.len() as u32;
+    let prefiltered = Cubemap::empty_with_format(
+        display,
+        UncompressedFloatFormat::F16F16F16F16,
+        MipmapsOption::EmptyMipmapsMax(levels - 1),
+        cached.prefiltered_size,
+    ).context("Failed to create the prefiltered map")?;
+    for (level, faces) in cached.prefiltered.iter().enumerate() {
+        let size = mip_size(cached.prefiltered_size, level as u32);
+        for (layer, texels) in FACES.iter().zip(faces.iter()) {
+            upload_face(display, &prefiltered, level as u32, *layer, size, texels)?;
+        }
+    }
+
+    Ok(Environment {
+        irradiance,
+        prefiltered,
+        prefiltered_levels: levels,
+    })
+}
+
+fn load_brdf_lut(display: &dyn Facade, quad: &VertexBuffer<Vertex2d>, cache_dir: Option<&Path>)
+-> anyhow::Result<Texture2d> {
+    use anyhow::Context;
+    let size = consts::BRDF_LUT_SIZE;
+    let cache_path = utils::cache_location(consts::BRDF_LUT_CACHE_PATH, cache_dir);
+    // Nothing but the size goes into the table, the shader is covered by the version
+    let header = utils::CacheHeader::new(consts::BRDF_LUT_CACHE_VERSION, utils::content_hash(&size.to_le_bytes()));
+    let lut = match utils::read_cache::<CachedBrdfLut, _>(&cache_path, consts::BRDF_LUT_CACHE_MAGIC, &header) {
+        Ok(lut) if lut.size == size && lut.texels.len() == (size * size * 2) as usize => lut,
+        _ => {
+            log::info("Generating the BRDF lookup table");
+            let program = shaders::fullscreen(display, "ibl_brdf", "ibl_brdf.frag")?;
+            let lut = CachedBrdfLut {
+                size,
+                texels: render_to_cpu(display, quad, &program, size, &glium::uniforms::EmptyUniforms, 2)?,
+            };
+            if let Err(e) = utils::write_cache(&cache_path, consts::BRDF_LUT_CACHE_MAGIC, &header, &lut) {
+                log::err(&e);
+            }
+            lut
+        },
+    };
+
+    Texture2d::with_format(display, RawImage2d {
+        data: Cow::Owned(lut.texels),
+        width: size,
+        height: size,
+        format: ClientFormat::F32F32,
+    }, UncompressedFloatFormat::F16F16, MipmapsOption::NoMipmap)
+        .context("Failed to create the BRDF lookup table")
+}
+
+/// A hash of the images of a skybox's faces
+fn source_hash(name: &str) -> anyhow::Result<u64> {
+    use anyhow::Context;
+    let mut hashes = Vec::new();
+    for (_, suffix) in CUBEMAP_FACES.iter() {
+        let path = utils::extend_filename(name, suffix);
+        let data = std::fs::read(&path).context(format!("Failed opening {}", path.to_string_lossy()))?;
+        hashes.extend_from_slice(&utils::content_hash(&data).to_le_bytes());
+    }
+    Ok(utils::content_hash(&hashes))
+}
+
+/// The environments of the loaded skyboxes
+pub struct IblManager {
+    environments: HashMap<String, Environment>,
+    cache_dir: Option<PathBuf>,
+
+    // Loaded with the first skybox, so it's cached wherever `set_cache_dir` says
+    brdf_lut: Option<Texture2d>,
+
+    // Bound in place of the maps when there's no skybox, since the samplers can't be left empty
+    black: Cubemap,
+    black_lut: Texture2d,
+}
+
+impl IblManager {
+    pub fn new(display: &dyn Facade) -> anyhow::Result<IblManager> {
+        use anyhow::Context;
+        Ok(IblManager {
+            environments: HashMap::new(),
+            cache_dir: None,
+            brdf_lut: None,
+            black: Cubemap::empty_with_format(display, UncompressedFloatFormat::F16F16F16F16,
+                MipmapsOption::NoMipmap, 1).context("Failed to create the empty environment")?,
+            black_lut: Texture2d::empty_with_format(display, UncompressedFloatFormat::F16F16,
+                MipmapsOption::NoMipmap, 1, 1).context("Failed to create the empty BRDF lookup table")?,
+        })
+    }
+
+    /// Keeps the environment caches in their own folder instead of next to the skyboxes
+    pub fn set_cache_dir(&mut self, cache_dir: Option<PathBuf>) {
+        self.cache_dir = cache_dir;
+    }
+
+    /// Loads the environment of a skybox from its cache, or generates it when the cache is
+    /// missing or wasn't built from the skybox's current images
+    pub fn try_load(&mut self, display: &dyn Facade, quad: &VertexBuffer<Vertex2d>, name: &str,
+        source: &SrgbCubemap) -> anyhow::Result<()> {
+        if self.environments.contains_key(name) {
+            return Ok(());
+        }
+        if self.brdf_lut.is_none() {
+            self.brdf_lut = Some(load_brdf_lut(display, quad, self.cache_dir.as_deref())?);
+        }
+
+        let cache_path = utils::cache_location(format!("{}{}", name, consts::IBL_CACHE_EXTENSION),
+            self.cache_dir.as_deref());
+        let header = utils::CacheHeader::new(consts::IBL_CACHE_VERSION, source_hash(name)?);
+        let cached = utils::read_cache::<CachedEnvironment, _>(&cache_path, consts::IBL_CACHE_MAGIC, &header)
+            .and_then(|c| c.validate().map(|_| c));
+        let cached = match cached {
+            Ok(cached) => cached,
+            Err(e) => {
+                log::info(&format!("{:#}, generating the image based lighting of {}", e, name));
+                let cached = generate(display, quad, source)?;
+                if let Err(e) = utils::write_cache(&cache_path, consts::IBL_CACHE_MAGIC, &header, &cached) {
+                    log::err(&e);
+                }
+                cached
+            },
+        };
+
+        self.environments.insert(String::from(name), upload(display, &cached)?);
+        Ok(())
+    }
+
+    /// The maps for a frame with the given skybox. Without one there's no image based lighting.
+    pub fn bindings(&self, skybox: Option<&str>) -> IblBindings {
+        // An environment is only stored after the lookup table is loaded
+        match (skybox.and_then(|s| self.environments.get(s)), &self.brdf_lut) {
+            (Some(environment), Some(brdf_lut)) => IblBindings {
+                enabled: true,
+                irradiance: &environment.irradiance,
+                prefiltered: &environment.prefiltered,
+                prefiltered_max_lod: (environment.prefiltered_levels - 1) as f32,
+                brdf_lut,
+            },
+            _ => IblBindings {
+                enabled: false,
+                irradiance: &self.black,
+                prefiltered: &self.black,
+                prefiltered_max_lod: 0.0,
+                brdf_lut: &self.black_lut,
+            },
+        }
+    }
+}
+
+#[test]
+fn cached_environments_are_validated() {
+    let faces = |size: u32| vec![vec![0.0; (size * size * 3) as usize]; 6];
+    let mut cached = CachedEnvironment {
+        irradiance_size: consts::IBL_IRRADIANCE_SIZE,
+        irradiance: faces(consts::IBL_IRRADIANCE_SIZE),
+        prefiltered_size: consts::IBL_PREFILTERED_SIZE,
+        prefiltered: (0..consts::IBL_PREFILTERED_LEVELS)
+            .map(|level| faces(mip_size(consts::IBL_PREFILTERED_SIZE, level)))
+            .collect(),
+    };
+    assert!(cached.validate().is_ok());
+
+    cached.prefiltered[2][5].pop();
+    assert!(cached.validate().is_err());
+    cached.prefiltered.pop();
+    assert!(cached.validate().is_err());
+}
@@ -1,22 +1,44 @@
 mod window;
-pub use window::make_window;
+pub use window::{WindowMode, WindowSettings, MonitorInfo};
+
+mod headless;
 
 mod camera;
 pub use camera::Camera;
 
 mod skybox;
 
+mod ibl;
+
+mod shadows;
+pub use shadows::{ShadowSettings, ShadowQuality};
+
 mod models;
 pub use models::{ModelsManager, Model};
 
 mod textures;
 pub use textures::{Texture, TexturesManager};
 
+mod texture_cache;
+
 mod renderer;
-pub use renderer::Renderer;
+pub use renderer::{Renderer, FrameStats};
 
 mod framebuilder;
 pub use framebuilder::FrameBuilder;
 
 mod shaders;
+
+mod postprocess;
+pub use postprocess::{PostProcessSettings, Tonemapper};
+
+mod text;
+
+mod ui;
+
+mod culling;
+pub use culling::{BoundingSphere, Frustum};
 mod vertex;
+
+#[cfg(test)]
+mod golden;
@@ -1,9 +1,10 @@
 use crate::engine::prelude::*;
 use std::collections::HashMap;
-use glium::{Display, VertexBuffer, IndexBuffer};
+use glium::{backend::Facade, VertexBuffer, IndexBuffer};
 use itertools::izip;
 
 use super::vertex::Vertex;
+use super::culling::BoundingSphere;
 
 pub struct Primitive<V: serde::Serialize + serde::de::DeserializeOwned + glium::Vertex + Copy> {
     pub vertices: VertexBuffer<V>,
@@ -16,11 +17,13 @@ pub struct Primitive<V: serde::Serialize + serde::de::DeserializeOwned + glium::
 
 pub struct Model<V: serde::Serialize + serde::de::DeserializeOwned + glium::Vertex + Copy> {
     pub primitives: Vec<Primitive<V>>,
+    pub bounds: BoundingSphere,
 }
 
 #[derive(Default, serde::Serialize, serde::Deserialize)]
 pub struct CachedModel<V> {
     pub primitives: Vec<CachedPrimitive<V>>,
+    pub bounds: BoundingSphere,
 }
 
 #[derive(Default, serde::Serialize, serde::Deserialize)]
@@ -33,13 +36,43 @@ pub struct CachedPrimitive<V> {
     pub occlusion: Option<Vec<u8>>,
 }
 
-impl<V: serde::Serialize + serde::de::DeserializeOwned + glium::Vertex + Copy> Model<V> {
-    fn from_cache<P: AsRef<std::path::Path>>(filename: P) -> anyhow::Result<CachedModel<V>> {
-        use anyhow::Context;
-        bincode::deserialize_from(std::io::BufReader::new(
-            std::fs::File::open(filename).context("Cached model does not exist")?
-        )).context("Failed loading cached model")
+/// A hash of a glTF file and the buffers and images it references by path
+fn source_hash(path: &std::path::Path) -> anyhow::Result<u64> {
+    use anyhow::Context;
+    let data = std::fs::read(path).context(format!("Failed opening model {}", path.to_string_lossy()))?;
+    let gltf = gltf::Gltf::from_slice(&data)?;
+    let uris = gltf.buffers()
+        .filter_map(|b| match b.source() {
+            gltf::buffer::Source::Uri(uri) => Some(uri),
+            gltf::buffer::Source::Bin => None,
+        })
+        .chain(gltf.images().filter_map(|i| match i.source() {
+            gltf::image::Source::Uri { uri, .. } => Some(uri),
+            gltf::image::Source::View { .. } => None,
+        }))
+        .filter(|uri| !uri.starts_with("data:"));
+
+    let parent = path.parent().unwrap_or_else(|| std::path::Path::new("./"));
+    let mut hash = utils::content_hash(&data);
+    for uri in uris {
+        let referenced = std::fs::read(parent.join(uri)).context(format!("Failed opening {}", uri))?;
+        let combined: Vec<u8> = hash.to_le_bytes().iter()
+            .chain(utils::content_hash(&referenced).to_le_bytes().iter())
+            .cloned()
+            .collect();
+        hash = utils::content_hash(&combined);
     }
+    Ok(hash)
+}
+
+/// Where a model's cache goes: next to it, or under the same relative path in `cache_dir`
+fn cache_path(path: &std::path::Path, cache_dir: Option<&std::path::Path>) -> std::path::PathBuf {
+    let mut filename = path.file_name().unwrap_or_default().to_os_string();
+    filename.push(consts::MODEL_CACHE_EXTENSION);
+    utils::cache_location(path.with_file_name(filename), cache_dir)
+}
+
+impl<V: serde::Serialize + serde::de::DeserializeOwned + glium::Vertex + Copy> Model<V> {
 
     fn gltf_get_texture<P: AsRef<std::path::Path>>
     (tex: &gltf::Texture<'_>,
@@ -147,12 +180,16 @@ impl<V: serde::Serialize + serde::de::DeserializeOwned + glium::Vertex + Copy> M
             }
         }
 
+        result.bounds = BoundingSphere::from_points(
+            result.primitives.iter().flat_map(|p| p.vertices.iter().map(|v| v.position))
+        );
         Ok(result)
     }
 
-    pub fn from_data(cached: CachedModel<V>, display: &Display)
+    /// Builds the GPU buffers of a model, with its textures cached in `cache_dir` if set
+    pub fn from_data(cached: CachedModel<V>, display: &dyn Facade, cache_dir: Option<&std::path::Path>)
     -> anyhow::Result<Model<V>> {
-        use glium::texture::{RawImage2d, CompressedSrgbTexture2d, CompressedTexture2d};
+        use super::texture_cache::{load_srgb, load_linear};
         use anyhow::Context;
         let mut primitives = Vec::new();
 
@@ -160,58 +197,43 @@ impl<V: serde::Serialize + serde::de::DeserializeOwned + glium::Vertex + Copy> M
             primitives.push(Primitive {
                 vertices: VertexBuffer::immutable(display, &p.vertices)?,
                 indices: IndexBuffer::immutable(display, glium::index::PrimitiveType::TrianglesList, &p.indices)?,
-                albedo: if let Some(alb) = p.albedo {
-                    let tex = image::load_from_memory(&alb).context("Failed to load albedo texture")?.to_rgba();
-                    let dims =  tex.dimensions();
-                    let raw_image = RawImage2d::from_raw_rgba(tex.into_raw(), dims);
-                    Some(CompressedSrgbTexture2d::new(display, raw_image).context("Failed to create albedo texture")?)
-                } else { None },
-                normalmap: if let Some(norm) = p.normalmap {
-                    let tex = image::load_from_memory(&norm).context("Failed to load normal map")?.to_rgb();
-                    let dims = tex.dimensions();
-                    let raw_image = RawImage2d::from_raw_rgb(tex.into_raw(), dims);
-                    Some(CompressedTexture2d::new(display, raw_image).context("Failed to create normalmap")?)
-                } else { None },
-                metal_roughness: if let Some(mr) = p.metal_roughness {
-                    let tex = image::load_from_memory(&mr).context("Failed to load metal/roughness map")?.to_rgb();
-                    let dims = tex.dimensions();
-                    let raw_image = RawImage2d::from_raw_rgb(tex.into_raw(), dims);
-                    Some(CompressedTexture2d::new(display, raw_image).context("Failed to create metal/roughness map")?)
-                } else { None },
-                occlusion: if let Some(occ) = p.occlusion {
-                    let tex = image::load_from_memory(&occ).context("Failed to load AO map")?.to_rgb();
-                    let dims = tex.dimensions();
-                    let raw_image = RawImage2d::from_raw_rgb(tex.into_raw(), dims);
-                    Some(CompressedTexture2d::new(display, raw_image).context("Failed to create AO map")?)
-                } else { None },
+                albedo: p.albedo.map(|alb| load_srgb(display, &alb, cache_dir)).transpose()
+                    .context("Failed to load albedo texture")?,
+                normalmap: p.normalmap.map(|norm| load_linear(display, &norm, cache_dir)).transpose()
+                    .context("Failed to load normal map")?,
+                metal_roughness: p.metal_roughness.map(|mr| load_linear(display, &mr, cache_dir)).transpose()
+                    .context("Failed to load metal/roughness map")?,
+                occlusion: p.occlusion.map(|occ| load_linear(display, &occ, cache_dir)).transpose()
+                    .context("Failed to load AO map")?,
             });
         }
-        Ok(Model {primitives})
+        Ok(Model {primitives, bounds: cached.bounds})
     }
 
-    pub fn from<P: AsRef<std::path::Path>>(filename: P, display: &Display) -> anyhow::Result<Model<Vertex>> {
-        use anyhow::Context;
+    /// Loads a model from its cache, which is rebuilt when it's missing or doesn't match
+    pub fn from<P: AsRef<std::path::Path>>(filename: P, display: &dyn Facade, cache_dir: Option<&std::path::Path>)
+    -> anyhow::Result<Model<Vertex>> {
         let path = std::path::PathBuf::from("./resources/models/").join(&filename);
-        let model_data = match utils::should_load_from_cache(&path) {
-            (true, Some(cache_path)) => Model::from_cache(cache_path),
-            (false, Some(cache_path)) => {
-                let m = Model::<Vertex>::from_gltf(path)?;
-                let mut file = std::fs::File::create(&cache_path).context("Error creating cache file")?;
-                bincode::serialize_into(&mut file, &m).context("Error serializing")?;
-                Ok(m)
+        let hash = source_hash(&path)?;
+        let cache_path = cache_path(&path, cache_dir);
+
+        let header = utils::CacheHeader::new(consts::MODEL_CACHE_VERSION, hash);
+        let cached = utils::read_cache::<CachedModel<Vertex>, _>(&cache_path, consts::MODEL_CACHE_MAGIC, &header);
+        let model_data = match cached {
+            Ok(m) => m,
+            Err(e) => {
+                log::info(&format!("{:#}, rebuilding {}", e, cache_path.to_string_lossy()));
+                let m = Model::<Vertex>::from_gltf(&path)?;
+                if let Err(e) = utils::write_cache(&cache_path, consts::MODEL_CACHE_MAGIC, &header, &m) {
+                    log::err(&e);
+                }
+                m
             },
-            _ => {
-                Model::<Vertex>::from_gltf(path)
-            }
         };
-
-        match model_data {
-            Ok(data) => Model::from_data(data, display),
-            Err(e) => Err(e),
-        }
+        Model::from_data(model_data, display, cache_dir)
     }
 
-    pub fn cube(display: &Display) -> Model<Vertex> {
+    pub fn cube(display: &dyn Facade) -> Model<Vertex> {
         Model::<Vertex>::from_data(CachedModel { primitives: vec![CachedPrimitive {
             vertices: vec![
                 // +z
@@ -386,27 +408,38 @@ impl<V: serde::Serialize + serde::de::DeserializeOwned + glium::Vertex + Copy> M
             normalmap: None,
             metal_roughness: None,
             occlusion: None,
-        }]}, display).expect("Failed to create cube")
+        }],
+        bounds: BoundingSphere {
+            center: [0.0, 0.0, 0.0],
+            radius: 3.0f32.sqrt(),
+        }}, display, None).expect("Failed to create cube")
     }
 }
 
 pub struct ModelsManager {
     models: HashMap<String, Model<Vertex>>,
     default_model: Model<Vertex>,
+    cache_dir: Option<std::path::PathBuf>,
 }
 impl ModelsManager {
-    pub fn new(display: &Display) -> ModelsManager {
+    pub fn new(display: &dyn Facade) -> ModelsManager {
         ModelsManager {
             models: HashMap::new(),
             default_model: Model::<Vertex>::cube(display),
+            cache_dir: None,
         }
     }
 
+    /// Keeps model caches, and the caches of their textures, in their own folder
+    pub fn set_cache_dir(&mut self, cache_dir: Option<std::path::PathBuf>) {
+        self.cache_dir = cache_dir;
+    }
+
     pub fn get(&self, name: &str) -> &Model<Vertex> {
         self.models.get(name).unwrap_or(&self.default_model)
     }
 
-    pub fn try_load(&mut self, display: &Display, name: &str) -> anyhow::Result<()> {
+    pub fn try_load(&mut self, display: &dyn Facade, name: &str) -> anyhow::Result<()> {
         use anyhow::Context;
         if self.models.contains_key(name) {
             return Ok(())
@@ -414,8 +447,19 @@ impl ModelsManager {
 
         self.models.insert(
             String::from(name),
-            Model::<Vertex>::from(name, display).context(format!("Failed to load {}", name))?
+            Model::<Vertex>::from(name, display, self.cache_dir.as_deref()).context(format!("Failed to load {}", name))?
         );
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn caches_stay_inside_the_cache_dir() {
+    use std::path::Path;
+    assert_eq!(cache_path(Path::new("./resources/models/ship.gltf"), None),
+        Path::new("./resources/models/ship.gltf.cache"));
+    assert_eq!(cache_path(Path::new("./resources/models/ship.gltf"), Some(Path::new("cache"))),
+        Path::new("cache/resources/models/ship.gltf.cache"));
+    assert_eq!(cache_path(Path::new("./resources/models/../../ship.gltf"), Some(Path::new("cache"))),
+        Path::new("cache/resources/models/ship.gltf.cache"));
+}
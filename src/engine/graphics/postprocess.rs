@@ -0,0 +1,303 @@
+use crate::engine::prelude::*;
+use glium::backend::Facade;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::{Texture2d, UncompressedFloatFormat, MipmapsOption};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, SamplerWrapFunction, Uniforms};
+use glium::{Surface, VertexBuffer, uniform};
+use super::shaders::{self, ShaderProgram};
+use super::vertex::Vertex2d;
+use std::time::Instant;
+
+/// How the HDR image is mapped to the screen's range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tonemapper {
+    /// Clamps, for debugging the raw lighting
+    None,
+    Reinhard,
+    Aces,
+    Filmic,
+}
+
+impl Tonemapper {
+    /// Matches the TONEMAP_* constants of tonemapping.glsl
+    pub fn shader_id(self) -> i32 {
+        match self {
+            Tonemapper::None => 0,
+            Tonemapper::Reinhard => 1,
+            Tonemapper::Aces => 2,
+            Tonemapper::Filmic => 3,
+        }
+    }
+}
+
+impl std::str::FromStr for Tonemapper {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Tonemapper::None),
+            "reinhard" => Ok(Tonemapper::Reinhard),
+            "aces" => Ok(Tonemapper::Aces),
+            "filmic" => Ok(Tonemapper::Filmic),
+            _ => Err(anyhow!("Unknown tonemapper {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Tonemapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Tonemapper::None => "none",
+            Tonemapper::Reinhard => "reinhard",
+            Tonemapper::Aces => "aces",
+            Tonemapper::Filmic => "filmic",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PostProcessSettings {
+    pub bloom: bool,
+
+    /// How much of the bloom is added onto the image
+    pub bloom_intensity: f32,
+    pub tonemapper: Tonemapper,
+    pub gamma: f32,
+
+    /// Scales the exposure to the average brightness of the scene, adapting over time
+    pub auto_exposure: bool,
+
+    /// Fixed exposure, or a multiplier of the automatic one
+    pub exposure: f32,
+}
+
+impl Default for PostProcessSettings {
+    fn default() -> Self {
+        PostProcessSettings {
+            bloom: true,
+            bloom_intensity: consts::DEFAULT_BLOOM_INTENSITY,
+            tonemapper: Tonemapper::Aces,
+            gamma: consts::DEFAULT_GAMMA,
+            auto_exposure: true,
+            exposure: 1.0,
+        }
+    }
+}
+
+/// The passes between the PBR pass and the composition: bloom and the scene's luminance.
+pub struct PostProcess {
+    settings: PostProcessSettings,
+    program_downsample: ShaderProgram,
+    program_upsample: ShaderProgram,
+    program_luminance: ShaderProgram,
+    program_reduce: ShaderProgram,
+    program_adapt: ShaderProgram,
+
+    // Each level half the size of the previous one, starting at half the resolution
+    bloom_chain: Vec<Texture2d>,
+
+    // Log luminance, averaged down to a single texel
+    luminance_chain: Vec<Texture2d>,
+
+    // Ping-ponged, since the adaptation reads the previous frame's value
+    adapted: [Texture2d; 2],
+    adapted_current: usize,
+    last_run: Option<Instant>,
+}
+
+fn texture(display: &dyn Facade, format: UncompressedFloatFormat, size: [u32; 2]) -> Texture2d {
+    Texture2d::empty_with_format(display, format, MipmapsOption::NoMipmap, size[0], size[1]).unwrap()
+}
+
+fn build_bloom_chain(display: &dyn Facade, resolution: [u32; 2]) -> Vec<Texture2d> {
+    (1..=consts::BLOOM_LEVELS)
+        .map(|level| texture(display, UncompressedFloatFormat::F16F16F16F16, [
+            (resolution[0] >> level).max(1),
+            (resolution[1] >> level).max(1),
+        ]))
+        .collect()
+}
+
+fn linear(texture: &Texture2d) -> glium::uniforms::Sampler<Texture2d> {
+    texture.sampled()
+        .magnify_filter(MagnifySamplerFilter::Linear)
+        .minify_filter(MinifySamplerFilter::Linear)
+        .wrap_function(SamplerWrapFunction::Clamp)
+}
+
+fn texel_size(texture: &Texture2d) -> [f32; 2] {
+    [1.0 / texture.width() as f32, 1.0 / texture.height() as f32]
+}
+
+/// Draws a fullscreen quad into `target`, optionally adding onto what's already there.
+fn pass<U: Uniforms>(
+    display: &dyn Facade,
+    quad: &VertexBuffer<Vertex2d>,
+    target: &Texture2d,
+    program: &ShaderProgram,
+    uniforms: &U,
+    additive: bool,
+) {
+    let mut fb = SimpleFrameBuffer::new(display, target).unwrap();
+    let params = glium::DrawParameters {
+        blend: if additive {
+            let add = glium::BlendingFunction::Addition {
+                source: glium::LinearBlendingFactor::One,
+                destination: glium::LinearBlendingFactor::One,
+            };
+            glium::Blend {
+                color: add,
+                alpha: add,
+                constant_value: (0.0, 0.0, 0.0, 0.0),
+            }
+        } else {
+            Default::default()
+        },
+        ..Default::default()
+    };
+    fb.draw(
+        quad,
+        glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+        program,
+        uniforms,
+        &params,
+    ).unwrap();
+}
+
+impl PostProcess {
+    pub fn new(display: &dyn Facade, resolution: [u32; 2]) -> anyhow::Result<PostProcess> {
+        let mut luminance_chain = Vec::new();
+        let mut size = consts::LUMINANCE_SIZE;
+        loop {
+            luminance_chain.push(texture(display, UncompressedFloatFormat::F32, [size, size]));
+            if size == 1 {
+                break;
+            }
+            size = (size / 4).max(1);
+        }
+
+        Ok(PostProcess {
+            settings: PostProcessSettings::default(),
+            program_downsample: shaders::fullscreen(display, "bloom_downsample", "bloom_downsample.frag")?,
+            program_upsample: shaders::fullscreen(display, "bloom_upsample", "bloom_upsample.frag")?,
+            program_luminance: shaders::fullscreen(display, "luminance", "luminance.frag")?,
+            program_reduce: shaders::fullscreen(display, "luminance_reduce", "luminance_reduce.frag")?,
+            program_adapt: shaders::fullscreen(display, "luminance_adapt", "luminance_adapt.frag")?,
+            bloom_chain: build_bloom_chain(display, resolution),
+            luminance_chain,
+            adapted: [
+                texture(display, UncompressedFloatFormat::F32, [1, 1]),
+                texture(display, UncompressedFloatFormat::F32, [1, 1]),
+            ],
+            adapted_current: 0,
+            last_run: None,
+        })
+    }
+
+    pub fn settings(&self) -> PostProcessSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: PostProcessSettings) {
+        // Don't fade in from whatever was measured before auto exposure was turned off
+        if settings.auto_exposure && !self.settings.auto_exposure {
+            self.last_run = None;
+        }
+        self.settings = settings;
+    }
+
+    pub fn resize(&mut self, display: &dyn Facade, resolution: [u32; 2]) {
+        self.bloom_chain = build_bloom_chain(display, resolution);
+    }
+
+    pub fn hot_reload(&mut self, display: &dyn Facade) {
+        for program in vec![
+            &mut self.program_downsample,
+            &mut self.program_upsample,
+            &mut self.program_luminance,
+            &mut self.program_reduce,
+            &mut self.program_adapt,
+        ] {
+            program.reload_if_changed(display);
+        }
+    }
+
+    /// Runs the enabled passes on the PBR pass's HDR color
+    pub fn run(&mut self, display: &dyn Facade, quad: &VertexBuffer<Vertex2d>, color: &Texture2d) {
+        if self.settings.bloom {
+            self.run_bloom(display, quad, color);
+        }
+        if self.settings.auto_exposure {
+            self.run_luminance(display, quad, color);
+        }
+    }
+
+    fn run_bloom(&self, display: &dyn Facade, quad: &VertexBuffer<Vertex2d>, color: &Texture2d) {
+        // Only the first downsample keeps just the bright parts
+        let mut source = color;
+        let mut threshold = consts::BLOOM_THRESHOLD;
+        for level in self.bloom_chain.iter() {
+            pass(display, quad, level, &self.program_downsample, &uniform! {
+                source: linear(source),
+                texel_size: texel_size(source),
+                threshold: threshold,
+            }, false);
+            source = level;
+            threshold = -1.0;
+        }
+
+        // Back up the chain, blurring each level onto the larger one
+        for pair in self.bloom_chain.windows(2).rev() {
+            pass(display, quad, &pair[0], &self.program_upsample, &uniform! {
+                source: linear(&pair[1]),
+                texel_size: texel_size(&pair[1]),
+            }, true);
+        }
+    }
+
+    fn run_luminance(&mut self, display: &dyn Facade, quad: &VertexBuffer<Vertex2d>, color: &Texture2d) {
+        pass(display, quad, &self.luminance_chain[0], &self.program_luminance, &uniform! {
+            color: linear(color),
+        }, false);
+        for pair in self.luminance_chain.windows(2) {
+            pass(display, quad, &pair[1], &self.program_reduce, &uniform! {
+                source: &pair[0],
+            }, false);
+        }
+
+        // Exponential decay towards the measured luminance, independent of the framerate
+        let now = Instant::now();
+        let rate = match self.last_run {
+            Some(last) => 1.0 - (-(now - last).as_secs_f32() * consts::EXPOSURE_ADAPTATION_SPEED).exp(),
+            None => 1.0,
+        };
+        self.last_run = Some(now);
+
+        let next = 1 - self.adapted_current;
+        pass(display, quad, &self.adapted[next], &self.program_adapt, &uniform! {
+            previous: &self.adapted[self.adapted_current],
+            current: self.luminance_chain.last().unwrap(),
+            rate: rate,
+        }, false);
+        self.adapted_current = next;
+    }
+
+    /// The blurred bright parts, sampled linearly, at half resolution
+    pub fn bloom_texture(&self) -> glium::uniforms::Sampler<Texture2d> {
+        linear(&self.bloom_chain[0])
+    }
+
+    /// The log of the adapted average luminance, in a single texel
+    pub fn luminance_texture(&self) -> &Texture2d {
+        &self.adapted[self.adapted_current]
+    }
+}
+
+#[test]
+fn tonemapper_round_trips_through_config_strings() {
+    for t in &[Tonemapper::None, Tonemapper::Reinhard, Tonemapper::Aces, Tonemapper::Filmic] {
+        assert_eq!(t.to_string().parse::<Tonemapper>().unwrap(), *t);
+    }
+    assert_eq!("ACES".parse::<Tonemapper>().unwrap(), Tonemapper::Aces);
+    assert!("bogus".parse::<Tonemapper>().is_err());
+}
@@ -1,13 +1,20 @@
 use crate::engine::prelude::*;
 use glium::glutin::event_loop::EventLoop;
 use glium::{Display, Surface, VertexBuffer};
-use glium::program::Program;
+use glium::backend::Facade;
+use super::shaders::ShaderProgram;
+use super::postprocess::{PostProcess, PostProcessSettings};
+use super::text::TextRenderer;
+use super::ui::UiRenderer;
+use super::ibl::{IblManager, IblBindings};
+use super::shadows::{ShadowMaps, ShadowSettings};
+use super::window::{WindowSettings, MonitorInfo};
 use glium::uniforms::UniformValue;
 use glium::texture::{UnsignedTexture2d, Texture2d, pixel_buffer::PixelBuffer, CompressedSrgbTexture2d,
     CompressedTexture2d};
-use glium::framebuffer::{ColorAttachment, MultiOutputFrameBuffer, DepthRenderBuffer};
+use glium::framebuffer::{ColorAttachment, MultiOutputFrameBuffer, DepthRenderBuffer, SimpleFrameBuffer};
 use super::{ModelsManager, Model, TexturesManager, Texture, vertex::{Vertex2d, VertexSkybox}};
-use crate::engine::systems::MeshInstance;
+use crate::engine::systems::{MeshInstance, LightInstance};
 use glium::uniform;
 use rental::rental;
 
@@ -15,15 +22,26 @@ pub struct Fbos {
     pub color: Texture2d,
     pub pick: UnsignedTexture2d,
     pub depth: DepthRenderBuffer,
+
+    // The composed frame, for when it isn't presented to a window
+    pub output: Texture2d,
 }
 
-struct PointLight {
-    pos: [f32; 3],
-    color: [f32; 3],
+/// Where the renderer's frames end up
+pub enum RenderContext {
+    Window(Display),
+
+    /// Frames are only composed into an offscreen texture, which can be read back.
+    Headless(Box<dyn Facade>),
 }
 
-struct Lights {
-    pointlights: [PointLight; consts::DEFAULT_MAX_LIGHTS]
+impl RenderContext {
+    pub fn facade(&self) -> &dyn Facade {
+        match self {
+            RenderContext::Window(display) => display,
+            RenderContext::Headless(headless) => headless.as_ref(),
+        }
+    }
 }
 
 struct PbrUniforms<'a> {
@@ -34,9 +52,10 @@ struct PbrUniforms<'a> {
     normal_map: &'a CompressedTexture2d,
     ao: &'a CompressedTexture2d,
 
-    lights: &'a Lights,
+    lights: &'a [LightInstance],
     camera_position: nalgebra::Point3<f32>,
-    exposure: f32,
+    ibl: &'a IblBindings<'a>,
+    shadows: &'a ShadowMaps,
 }
 impl<'a> glium::uniforms::Uniforms for PbrUniforms<'a> {
     fn visit_values<'b, F: FnMut(&str, UniformValue<'b>)>(&'b self, mut f: F) {
@@ -51,12 +70,37 @@ impl<'a> glium::uniforms::Uniforms for PbrUniforms<'a> {
         f("metallic_rough", UniformValue::CompressedTexture2d(self.metallic_rough, sampler));
         f("normal_map", UniformValue::CompressedTexture2d(self.normal_map, sampler));
         f("ao", UniformValue::CompressedTexture2d(self.ao, sampler));
+        f("irradiance_map", UniformValue::Cubemap(self.ibl.irradiance, Some(glium::uniforms::SamplerBehavior {
+            magnify_filter: glium::uniforms::MagnifySamplerFilter::Linear,
+            minify_filter: glium::uniforms::MinifySamplerFilter::Linear,
+            ..Default::default()
+        })));
+        f("prefiltered_map", UniformValue::Cubemap(self.ibl.prefiltered, sampler));
+        f("brdf_lut", UniformValue::Texture2d(self.ibl.brdf_lut, Some(glium::uniforms::SamplerBehavior {
+            magnify_filter: glium::uniforms::MagnifySamplerFilter::Linear,
+            minify_filter: glium::uniforms::MinifySamplerFilter::Linear,
+            wrap_function: (
+                glium::uniforms::SamplerWrapFunction::Clamp,
+                glium::uniforms::SamplerWrapFunction::Clamp,
+                glium::uniforms::SamplerWrapFunction::Clamp,
+            ),
+            ..Default::default()
+        })));
+        f("ibl_enabled", UniformValue::SignedInt(self.ibl.enabled as i32));
+        f("prefiltered_max_lod", UniformValue::Float(self.ibl.prefiltered_max_lod));
         f("cameraPosition", UniformValue::Vec3(point_to_floats(self.camera_position.into())));
-        f("exposure", UniformValue::Float(self.exposure));
-        for i in 0..consts::DEFAULT_MAX_LIGHTS {
-            f(&format!("pointlights[{}].pos", i), UniformValue::Vec3(self.lights.pointlights[i].pos));
-            f(&format!("pointlights[{}].color", i), UniformValue::Vec3(self.lights.pointlights[i].color));
+        f("num_lights", UniformValue::SignedInt(self.lights.len() as i32));
+        for (i, light) in self.lights.iter().enumerate() {
+            f(&format!("lights[{}].kind", i), UniformValue::SignedInt(light.kind));
+            f(&format!("lights[{}].pos", i), UniformValue::Vec3(light.position));
+            f(&format!("lights[{}].direction", i), UniformValue::Vec3(light.direction));
+            f(&format!("lights[{}].color", i), UniformValue::Vec3(light.color));
+            f(&format!("lights[{}].range", i), UniformValue::Float(light.range));
+            f(&format!("lights[{}].cos_inner", i), UniformValue::Float(light.cos_inner));
+            f(&format!("lights[{}].cos_outer", i), UniformValue::Float(light.cos_outer));
+            f(&format!("lights[{}].shadow", i), UniformValue::SignedInt(self.shadows.light_view(i)));
         }
+        self.shadows.visit_values(&mut f);
     }
 }
 
@@ -74,14 +118,30 @@ rental! {
 
 pub struct Renderer {
     // Basic singletons
-    display: Display,
+    context: RenderContext,
+
+    // The scene is rendered at `resolution`, a fraction (`render_scale`) of the window's
+    // size in physical pixels, then scaled up to the window
     resolution: [u32; 2],
+    window_size: [u32; 2],
+    render_scale: f32,
+
+    // Physical pixels per logical pixel, which the labels and the UI are sized in
+    scale_factor: f64,
     projection: nalgebra::Matrix4<f32>,
-    program_pbr: Program,
-    program_skybox: Program,
-    program_composition: Program,
+    program_pbr: ShaderProgram,
+    program_skybox: ShaderProgram,
+    program_composition: ShaderProgram,
+    post: PostProcess,
+    text: TextRenderer,
+    ui: UiRenderer,
+    ibl: IblManager,
+    shadows: ShadowMaps,
+    last_shader_check: std::time::Instant,
     resolution_dependents: rentals::ResolutionDependents,
     instance_buffer: VertexBuffer<MeshInstance>,
+    instance_buffer_offset: usize,
+    visible_instances: Vec<MeshInstance>,
     quad_vbuffer: VertexBuffer<Vertex2d>,
     skybox_model: Model<VertexSkybox>,
 
@@ -89,8 +149,52 @@ pub struct Renderer {
     textures_manager: TexturesManager,
     latest_pick_result: Option<u32>,
     picking_pbo: PixelBuffer<u32>,
+    pending_screenshot: Option<std::path::PathBuf>,
+    stats: FrameStats,
+    max_lights: usize,
+    frame_lights: Vec<LightInstance>,
+}
+
+/// Counts from the last drawn frame, for diagnostics
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameStats {
+    /// Mesh instances that were inside the view and drawn
+    pub drawn_instances: usize,
+
+    /// Mesh instances outside the view, that were skipped
+    pub culled_instances: usize,
+
+    /// Mesh instances drawn into the shadow maps, counted once per shadow view
+    pub shadow_instances: usize,
+}
 
-    pub light_pos: [f32; 3],
+/// The scene resolution for a window size at a render scale
+fn render_resolution(window_size: [u32; 2], render_scale: f32) -> [u32; 2] {
+    [
+        ((window_size[0] as f32 * render_scale).round() as u32).max(1),
+        ((window_size[1] as f32 * render_scale).round() as u32).max(1),
+    ]
+}
+
+/// The pixel of the scene under the cursor, from the bottom left as OpenGL counts them.
+/// The cursor is in the window's physical pixels, from the top left.
+fn pick_pixel(cursor: [u32; 2], window_size: [u32; 2], resolution: [u32; 2]) -> [u32; 2] {
+    let scale = |c: u32, window: u32, scene: u32| utils::clamp(
+        ((c as f64 + 0.5) * scene as f64 / window.max(1) as f64) as u32, 0, scene - 1
+    );
+    let x = scale(cursor[0], window_size[0], resolution[0]);
+    let y = scale(cursor[1], window_size[1], resolution[1]);
+    [x, resolution[1] - 1 - y]
+}
+
+/// The ranges of `count` instances that are uploaded and drawn together, none longer than `cap`
+fn instance_batches(count: usize, cap: usize) -> impl Iterator<Item = std::ops::Range<usize>> {
+    (0..count).step_by(cap.max(1)).map(move |start| start..count.min(start + cap))
+}
+
+/// How big the instance buffer grows to hold a batch
+fn instance_buffer_capacity(batch: usize) -> usize {
+    batch.next_power_of_two().min(consts::MAX_INSTANCE_BUFFER_SIZE)
 }
 
 fn matrix_to_floats(m: nalgebra::Matrix4<f32>) -> [[f32; 4]; 4] {
@@ -106,28 +210,86 @@ fn point_to_floats(p: nalgebra::Point3<f32>) -> [f32; 3] {
 }
 
 impl Renderer {
-    pub fn get_display(&self) -> &Display {
-        &self.display
+    /// The window's display, or None for a headless renderer.
+    pub fn get_display(&self) -> Option<&Display> {
+        match &self.context {
+            RenderContext::Window(display) => Some(display),
+            RenderContext::Headless(_) => None,
+        }
+    }
+
+    pub fn window_id(&self) -> Option<winit::window::WindowId> {
+        self.get_display().map(|d| d.gl_window().window().id())
+    }
+
+    pub fn request_redraw(&self) {
+        if let Some(display) = self.get_display() {
+            display.gl_window().window().request_redraw();
+        }
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.get_display().is_none()
     }
 
     pub fn get_pick_result(&self) -> Option<u32> {
         self.latest_pick_result
     }
 
-    pub fn new(eventloop: &EventLoop<()>) -> Renderer {
-        let display = super::window::make_window(eventloop);
-        let program_pbr = super::shaders::pbr(&display);
-        let program_composition = super::shaders::composition(&display);
-        let program_skybox = super::shaders::static_skybox(&display);
-        let resolution = consts::DEFAULT_RESOLUTION;
-        let models_manager = ModelsManager::new(&display);
-        let textures_manager = TexturesManager::new(&display);
+    /// The window's size in physical pixels
+    pub fn get_window_size(&self) -> [u32; 2] {
+        self.window_size
+    }
+
+    /// The window's size in logical pixels, which the UI is laid out in
+    pub fn get_logical_size(&self) -> [f32; 2] {
+        [
+            (self.window_size[0] as f64 / self.scale_factor) as f32,
+            (self.window_size[1] as f64 / self.scale_factor) as f32,
+        ]
+    }
+
+    pub fn get_scale_factor(&self) -> f64 {
+        self.scale_factor
+    }
+
+    pub fn get_frame_stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    pub fn new(eventloop: &EventLoop<()>, settings: &WindowSettings) -> anyhow::Result<Renderer> {
+        let mut renderer = Renderer::from_context(
+            RenderContext::Window(super::window::make_window(eventloop, settings)?),
+            settings.resolution,
+        )?;
+        let scale_factor = renderer.get_display().unwrap().gl_window().window().scale_factor();
+        renderer.scale_factor = scale_factor;
+        renderer.set_window_settings(settings);
+        Ok(renderer)
+    }
+
+    /// A renderer without a window, that composes frames offscreen for `read_frame`.
+    pub fn new_headless(resolution: [u32; 2]) -> anyhow::Result<Renderer> {
+        let context = super::headless::make_headless_context(resolution)?;
+        Renderer::from_context(RenderContext::Headless(context), resolution)
+    }
 
-        let picking_pbo: PixelBuffer<u32> = PixelBuffer::new_empty(&display, 1);
+    fn from_context(context: RenderContext, resolution: [u32; 2]) -> anyhow::Result<Renderer> {
+        let display = context.facade();
+        let program_pbr = super::shaders::pbr(display, consts::DEFAULT_MAX_LIGHTS)?;
+        let program_composition = super::shaders::composition(display)?;
+        let program_skybox = super::shaders::static_skybox(display)?;
+        let post = PostProcess::new(display, resolution)?;
+        let text = TextRenderer::new(display)?;
+        let ui = UiRenderer::new(display)?;
+        let models_manager = ModelsManager::new(display);
+        let textures_manager = TexturesManager::new(display);
+
+        let picking_pbo: PixelBuffer<u32> = PixelBuffer::new_empty(display, 1);
         let instance_buffer = VertexBuffer::empty_dynamic(
-            &display, consts::DEFAULT_INSTANCE_BUFFER_SIZE
+            display, consts::DEFAULT_INSTANCE_BUFFER_SIZE
         ).unwrap();
-        let quad_vbuffer = VertexBuffer::immutable(&display, &[
+        let quad_vbuffer = VertexBuffer::immutable(display, &[
             Vertex2d {
                 position: [-1.0, 1.0],
                 texcoord: [0.0, 1.0],
@@ -153,38 +315,55 @@ impl Renderer {
                 texcoord: [0.0, 0.0],
             },
         ]).unwrap();
+        let ibl = IblManager::new(display)?;
+        let shadows = ShadowMaps::new(display)?;
         let resolution_dependents = Renderer::build_resolution_dependents(
-            &display,
-            resolution
+            display,
+            resolution,
+            resolution,
         );
 
-        let skybox_model = Renderer::create_skybox_vbuffer(&display);
+        let skybox_model = Renderer::create_skybox_vbuffer(display);
 
-        Renderer {
-            display,
+        Ok(Renderer {
+            context,
             program_pbr,
             program_composition,
             program_skybox,
+            post,
+            text,
+            ui,
+            ibl,
+            shadows,
+            last_shader_check: std::time::Instant::now(),
             models_manager,
             textures_manager,
             latest_pick_result: None,
             resolution_dependents,
             instance_buffer,
+            instance_buffer_offset: 0,
+            visible_instances: Vec::new(),
             quad_vbuffer,
             skybox_model,
             picking_pbo,
+            pending_screenshot: None,
+            stats: FrameStats::default(),
+            max_lights: consts::DEFAULT_MAX_LIGHTS,
+            frame_lights: Vec::new(),
             resolution,
-            light_pos: [0.4f32, 0.7, 0.25],
+            window_size: resolution,
+            render_scale: 1.0,
+            scale_factor: 1.0,
             projection: nalgebra::Matrix4::new_perspective(
-                consts::DEFAULT_ASPECT_RATIO,
+                resolution[0] as f32 / resolution[1] as f32,
                 consts::DEFAULT_VERTICAL_FOV_DEG * std::f32::consts::PI / 180.0,
                 consts::DEFAULT_NEAR_CLIP,
                 consts::DEFAULT_FAR_CLIP,
             )
-        }
+        })
     }
 
-    pub fn build_resolution_dependents(display: &Display, resolution: [u32; 2]) -> 
+    pub fn build_resolution_dependents(display: &dyn Facade, resolution: [u32; 2], window_size: [u32; 2]) ->
     rentals::ResolutionDependents {
         rentals::ResolutionDependents::new(
             Box::new(Fbos {
@@ -208,6 +387,13 @@ impl Renderer {
                     resolution[0],
                     resolution[1],
                 ).unwrap(),
+                output: Texture2d::empty_with_format(
+                    display,
+                    glium::texture::UncompressedFloatFormat::U8U8U8U8,
+                    glium::texture::MipmapsOption::NoMipmap,
+                    window_size[0],
+                    window_size[1],
+                ).unwrap(),
             }),
             |fbos| {
                 (MultiOutputFrameBuffer::with_depth_buffer(
@@ -222,23 +408,89 @@ impl Renderer {
         )
     }
 
-    pub fn resize_window(&mut self, dims: [u32; 2]) {
-        self.get_display().gl_window().window().set_inner_size(winit::dpi::LogicalSize::new(dims[0], dims[1]));
-        self.resolution = dims;
+    /// Switches the window's mode, monitor and size. A headless renderer just resizes.
+    pub fn set_window_settings(&mut self, settings: &WindowSettings) {
+        let dims = match self.get_display() {
+            Some(display) => super::window::apply_settings(display.gl_window().window(), settings),
+            None => settings.resolution,
+        };
+        self.resize(dims);
+    }
+
+    /// Every monitor connected, with its video modes. Empty for a headless renderer.
+    pub fn get_monitors(&self) -> Vec<MonitorInfo> {
+        match self.get_display() {
+            Some(display) => super::window::monitors(display.gl_window().window()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Rebuilds everything sized to the window, after it was resized. `dims` are in physical pixels.
+    pub fn resize(&mut self, dims: [u32; 2]) {
+        // Minimized windows are 0x0
+        if dims == self.window_size || dims[0] == 0 || dims[1] == 0 {
+            return;
+        }
+        self.window_size = dims;
+        self.rebuild_resolution_dependents();
+    }
+
+    /// Renders the scene at a fraction of the window's size, scaled up when composed.
+    /// The labels and the UI stay at the window's size.
+    pub fn set_render_scale(&mut self, render_scale: f32) {
+        let render_scale = utils::clamp(render_scale, consts::MIN_RENDER_SCALE, consts::MAX_RENDER_SCALE);
+        if render_scale == self.render_scale {
+            return;
+        }
+        self.render_scale = render_scale;
+        self.rebuild_resolution_dependents();
+    }
+
+    pub fn get_render_scale(&self) -> f32 {
+        self.render_scale
+    }
+
+    /// Follows the window to a display with another scale factor
+    pub fn set_scale_factor(&mut self, scale_factor: f64) {
+        self.scale_factor = scale_factor;
+    }
+
+    fn rebuild_resolution_dependents(&mut self) {
+        let resolution = render_resolution(self.window_size, self.render_scale);
+        self.resolution = resolution;
         self.projection = nalgebra::Matrix4::new_perspective(
-            (dims[0] as f32) / (dims[1] as f32),
+            (self.window_size[0] as f32) / (self.window_size[1] as f32),
             consts::DEFAULT_VERTICAL_FOV_DEG * std::f32::consts::PI / 180.0,
             consts::DEFAULT_NEAR_CLIP,
             consts::DEFAULT_FAR_CLIP,
         );
-        self.resolution_dependents = Renderer::build_resolution_dependents(&self.display, dims);
+        self.resolution_dependents = Renderer::build_resolution_dependents(
+            self.context.facade(), resolution, self.window_size
+        );
+        self.post.resize(self.context.facade(), resolution);
+    }
+
+    pub fn set_post_process(&mut self, settings: PostProcessSettings) {
+        self.post.set_settings(settings);
+    }
+
+    pub fn get_post_process(&self) -> PostProcessSettings {
+        self.post.settings()
+    }
+
+    pub fn set_shadows(&mut self, settings: ShadowSettings) {
+        self.shadows.set_settings(self.context.facade(), settings);
+    }
+
+    pub fn get_shadows(&self) -> ShadowSettings {
+        self.shadows.settings()
     }
 
     pub fn draw_frame(
         &mut self,
         framebuilder: &super::FrameBuilder,
         camera: &dyn super::Camera,
-        mouse_coords: [u32; 2] // for picking
+        mouse_coords: [u32; 2] // for picking, in the window's physical pixels
     ) {
         // drawing a frame
         let params = glium::DrawParameters {
@@ -288,73 +540,75 @@ impl Renderer {
             }
         }
 
-        for (model, insts) in framebuilder.meshes.iter() {
-            {
-                if insts.len() > consts::DEFAULT_INSTANCE_BUFFER_SIZE {
-                    panic!("Too many instances of one model!")
-                }
-                let mut map = self.instance_buffer.map_write();
-                for (index, inst) in insts.iter().enumerate() {
-                    map.set(index, *inst);
-                }
-            }
+        let frustum = super::Frustum::from_matrix(&(self.projection * camera.get_view()));
+        self.stats = FrameStats::default();
+
+        self.select_lights(&framebuilder.lights, camera.get_world_position());
+        self.draw_shadows(framebuilder, camera);
 
-            let model_data = self.models_manager.get(model);
-            let ibufslice = self.instance_buffer.slice(0..insts.len()).unwrap();
-            let program = &self.program_pbr;
-            let proj = self.projection;
-            let texture_manager = &self.textures_manager;
+        let mut visible = std::mem::take(&mut self.visible_instances);
+        for (model, insts) in framebuilder.meshes.iter() {
+            // Only upload the instances inside the view
+            let bounds = self.models_manager.get(model).bounds;
+            visible.clear();
+            visible.extend(insts.iter().filter(|inst|
+                frustum.intersects(&bounds.transformed(&inst.world_matrix.into()))
+            ));
+            self.stats.drawn_instances += visible.len();
+            self.stats.culled_instances += insts.len() - visible.len();
+
+            // Models with more instances than a buffer can hold are drawn in batches
+            for batch in instance_batches(visible.len(), consts::MAX_INSTANCE_BUFFER_SIZE) {
+                let range = match self.upload_instances(&visible[batch]) {
+                    Some(range) => range,
+                    None => continue,
+                };
+                let model_data = self.models_manager.get(model);
+                let ibufslice = self.instance_buffer.slice(range).unwrap();
+                let program = &self.program_pbr;
+                let proj = self.projection;
+                let texture_manager = &self.textures_manager;
             
-            let mut lights = Lights {
-                pointlights: [
-                    PointLight {
-                        pos: self.light_pos,
-                        color: [400.0f32, 400.0, 400.0]//[4.5f32, 7.0, 450.0]
-                    },
-                    PointLight {
-                        pos: self.light_pos,
-                        color: [400.0f32, 400.0, 400.0]//[4.5f32, 450.0, 30.0]
-                    },
-                ]
-            };
-            lights.pointlights[1].pos[0] *= -1.0;
+                let lights = &self.frame_lights[..];
+                let ibl = self.ibl.bindings(framebuilder.skybox.as_deref());
+                let shadows = &self.shadows;
 
-            self.resolution_dependents.rent_mut(|(fb, _)| {
-                for p in model_data.primitives.iter() {
-                    fb.draw(
-                        (&p.vertices, ibufslice.per_instance().unwrap()),
-                        &p.indices,
-                        program,
-                        &PbrUniforms {
-                            view: camera.get_view(),
-                            proj: proj,
-                            albedo: &p.albedo.as_ref().unwrap_or(texture_manager.get_default_albedo()),
-                            metallic_rough: &p.metal_roughness.as_ref().unwrap_or(texture_manager.get_default_rough_metal()),
-                            normal_map: &p.normalmap.as_ref().unwrap_or(texture_manager.get_default_normal()),
-                            ao: &p.occlusion.as_ref().unwrap_or(texture_manager.get_default_occ()),
-
-                            lights: &lights,
-                            camera_position: camera.get_world_position(),
-                            exposure: 1.0f32, // between 1 and 3?  
-                        },
-                        &params
-                    ).unwrap();
-                }
-            });
+                self.resolution_dependents.rent_mut(|(fb, _)| {
+                    for p in model_data.primitives.iter() {
+                        fb.draw(
+                            (&p.vertices, ibufslice.per_instance().unwrap()),
+                            &p.indices,
+                            program,
+                            &PbrUniforms {
+                                view: camera.get_view(),
+                                proj: proj,
+                                albedo: &p.albedo.as_ref().unwrap_or(texture_manager.get_default_albedo()),
+                                metallic_rough: &p.metal_roughness.as_ref().unwrap_or(texture_manager.get_default_rough_metal()),
+                                normal_map: &p.normalmap.as_ref().unwrap_or(texture_manager.get_default_normal()),
+                                ao: &p.occlusion.as_ref().unwrap_or(texture_manager.get_default_occ()),
+
+                                lights,
+                                camera_position: camera.get_world_position(),
+                                ibl: &ibl,
+                                shadows,
+                            },
+                            &params
+                        ).unwrap();
+                    }
+                });
+            }
         }
+        self.visible_instances = visible;
 
         // Determine pick output
+        let pick = pick_pixel(mouse_coords, self.window_size, self.resolution);
         self.resolution_dependents.rent(|(_fb, fbos)| {
             fbos.pick.main_level()
             .first_layer()
             .into_image(None).unwrap()
             .raw_read_to_pixel_buffer(&glium::Rect {
-                left: utils::clamp(mouse_coords[0] as u32, 0, self.resolution[0] - 1),
-                bottom: utils::clamp(
-                    self.resolution[1] as i32 - mouse_coords[1] as i32,
-                    0i32,
-                    self.resolution[1] as i32 - 1
-                ) as u32,
+                left: pick[0],
+                bottom: pick[1],
                 width: 1,
                 height: 1,
             }, &self.picking_pbo);
@@ -364,40 +618,311 @@ impl Renderer {
             self.latest_pick_result = None;
         }
 
-        let mut target = self.display.draw();
-        target.clear_color_srgb_and_depth((0.0, 0.0, 0.0, 1.0), 1.0);
-        self.resolution_dependents.rent(|(_fb, fbos)| {
-            target.draw(
-                &self.quad_vbuffer,
-                glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
-                &self.program_composition,
-                &uniform! {
-                    color: &fbos.color,
+        let facade = self.context.facade();
+        let post = &mut self.post;
+        let quad = &self.quad_vbuffer;
+        self.resolution_dependents.rent(|(_fb, fbos)| post.run(facade, quad, &fbos.color));
+        // The labels go under the UI, whose layers each have their text over their quads
+        let mut text_layers = vec![&framebuilder.texts[..]];
+        text_layers.extend(framebuilder.ui.iter().map(|layer| &layer.texts[..]));
+        let ui_scale = self.scale_factor as f32;
+        self.text.prepare(facade, &text_layers, self.window_size, ui_scale, &(self.projection * camera.get_view()));
+        self.ui.prepare(facade, &framebuilder.ui, ui_scale, &mut self.textures_manager);
+
+        // Compose offscreen when there's no window or someone wants to read the frame
+        if self.is_headless() || self.pending_screenshot.is_some() {
+            let facade = self.context.facade();
+            self.resolution_dependents.rent(|(_fb, fbos)| {
+                let mut output = SimpleFrameBuffer::new(facade, &fbos.output).unwrap();
+                output.clear_color(0.0, 0.0, 0.0, 1.0);
+                self.compose(&mut output, &fbos.color);
+            });
+        }
+        if let Some(path) = self.pending_screenshot.take() {
+            match self.save_frame(&path) {
+                Ok(()) => log::info(&format!("Saved screenshot {}", path.to_string_lossy())),
+                Err(e) => log::err(&e),
+            }
+        }
+
+        if let RenderContext::Window(display) = &self.context {
+            let mut target = display.draw();
+            target.clear_color_srgb_and_depth((0.0, 0.0, 0.0, 1.0), 1.0);
+            self.resolution_dependents.rent(|(_fb, fbos)| {
+                self.compose(&mut target, &fbos.color);
+            });
+            target.finish().unwrap();
+        }
+    }
+
+    /// How many lights can shade a frame. Rebuilds the PBR shader, since it's a compile time size.
+    pub fn set_max_lights(&mut self, max_lights: usize) -> anyhow::Result<()> {
+        let max_lights = utils::clamp(max_lights, 1, consts::MAX_LIGHTS_LIMIT);
+        if max_lights == self.max_lights {
+            return Ok(());
+        }
+        self.program_pbr = super::shaders::pbr(self.context.facade(), max_lights)?;
+        self.max_lights = max_lights;
+        Ok(())
+    }
+
+    /// Picks the lights that shade this frame when there are more than `max_lights`:
+    /// directional lights first, then the lights whose range reaches closest to the camera.
+    fn select_lights(&mut self, lights: &[LightInstance], camera_position: nalgebra::Point3<f32>) {
+        let importance = |light: &LightInstance| {
+            if light.is_directional() {
+                std::f32::MIN
+            } else {
+                (nalgebra::Point3::from(light.position) - camera_position).norm() - light.range
+            }
+        };
+
+        self.frame_lights.clear();
+        self.frame_lights.extend_from_slice(lights);
+        if self.frame_lights.len() > self.max_lights {
+            self.frame_lights.sort_by(|a, b| importance(a).partial_cmp(&importance(b))
+                .unwrap_or(std::cmp::Ordering::Equal));
+            self.frame_lights.truncate(self.max_lights);
+        }
+    }
+
+    /// Renders the depth of every mesh into the shadow views of the frame's lights
+    fn draw_shadows(&mut self, framebuilder: &super::FrameBuilder, camera: &dyn super::Camera) {
+        self.shadows.plan(&self.frame_lights, &camera.get_view(), &self.projection);
+        if self.shadows.views().is_empty() {
+            return;
+        }
+        self.shadows.framebuffer(self.context.facade()).clear_depth(1.0);
+
+        let views = self.shadows.views().to_vec();
+        let mut visible = std::mem::take(&mut self.visible_instances);
+        for view in views.iter() {
+            let frustum = super::Frustum::from_matrix(&view.view_proj);
+            let params = glium::DrawParameters {
+                depth: glium::Depth {
+                    test: glium::DepthTest::IfLess,
+                    write: true,
+                    .. Default::default()
                 },
-                &Default::default()
-            ).unwrap();
-        });
-        target.finish().unwrap();
+                viewport: Some(view.viewport),
+                .. Default::default()
+            };
+
+            for (model, insts) in framebuilder.meshes.iter() {
+                let bounds = self.models_manager.get(model).bounds;
+                visible.clear();
+                visible.extend(insts.iter().filter(|inst|
+                    frustum.intersects(&bounds.transformed(&inst.world_matrix.into()))
+                ));
+                self.stats.shadow_instances += visible.len();
+
+                for batch in instance_batches(visible.len(), consts::MAX_INSTANCE_BUFFER_SIZE) {
+                    let range = match self.upload_instances(&visible[batch]) {
+                        Some(range) => range,
+                        None => continue,
+                    };
+                    let ibufslice = self.instance_buffer.slice(range).unwrap();
+                    let mut target = self.shadows.framebuffer(self.context.facade());
+                    for p in self.models_manager.get(model).primitives.iter() {
+                        target.draw(
+                            (&p.vertices, ibufslice.per_instance().unwrap()),
+                            &p.indices,
+                            self.shadows.program(),
+                            &uniform! {
+                                view: matrix_to_floats(nalgebra::Matrix4::identity()),
+                                proj: matrix_to_floats(view.view_proj),
+                            },
+                            &params
+                        ).unwrap();
+                    }
+                }
+            }
+        }
+        self.visible_instances = visible;
+    }
+
+    /// Streams instances into the instance buffer, after the ones already written. A full
+    /// buffer is orphaned and refilled from the start, so the driver never waits for draws
+    /// that still read the old data. The buffer grows when a batch doesn't fit at all.
+    fn upload_instances(&mut self, instances: &[MeshInstance]) -> Option<std::ops::Range<usize>> {
+        if instances.len() > self.instance_buffer.len() {
+            let capacity = instance_buffer_capacity(instances.len());
+            match VertexBuffer::empty_dynamic(self.context.facade(), capacity) {
+                Ok(buffer) => self.instance_buffer = buffer,
+                Err(e) => {
+                    log::error(&format!("Failed to grow the instance buffer to {}: {}", capacity, e));
+                    return None;
+                }
+            }
+            self.instance_buffer_offset = 0;
+        } else if self.instance_buffer_offset + instances.len() > self.instance_buffer.len() {
+            self.instance_buffer.invalidate();
+            self.instance_buffer_offset = 0;
+        }
+
+        let range = self.instance_buffer_offset..self.instance_buffer_offset + instances.len();
+        self.instance_buffer.slice(range.clone())?.write(instances);
+        self.instance_buffer_offset = range.end;
+        Some(range)
+    }
+
+    /// Draws the final image from the HDR color target, with the post processing applied
+    /// and the labels and the UI on top
+    fn compose<S: Surface>(&self, target: &mut S, color: &Texture2d) {
+        let settings = self.post.settings();
+        target.draw(
+            &self.quad_vbuffer,
+            glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+            &self.program_composition,
+            &uniform! {
+                color: color,
+                bloom: self.post.bloom_texture(),
+                adapted_luminance: self.post.luminance_texture(),
+                bloom_enabled: settings.bloom as i32,
+                bloom_intensity: settings.bloom_intensity,
+                auto_exposure: settings.auto_exposure as i32,
+                exposure: settings.exposure,
+                tonemapper: settings.tonemapper.shader_id(),
+                gamma: settings.gamma,
+            },
+            &Default::default()
+        ).unwrap();
+        self.text.draw(target, 0, self.window_size);
+        for layer in 0..self.ui.layer_count() {
+            self.ui.draw(target, layer, self.window_size, &self.textures_manager);
+            self.text.draw(target, layer + 1, self.window_size);
+        }
+    }
+
+    /// The last frame that was composed offscreen - every frame of a headless renderer,
+    /// or the frame a screenshot was taken of.
+    pub fn read_frame(&self) -> image::RgbaImage {
+        self.resolution_dependents.rent(|(_fb, fbos)| {
+            let raw: glium::texture::RawImage2d<u8> = fbos.output.read();
+            let image = image::RgbaImage::from_raw(raw.width, raw.height, raw.data.into_owned())
+                .expect("Frame readback has the wrong size");
+
+            // OpenGL's origin is the bottom left
+            image::imageops::flip_vertical(&image)
+        })
     }
 
-    pub fn get_supported_resolutions(&self) -> Vec<[u32; 2]> {
-        let max_size = self.get_display().gl_window().window().current_monitor().map(|mon| mon.size());
-        if let Some(max_size) = max_size {
-            vec![[max_size.width as u32, max_size.height as u32]]
-        } else {
-            vec![[800, 600]]
+    pub fn save_frame<P: AsRef<std::path::Path>>(&self, path: P) -> anyhow::Result<()> {
+        use anyhow::Context;
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent).context("Failed to create screenshot folder")?;
         }
+        self.read_frame().save_with_format(path.as_ref(), image::ImageFormat::Png)
+            .context(format!("Failed to save {}", path.as_ref().to_string_lossy()))
+    }
+
+    /// Saves the next frame as a PNG
+    pub fn request_screenshot<P: AsRef<std::path::Path>>(&mut self, path: P) {
+        self.pending_screenshot = Some(path.as_ref().to_path_buf());
+    }
+
+    /// Rebuilds the shaders whose files changed on disk. Only debug builds read shaders from disk.
+    pub fn hot_reload_shaders(&mut self) {
+        if self.last_shader_check.elapsed().as_secs_f32() < consts::SHADER_HOT_RELOAD_INTERVAL_SECONDS {
+            return;
+        }
+        self.last_shader_check = std::time::Instant::now();
+
+        let display = self.context.facade();
+        for program in vec![&mut self.program_pbr, &mut self.program_skybox, &mut self.program_composition] {
+            program.reload_if_changed(display);
+        }
+        self.post.hot_reload(display);
+        self.shadows.hot_reload(display);
+        self.text.hot_reload(display);
+        self.ui.hot_reload(display);
+    }
+
+    /// Where model, texture and image based lighting caches go, instead of next to their
+    /// sources. Set before loading anything, so nothing gets cached in both places.
+    pub fn set_cache_dir(&mut self, cache_dir: Option<std::path::PathBuf>) {
+        self.models_manager.set_cache_dir(cache_dir.clone());
+        self.textures_manager.set_cache_dir(cache_dir.clone());
+        self.ibl.set_cache_dir(cache_dir);
     }
 
     pub fn load_model(&mut self, m: &str) -> anyhow::Result<()> {
-        self.models_manager.try_load(&self.display, m)
+        self.models_manager.try_load(self.context.facade(), m)
     }
 
     pub fn load_texture(&mut self, t: &str) -> anyhow::Result<()> {
-        self.textures_manager.try_load(&self.display, t)
+        self.textures_manager.try_load(self.context.facade(), t)
     }
     
+    /// Loads a skybox, along with the image based lighting it gives off
     pub fn load_cubemap(&mut self, cm: &str) -> anyhow::Result<()> {
-        self.textures_manager.try_load_cubemap(&self.display, cm)
+        self.textures_manager.try_load_cubemap(self.context.facade(), cm)?;
+        match self.textures_manager.get(cm) {
+            Some(Texture::Cubemap(cubemap)) =>
+                self.ibl.try_load(self.context.facade(), &self.quad_vbuffer, cm, cubemap),
+            _ => Ok(()),
+        }
     }
 }
+
+#[test]
+fn pick_pixel_follows_the_render_scale() {
+    // Same size: only flipped to OpenGL's bottom left origin
+    assert_eq!(pick_pixel([0, 0], [800, 600], [800, 600]), [0, 599]);
+    assert_eq!(pick_pixel([799, 599], [800, 600], [800, 600]), [799, 0]);
+
+    // Half the window's resolution
+    assert_eq!(pick_pixel([400, 300], [800, 600], [400, 300]), [200, 149]);
+
+    // Cursors outside the window are clamped
+    assert_eq!(pick_pixel([2000, 2000], [800, 600], [400, 300]), [399, 0]);
+    assert_eq!(render_resolution([800, 600], 0.5), [400, 300]);
+    assert_eq!(render_resolution([1, 1], 0.25), [1, 1]);
+}
+
+#[test]
+fn renders_more_instances_than_the_initial_buffer() {
+    use nalgebra::{Matrix4, Point3, Vector3};
+    use crate::engine::camera::Camera;
+    use super::FrameBuilder;
+
+    let mut renderer = Renderer::new_headless(consts::GOLDEN_RESOLUTION)
+        .unwrap_or_else(|e| panic!("Instance buffer test needs a headless OpenGL context: {:#}", e));
+
+    // Tiny cubes in front of the camera, and as many behind it that get culled
+    let count = consts::DEFAULT_INSTANCE_BUFFER_SIZE * 2 + 1;
+    let instances = (0..count)
+        .map(|i| MeshInstance {
+            world_matrix: (Matrix4::new_translation(&Vector3::new(
+                (i % 256) as f32 * 0.01 - 1.28,
+                (i / 256 % 256) as f32 * 0.01 - 1.28,
+                if i % 2 == 0 { 2.0 } else { -10.0 },
+            )) * Matrix4::new_scaling(0.001)).into(),
+            ..Default::default()
+        })
+        .collect();
+    let mut framebuilder = FrameBuilder::new();
+    framebuilder.with_meshes(vec![(String::from("cube"), instances)].into_iter().collect());
+
+    let camera = Camera::new(Point3::new(0.0, 0.0, -4.0), Point3::origin(), Vector3::y());
+    renderer.draw_frame(&framebuilder, &camera, [0, 0]);
+    let stats = renderer.get_frame_stats();
+    assert_eq!(stats.drawn_instances, count / 2 + 1);
+    assert_eq!(stats.culled_instances, count / 2);
+}
+
+#[test]
+fn instance_batches_split_at_the_buffer_cap() {
+    let cap = consts::MAX_INSTANCE_BUFFER_SIZE;
+    let count = cap * 2 + 3;
+    let batches: Vec<_> = instance_batches(count, cap).collect();
+    assert_eq!(batches, vec![0..cap, cap..cap * 2, cap * 2..count]);
+    assert!(batches.iter().all(|batch| instance_buffer_capacity(batch.len()) >= batch.len()));
+    assert_eq!(instance_batches(0, cap).count(), 0);
+    assert_eq!(instance_batches(cap, cap).collect::<Vec<_>>(), vec![0..cap]);
+
+    // The buffer grows in powers of two, up to the cap
+    assert_eq!(instance_buffer_capacity(consts::DEFAULT_INSTANCE_BUFFER_SIZE + 1),
+        consts::DEFAULT_INSTANCE_BUFFER_SIZE * 2);
+    assert_eq!(instance_buffer_capacity(cap), cap);
+}
@@ -1,71 +1,369 @@
-use glium::{Display, Program};
-
-pub fn pbr(display: &Display) -> Program {
-    Program::from_source(display,
-        include_str!("./pbr.vert"),
-        include_str!("./pbr.frag"),
-        None
-    ).unwrap()
-}
-
-pub fn composition(display: &Display) -> Program {
-    Program::from_source(display,
-        "
-            #version 450
-            in vec2 position;
-            in vec2 texcoord;
-            out vec2 frag_texcoord;
-
-            void main() {
-                frag_texcoord = texcoord;
-                gl_Position = vec4(position, 0.0, 1.0);
-            }
-        ",
-        "
-            #version 450
-            uniform sampler2D color;
-            in vec2 frag_texcoord;
-            out vec4 fragColor;
-
-            void main() {
-                fragColor = texture(color, frag_texcoord);
-            }
-        ",
-        None)
-        .unwrap()
+use crate::engine::prelude::*;
+use glium::{backend::Facade, Program};
+use glium::{Api, Version};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// Every shader file, embedded for release builds. Debug builds read them from
+/// `consts::SHADERS_PATH` instead, so they can be edited while the game runs.
+const EMBEDDED_SHADERS: &[(&str, &str)] = &[
+    ("lighting.glsl", include_str!("./shaders/lighting.glsl")),
+    ("pbr.vert", include_str!("./shaders/pbr.vert")),
+    ("pbr.frag", include_str!("./shaders/pbr.frag")),
+    ("pbr_depth.frag", include_str!("./shaders/pbr_depth.frag")),
+    ("composition.vert", include_str!("./shaders/composition.vert")),
+    ("composition.frag", include_str!("./shaders/composition.frag")),
+    ("skybox.vert", include_str!("./shaders/skybox.vert")),
+    ("skybox.frag", include_str!("./shaders/skybox.frag")),
+    ("tonemapping.glsl", include_str!("./shaders/tonemapping.glsl")),
+    ("bloom_downsample.frag", include_str!("./shaders/bloom_downsample.frag")),
+    ("bloom_upsample.frag", include_str!("./shaders/bloom_upsample.frag")),
+    ("luminance.frag", include_str!("./shaders/luminance.frag")),
+    ("luminance_reduce.frag", include_str!("./shaders/luminance_reduce.frag")),
+    ("luminance_adapt.frag", include_str!("./shaders/luminance_adapt.frag")),
+    ("text.vert", include_str!("./shaders/text.vert")),
+    ("text.frag", include_str!("./shaders/text.frag")),
+    ("ui.vert", include_str!("./shaders/ui.vert")),
+    ("ui.frag", include_str!("./shaders/ui.frag")),
+    ("ibl.glsl", include_str!("./shaders/ibl.glsl")),
+    ("ibl_irradiance.frag", include_str!("./shaders/ibl_irradiance.frag")),
+    ("ibl_prefilter.frag", include_str!("./shaders/ibl_prefilter.frag")),
+    ("ibl_brdf.frag", include_str!("./shaders/ibl_brdf.frag")),
+];
+
+/// Compile time options of a program
+#[derive(Debug, Clone, Default)]
+pub struct ShaderOptions {
+    /// Extra `#define`s, for settings that have to be known at compile time
+    pub defines: Vec<(&'static str, String)>,
+
+    /// The program gamma corrects its output, so the framebuffer mustn't convert it again
+    pub outputs_srgb: bool,
+}
+
+/// The GLSL dialects the shaders are written for. Shader sources don't have a
+/// `#version` line, `expand` adds one along with the macros that hide the
+/// differences between them:
+/// - `LOCATION(n)` for explicit varying locations (GL 4.1+)
+/// - `BINDING(n)` for explicit sampler bindings (GL 4.2+)
+/// - `GLSL_450` or `GLSL_330` for anything else that has to differ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GlslVersion {
+    Glsl450,
+    Glsl330,
 }
 
-pub fn static_skybox(display: &Display) -> Program {
-    Program::from_source(display,
-        "
-            #version 450
-            // constants
-            uniform mat4 proj;
-            uniform mat4 view;
+impl GlslVersion {
+    /// The newest dialect the context supports, or an error for contexts too old to run the game.
+    pub fn for_context(display: &dyn Facade) -> anyhow::Result<GlslVersion> {
+        let context = display.get_context();
+        if context.is_glsl_version_supported(&Version(Api::Gl, 4, 5)) {
+            Ok(GlslVersion::Glsl450)
+        } else if context.is_glsl_version_supported(&Version(Api::Gl, 3, 3)) {
+            Ok(GlslVersion::Glsl330)
+        } else {
+            let Version(api, major, minor) = *context.get_opengl_version();
+            Err(anyhow!(
+                "OpenGL 3.3 or newer is required, but the driver only provides {:?} {}.{} ({}). \
+                Updating the graphics drivers may help.",
+                api, major, minor, context.get_opengl_renderer_string()
+            ))
+        }
+    }
 
-            in vec3 position;
+    fn header(&self) -> &'static str {
+        match self {
+            GlslVersion::Glsl450 => "\
+                #version 450\n\
+                #define GLSL_450\n\
+                #define LOCATION(n) layout(location=n)\n\
+                #define BINDING(n) layout(binding=n)\n",
+            GlslVersion::Glsl330 => "\
+                #version 330 core\n\
+                #define GLSL_330\n\
+                #define LOCATION(n)\n\
+                #define BINDING(n)\n",
+        }
+    }
+}
+
+fn read_shader_file(name: &str) -> anyhow::Result<String> {
+    use anyhow::Context;
+    if cfg!(debug_assertions) {
+        std::fs::read_to_string(std::path::Path::new(consts::SHADERS_PATH).join(name))
+            .context(format!("Failed to read shader {}", name))
+    } else {
+        EMBEDDED_SHADERS.iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, source)| String::from(*source))
+            .ok_or_else(|| anyhow!("Shader {} is not embedded", name))
+    }
+}
 
-            out vec3 out_direction;
+/// The file name in an `#include "file"` line
+fn parse_include(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("#include")?.trim();
+    rest.strip_prefix('"')?.strip_suffix('"')
+}
 
-            void main() {
-                out_direction = position;
-                gl_Position = proj * view * vec4(position, 1.0);
+/// Appends a shader file to `out` with its includes expanded in place. Every file gets
+/// a `#line` source number, its index in `files`, so compiler errors can be traced back.
+fn expand_file(name: &str, files: &mut Vec<String>, stack: &mut Vec<String>, out: &mut String)
+-> anyhow::Result<()> {
+    use anyhow::Context;
+    if stack.iter().any(|f| f == name) {
+        return Err(anyhow!("{} includes itself through {}", name, stack.join(" -> ")));
+    }
+    let index = match files.iter().position(|f| f == name) {
+        Some(i) => i,
+        None => {
+            files.push(String::from(name));
+            files.len() - 1
+        }
+    };
+    let source = read_shader_file(name)?;
+
+    stack.push(String::from(name));
+    out.push_str(&format!("#line 1 {}\n", index));
+    for (i, line) in source.lines().enumerate() {
+        match parse_include(line) {
+            Some(included) => {
+                expand_file(included, files, stack, out)
+                    .context(format!("Included from {}:{}", name, i + 1))?;
+                out.push_str(&format!("#line {} {}\n", i + 2, index));
+            },
+            None => {
+                out.push_str(line);
+                out.push('\n');
             }
-        ",
-        "
-            #version 450
-            uniform samplerCube tex;
-            
-            in vec3 out_direction;
-
-            out vec4 fragColor;
-            out uint fragPick;
-
-            void main() {
-                fragColor = texture(tex, out_direction);
-                fragPick = 0;
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Builds the source the driver compiles: the version header and `defines`, then the file
+/// with its includes expanded. Files are added to `files` in the order of their `#line` numbers.
+pub fn expand(name: &str, version: GlslVersion, defines: &[(&str, String)], files: &mut Vec<String>)
+-> anyhow::Result<String> {
+    let mut result = String::from(version.header());
+    for (define, value) in defines {
+        result.push_str(&format!("#define {} {}\n", define, value));
+    }
+    expand_file(name, files, &mut Vec::new(), &mut result)?;
+    Ok(result)
+}
+
+/// Finds the first "<source>:<line>" or "<source>(<line>)" in a line of compiler output.
+/// Returns its byte range, source number and line number.
+fn find_location(line: &str) -> Option<(usize, usize, usize, usize)> {
+    let bytes = line.as_bytes();
+    let digits_end = |from: usize| (from..bytes.len())
+        .find(|&i| !bytes[i].is_ascii_digit())
+        .unwrap_or_else(|| bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_digit() || (i > 0 && bytes[i - 1].is_ascii_alphanumeric()) {
+            i += 1;
+            continue;
+        }
+
+        let source_end = digits_end(i);
+        if source_end < bytes.len() && (bytes[source_end] == b':' || bytes[source_end] == b'(') {
+            let line_end = digits_end(source_end + 1);
+            let parenthesized = bytes[source_end] == b'(';
+            if line_end > source_end + 1 && (!parenthesized || bytes.get(line_end) == Some(&b')')) {
+                return Some((
+                    i,
+                    if parenthesized { line_end + 1 } else { line_end },
+                    line[i..source_end].parse().ok()?,
+                    line[source_end + 1..line_end].parse().ok()?,
+                ));
             }
-        ",
-        None)
-        .unwrap()
-}
\ No newline at end of file
+        }
+        i = source_end;
+    }
+    None
+}
+
+/// Rewrites the source numbers in a GLSL compiler log to file names. Drivers format
+/// locations differently: "0:12(5)" on Mesa, "0(12)" on NVIDIA, "0:12" on AMD and Intel.
+pub fn map_error_log(log: &str, files: &[String]) -> String {
+    log.lines()
+        .map(|line| match find_location(line) {
+            Some((start, end, source, line_number)) if source < files.len() =>
+                format!("{}{}:{}{}", &line[..start], files[source], line_number, &line[end..]),
+            _ => String::from(line),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn build(display: &dyn Facade, name: &str, vertex: &str, fragment: &str, options: &ShaderOptions)
+-> anyhow::Result<(Program, Vec<String>)> {
+    use glium::program::{ProgramCreationError, ProgramCreationInput};
+    let version = GlslVersion::for_context(display)?;
+
+    // Both stages share the file numbering, so one list maps errors from either
+    let mut files = Vec::new();
+    let vertex_source = expand(vertex, version, &options.defines, &mut files)?;
+    let fragment_source = expand(fragment, version, &options.defines, &mut files)?;
+
+    let program = Program::new(display, ProgramCreationInput::SourceCode {
+        vertex_shader: &vertex_source,
+        tessellation_control_shader: None,
+        tessellation_evaluation_shader: None,
+        geometry_shader: None,
+        fragment_shader: &fragment_source,
+        transform_feedback_varyings: None,
+        outputs_srgb: options.outputs_srgb,
+        uses_point_size: false,
+    })
+        .map_err(|e| match e {
+            ProgramCreationError::CompilationError(log, ..) | ProgramCreationError::LinkingError(log) =>
+                anyhow!("Failed to compile the {} shader for {:?}:\n{}", name, version,
+                    map_error_log(&log, &files)),
+            e => anyhow!("Failed to compile the {} shader for {:?}: {}", name, version, e),
+        })?;
+    Ok((program, files))
+}
+
+fn modification_times<'a, I: IntoIterator<Item = &'a String>>(files: I)
+-> HashMap<String, Option<SystemTime>> {
+    files.into_iter()
+        .map(|f| (f.clone(), std::fs::metadata(std::path::Path::new(consts::SHADERS_PATH).join(f))
+            .and_then(|md| md.modified())
+            .ok()))
+        .collect()
+}
+
+/// A compiled program that knows which files it was built from, so it can be rebuilt
+/// when they change.
+pub struct ShaderProgram {
+    name: &'static str,
+    vertex: &'static str,
+    fragment: &'static str,
+    options: ShaderOptions,
+    program: Program,
+    mtimes: HashMap<String, Option<SystemTime>>,
+}
+
+impl ShaderProgram {
+    pub fn load(display: &dyn Facade, name: &'static str, vertex: &'static str, fragment: &'static str)
+    -> anyhow::Result<ShaderProgram> {
+        ShaderProgram::load_with_options(display, name, vertex, fragment, ShaderOptions::default())
+    }
+
+    pub fn load_with_options(
+        display: &dyn Facade,
+        name: &'static str,
+        vertex: &'static str,
+        fragment: &'static str,
+        options: ShaderOptions,
+    ) -> anyhow::Result<ShaderProgram> {
+        let (program, files) = build(display, name, vertex, fragment, &options)?;
+        Ok(ShaderProgram {
+            name,
+            vertex,
+            fragment,
+            options,
+            program,
+            mtimes: modification_times(&files),
+        })
+    }
+
+    /// Rebuilds the program if any of its files changed on disk. When the new version
+    /// doesn't compile the error is logged and the previous program stays in use.
+    pub fn reload_if_changed(&mut self, display: &dyn Facade) {
+        let mtimes = modification_times(self.mtimes.keys());
+        if mtimes == self.mtimes {
+            return;
+        }
+        // Remember the broken version too, so it's only reported once
+        self.mtimes = mtimes;
+
+        match build(display, self.name, self.vertex, self.fragment, &self.options) {
+            Ok((program, files)) => {
+                log::info(&format!("Reloaded the {} shader", self.name));
+                self.program = program;
+                self.mtimes = modification_times(&files);
+            },
+            Err(e) => log::err(&e),
+        }
+    }
+}
+
+impl std::ops::Deref for ShaderProgram {
+    type Target = Program;
+
+    fn deref(&self) -> &Program {
+        &self.program
+    }
+}
+
+pub fn pbr(display: &dyn Facade, max_lights: usize) -> anyhow::Result<ShaderProgram> {
+    ShaderProgram::load_with_options(display, "pbr", "pbr.vert", "pbr.frag", ShaderOptions {
+        defines: vec![
+            ("MAX_LIGHTS", max_lights.to_string()),
+            ("MAX_SHADOW_CASCADES", consts::MAX_SHADOW_CASCADES.to_string()),
+            ("MAX_SHADOW_VIEWS", consts::MAX_SHADOW_VIEWS.to_string()),
+        ],
+        ..Default::default()
+    })
+}
+
+/// The PBR program's vertex stage with nothing but depth, for the shadow maps
+pub fn pbr_depth(display: &dyn Facade) -> anyhow::Result<ShaderProgram> {
+    ShaderProgram::load_with_options(display, "pbr_depth", "pbr.vert", "pbr_depth.frag", ShaderOptions {
+        defines: vec![("DEPTH_ONLY", String::from("1"))],
+        ..Default::default()
+    })
+}
+
+/// Tonemaps and gamma corrects the HDR image into the final frame
+pub fn composition(display: &dyn Facade) -> anyhow::Result<ShaderProgram> {
+    ShaderProgram::load_with_options(display, "composition", "composition.vert", "composition.frag",
+        ShaderOptions {
+            outputs_srgb: true,
+            ..Default::default()
+        })
+}
+
+/// Labels, drawn onto the gamma corrected frame
+pub fn text(display: &dyn Facade) -> anyhow::Result<ShaderProgram> {
+    ShaderProgram::load_with_options(display, "text", "text.vert", "text.frag", ShaderOptions {
+        outputs_srgb: true,
+        ..Default::default()
+    })
+}
+
+/// The UI's quads, also drawn onto the gamma corrected frame
+pub fn ui(display: &dyn Facade) -> anyhow::Result<ShaderProgram> {
+    ShaderProgram::load_with_options(display, "ui", "ui.vert", "ui.frag", ShaderOptions {
+        outputs_srgb: true,
+        ..Default::default()
+    })
+}
+
+/// A fullscreen pass, for the post processing steps
+pub fn fullscreen(display: &dyn Facade, name: &'static str, fragment: &'static str)
+-> anyhow::Result<ShaderProgram> {
+    ShaderProgram::load(display, name, "composition.vert", fragment)
+}
+
+pub fn static_skybox(display: &dyn Facade) -> anyhow::Result<ShaderProgram> {
+    ShaderProgram::load(display, "skybox", "skybox.vert", "skybox.frag")
+}
+
+#[test]
+fn compiler_errors_map_to_files() {
+    let files = vec![String::from("pbr.frag"), String::from("lighting.glsl")];
+    assert_eq!(map_error_log("1:12(5): error: syntax error", &files),
+        "lighting.glsl:12(5): error: syntax error");
+    assert_eq!(map_error_log("0(7) : error C0000: syntax error", &files),
+        "pbr.frag:7 : error C0000: syntax error");
+    assert_eq!(map_error_log("ERROR: 1:3: 'x' : undeclared identifier", &files),
+        "ERROR: lighting.glsl:3: 'x' : undeclared identifier");
+    assert_eq!(map_error_log("error in vec3 at 9:1", &files), "error in vec3 at 9:1");
+}
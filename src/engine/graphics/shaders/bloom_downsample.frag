@@ -0,0 +1,44 @@
+// Halves the image with a 13 tap filter (Jimenez, "Next Generation Post Processing in
+// Call of Duty: Advanced Warfare"). The first pass also keeps only the bright parts.
+uniform sampler2D source;
+uniform vec2 texel_size; // of the source
+uniform float threshold; // negative to keep everything
+
+in vec2 frag_texcoord;
+out vec4 fragColor;
+
+vec3 tap(float x, float y) {
+    return texture(source, frag_texcoord + vec2(x, y) * texel_size).rgb;
+}
+
+void main() {
+    vec3 a = tap(-2.0, 2.0);
+    vec3 b = tap(0.0, 2.0);
+    vec3 c = tap(2.0, 2.0);
+    vec3 d = tap(-2.0, 0.0);
+    vec3 e = tap(0.0, 0.0);
+    vec3 f = tap(2.0, 0.0);
+    vec3 g = tap(-2.0, -2.0);
+    vec3 h = tap(0.0, -2.0);
+    vec3 i = tap(2.0, -2.0);
+    vec3 j = tap(-1.0, 1.0);
+    vec3 k = tap(1.0, 1.0);
+    vec3 l = tap(-1.0, -1.0);
+    vec3 m = tap(1.0, -1.0);
+
+    vec3 result = e * 0.125;
+    result += (a + c + g + i) * 0.03125;
+    result += (b + d + f + h) * 0.0625;
+    result += (j + k + l + m) * 0.125;
+
+    if (threshold >= 0.0) {
+        // Soft knee, so the cutoff doesn't show as a hard edge
+        float brightness = max(result.r, max(result.g, result.b));
+        float knee = threshold * 0.5;
+        float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+        soft = soft * soft / (4.0 * knee + 0.0001);
+        result *= max(soft, brightness - threshold) / max(brightness, 0.0001);
+    }
+
+    fragColor = vec4(result, 1.0);
+}
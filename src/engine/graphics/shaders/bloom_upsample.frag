@@ -0,0 +1,17 @@
+// Doubles the image with a 3x3 tent filter. The result is added onto the larger level.
+uniform sampler2D source;
+uniform vec2 texel_size; // of the source
+
+in vec2 frag_texcoord;
+out vec4 fragColor;
+
+vec3 tap(float x, float y) {
+    return texture(source, frag_texcoord + vec2(x, y) * texel_size).rgb;
+}
+
+void main() {
+    vec3 result = tap(0.0, 0.0) * 4.0;
+    result += (tap(-1.0, 0.0) + tap(1.0, 0.0) + tap(0.0, -1.0) + tap(0.0, 1.0)) * 2.0;
+    result += tap(-1.0, -1.0) + tap(1.0, -1.0) + tap(-1.0, 1.0) + tap(1.0, 1.0);
+    fragColor = vec4(result / 16.0, 1.0);
+}
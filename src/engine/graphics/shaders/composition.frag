@@ -0,0 +1,34 @@
+#include "tonemapping.glsl"
+
+uniform sampler2D color;
+uniform sampler2D bloom;
+uniform sampler2D adapted_luminance; // log of the average scene luminance, 1x1
+
+uniform int bloom_enabled;
+uniform float bloom_intensity;
+uniform int auto_exposure;
+uniform float exposure;
+uniform int tonemapper;
+uniform float gamma;
+
+in vec2 frag_texcoord;
+out vec4 fragColor;
+
+// The average luminance auto exposure maps the scene to
+const float KEY_VALUE = 0.18;
+
+void main() {
+    vec3 hdr = texture(color, frag_texcoord).rgb;
+    if (bloom_enabled != 0) {
+        hdr += texture(bloom, frag_texcoord).rgb * bloom_intensity;
+    }
+
+    float scene_exposure = exposure;
+    if (auto_exposure != 0) {
+        float average = exp(texture(adapted_luminance, vec2(0.5)).r);
+        scene_exposure *= KEY_VALUE / max(average, 0.0001);
+    }
+
+    vec3 ldr = tonemap(hdr * scene_exposure, tonemapper);
+    fragColor = vec4(pow(ldr, vec3(1.0 / gamma)), 1.0);
+}
@@ -0,0 +1,42 @@
+// Shared by the passes that build the image based lighting maps
+const float PI = 3.14159265359;
+
+// The direction through a point of a cubemap face, with the faces in OpenGL's order:
+// +X, -X, +Y, -Y, +Z, -Z
+vec3 cube_direction(int face, vec2 uv)
+{
+    vec2 p = uv * 2.0 - 1.0;
+    if (face == 0) return normalize(vec3(1.0, -p.y, -p.x));
+    if (face == 1) return normalize(vec3(-1.0, -p.y, p.x));
+    if (face == 2) return normalize(vec3(p.x, 1.0, p.y));
+    if (face == 3) return normalize(vec3(p.x, -1.0, -p.y));
+    if (face == 4) return normalize(vec3(p.x, -p.y, 1.0));
+    return normalize(vec3(-p.x, -p.y, -1.0));
+}
+// ----------------------------------------------------------------------------
+// Low discrepancy points in [0, 1)^2, so few samples cover the hemisphere evenly
+vec2 hammersley(uint i, uint count)
+{
+    uint bits = i;
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return vec2(float(i) / float(count), float(bits) * 2.3283064365386963e-10);
+}
+// ----------------------------------------------------------------------------
+// A half vector around N, distributed like the GGX lobe of the roughness
+vec3 importance_sample_ggx(vec2 xi, vec3 N, float roughness)
+{
+    float a = roughness * roughness;
+    float phi = 2.0 * PI * xi.x;
+    float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    vec3 H = vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+    vec3 up = abs(N.z) < 0.999 ? vec3(0.0, 0.0, 1.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(up, N));
+    vec3 bitangent = cross(N, tangent);
+    return normalize(tangent * H.x + bitangent * H.y + N * H.z);
+}
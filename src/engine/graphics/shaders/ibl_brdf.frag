@@ -0,0 +1,40 @@
+#include "ibl.glsl"
+
+// The split sum approximation's BRDF integral: the scale (R) and bias (G) applied to F0,
+// by the angle between the normal and the view (X) and the roughness (Y)
+in vec2 frag_texcoord;
+out vec4 fragColor;
+
+const uint SAMPLE_COUNT = 1024u;
+
+float geometry_schlick_ggx(float NdotV, float roughness)
+{
+    // Image based lighting remaps k differently than analytic lights
+    float k = roughness * roughness / 2.0;
+    return NdotV / (NdotV * (1.0 - k) + k);
+}
+
+void main() {
+    float NdotV = max(frag_texcoord.x, 0.001);
+    float roughness = frag_texcoord.y;
+    vec3 V = vec3(sqrt(1.0 - NdotV * NdotV), 0.0, NdotV);
+    vec3 N = vec3(0.0, 0.0, 1.0);
+
+    float scale = 0.0;
+    float bias = 0.0;
+    for (uint i = 0u; i < SAMPLE_COUNT; ++i) {
+        vec3 H = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), N, roughness);
+        vec3 L = normalize(2.0 * dot(V, H) * H - V);
+        float NdotL = max(L.z, 0.0);
+        if (NdotL > 0.0) {
+            float NdotH = max(H.z, 0.0);
+            float VdotH = max(dot(V, H), 0.0);
+            float G = geometry_schlick_ggx(NdotV, roughness) * geometry_schlick_ggx(NdotL, roughness);
+            float visibility = G * VdotH / max(NdotH * NdotV, 0.0001);
+            float fresnel = pow(1.0 - VdotH, 5.0);
+            scale += (1.0 - fresnel) * visibility;
+            bias += fresnel * visibility;
+        }
+    }
+    fragColor = vec4(scale / float(SAMPLE_COUNT), bias / float(SAMPLE_COUNT), 0.0, 1.0);
+}
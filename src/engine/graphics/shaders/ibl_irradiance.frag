@@ -0,0 +1,29 @@
+#include "ibl.glsl"
+
+// Convolves the environment with a cosine lobe, for the diffuse ambient light
+uniform samplerCube environment;
+uniform int face;
+
+in vec2 frag_texcoord;
+out vec4 fragColor;
+
+const float SAMPLE_STEP = 0.025;
+
+void main() {
+    vec3 N = cube_direction(face, frag_texcoord);
+    vec3 up = abs(N.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(0.0, 0.0, 1.0);
+    vec3 right = normalize(cross(up, N));
+    up = cross(N, right);
+
+    vec3 irradiance = vec3(0.0);
+    float samples = 0.0;
+    for (float phi = 0.0; phi < 2.0 * PI; phi += SAMPLE_STEP) {
+        for (float theta = 0.0; theta < 0.5 * PI; theta += SAMPLE_STEP) {
+            vec3 local = vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            vec3 direction = local.x * right + local.y * up + local.z * N;
+            irradiance += texture(environment, direction).rgb * cos(theta) * sin(theta);
+            samples += 1.0;
+        }
+    }
+    fragColor = vec4(PI * irradiance / samples, 1.0);
+}
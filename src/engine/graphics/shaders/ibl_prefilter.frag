@@ -0,0 +1,32 @@
+#include "ibl.glsl"
+
+// Blurs the environment by the GGX lobe of a roughness, one roughness per mip level,
+// for the specular ambient light
+uniform samplerCube environment;
+uniform int face;
+uniform float roughness;
+
+in vec2 frag_texcoord;
+out vec4 fragColor;
+
+const uint SAMPLE_COUNT = 1024u;
+
+void main() {
+    // The view is assumed to be along the normal, which loses the stretched
+    // reflections at grazing angles but makes the map independent of the view
+    vec3 N = cube_direction(face, frag_texcoord);
+    vec3 V = N;
+
+    vec3 color = vec3(0.0);
+    float weight = 0.0;
+    for (uint i = 0u; i < SAMPLE_COUNT; ++i) {
+        vec3 H = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), N, roughness);
+        vec3 L = normalize(2.0 * dot(V, H) * H - V);
+        float NdotL = dot(N, L);
+        if (NdotL > 0.0) {
+            color += texture(environment, L).rgb * NdotL;
+            weight += NdotL;
+        }
+    }
+    fragColor = vec4(color / max(weight, 0.0001), 1.0);
+}
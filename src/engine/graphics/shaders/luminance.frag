@@ -0,0 +1,10 @@
+// The log of each pixel's luminance, averaged by the following reduce passes
+uniform sampler2D color;
+
+in vec2 frag_texcoord;
+out vec4 fragColor;
+
+void main() {
+    float luminance = dot(texture(color, frag_texcoord).rgb, vec3(0.2126, 0.7152, 0.0722));
+    fragColor = vec4(log(max(luminance, 0.0001)), 0.0, 0.0, 1.0);
+}
@@ -0,0 +1,12 @@
+// Moves the adapted luminance towards the current one over time, like eyes adjusting
+uniform sampler2D previous;
+uniform sampler2D current;
+uniform float rate;
+
+out vec4 fragColor;
+
+void main() {
+    float adapted = texelFetch(previous, ivec2(0, 0), 0).r;
+    float target = texelFetch(current, ivec2(0, 0), 0).r;
+    fragColor = vec4(mix(adapted, target, rate), 0.0, 0.0, 1.0);
+}
@@ -0,0 +1,15 @@
+// Averages 4x4 blocks of the source
+uniform sampler2D source;
+
+out vec4 fragColor;
+
+void main() {
+    ivec2 origin = ivec2(gl_FragCoord.xy) * 4;
+    float sum = 0.0;
+    for (int y = 0; y < 4; ++y) {
+        for (int x = 0; x < 4; ++x) {
+            sum += texelFetch(source, origin + ivec2(x, y), 0).r;
+        }
+    }
+    fragColor = vec4(sum / 16.0, 0.0, 0.0, 1.0);
+}
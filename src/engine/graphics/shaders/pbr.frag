@@ -0,0 +1,200 @@
+precision highp float;
+// Based on https://learnopengl.com/PBR/Theory
+LOCATION(0) in vec3 v_worldpos;
+LOCATION(1) in vec2 v_tex;
+LOCATION(2) in vec3 v_tangent;
+LOCATION(3) in vec3 v_norm;
+LOCATION(4) flat in uint v_ent;
+
+layout(location=0) out vec4 fragColor;
+layout(location=1) out uint fragPick;
+
+// material parameters
+BINDING(0) uniform sampler2D albedo;
+BINDING(1) uniform sampler2D metallic_rough; // metallic is the B channel, roughness is G
+BINDING(2) uniform sampler2D ao;
+BINDING(3) uniform sampler2D normal_map;
+
+// image based lighting from the skybox, when there is one
+BINDING(4) uniform samplerCube irradiance_map;
+BINDING(5) uniform samplerCube prefiltered_map;
+BINDING(6) uniform sampler2D brdf_lut;
+uniform int ibl_enabled;
+uniform float prefiltered_max_lod;
+
+// lights, MAX_LIGHTS is defined by the renderer
+#define LIGHT_POINT 0
+#define LIGHT_DIRECTIONAL 1
+#define LIGHT_SPOT 2
+struct Light {
+    int kind;
+    vec3 pos;
+    vec3 direction;
+    vec3 color;
+    float range;
+    float cos_inner;
+    float cos_outer;
+    int shadow; // the light's first view in the shadow atlas, or -1
+};
+uniform Light lights[MAX_LIGHTS];
+uniform int num_lights;
+uniform vec3 cameraPosition;
+uniform mat4 view;
+
+// shadows: every shadowed light's views share one depth atlas. Directional lights
+// have a view per cascade, point lights one per cube face.
+BINDING(7) uniform sampler2DShadow shadow_atlas;
+uniform mat4 shadow_matrices[MAX_SHADOW_VIEWS];
+uniform vec4 shadow_bounds[MAX_SHADOW_VIEWS];
+uniform float cascade_splits[MAX_SHADOW_CASCADES];
+uniform int num_cascades;
+uniform float shadow_texel;
+uniform float shadow_bias;
+uniform float shadow_normal_bias;
+uniform int pcf_radius;
+
+#include "lighting.glsl"
+
+// How much of a view's light reaches a point, averaged over the PCF kernel
+float sample_shadow(int view_index, vec3 world_pos)
+{
+    vec4 clip = shadow_matrices[view_index] * vec4(world_pos, 1.0);
+    vec3 coord = clip.xyz / clip.w;
+    if (coord.z >= 1.0) {
+        return 1.0;
+    }
+
+    vec4 bounds = shadow_bounds[view_index];
+    float lit = 0.0;
+    for (int x = -pcf_radius; x <= pcf_radius; ++x) {
+        for (int y = -pcf_radius; y <= pcf_radius; ++y) {
+            vec2 uv = clamp(coord.xy + vec2(x, y) * shadow_texel, bounds.xy, bounds.zw);
+            lit += texture(shadow_atlas, vec3(uv, coord.z - shadow_bias));
+        }
+    }
+    float taps = float((2 * pcf_radius + 1) * (2 * pcf_radius + 1));
+    return lit / taps;
+}
+
+float shadow_factor(Light light, vec3 N, vec3 L)
+{
+    if (light.shadow < 0) {
+        return 1.0;
+    }
+    vec3 pos = v_worldpos + N * shadow_normal_bias * (1.0 - max(dot(N, L), 0.0));
+
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        float depth = -(view * vec4(v_worldpos, 1.0)).z;
+        for (int i = 0; i < num_cascades; ++i) {
+            if (depth < cascade_splits[i]) {
+                return sample_shadow(light.shadow + i, pos);
+            }
+        }
+        return 1.0;
+    }
+
+    // The cube face the light sees this point through: +X, -X, +Y, -Y, +Z, -Z
+    vec3 d = pos - light.pos;
+    vec3 a = abs(d);
+    int face;
+    if (a.x >= a.y && a.x >= a.z) {
+        face = d.x > 0.0 ? 0 : 1;
+    } else if (a.y >= a.z) {
+        face = d.y > 0.0 ? 2 : 3;
+    } else {
+        face = d.z > 0.0 ? 4 : 5;
+    }
+    return sample_shadow(light.shadow + face, pos);
+}
+
+// ----------------------------------------------------------------------------
+void main()
+{
+    // Tangent space calculations
+    vec3 T = normalize(v_tangent);
+    vec3 N = normalize(v_norm);
+    vec3 B = cross(N, T);
+    mat3 TBN = mat3(T, B, N);
+    N = N;//TBN * (texture(normal_map, v_tex).xyz * 2.0 - 1.0);
+
+    vec3 V = normalize(cameraPosition - v_worldpos);
+    vec3 R = reflect(-V, N); 
+    vec3 f_albedo = texture(albedo, v_tex).xyz;
+    float f_roughness = texture(metallic_rough, v_tex).g;
+    float f_metallic = texture(metallic_rough, v_tex).b;
+
+    // calculate reflectance at normal incidence; if dia-electric (like plastic) use F0 
+    // of 0.04 and if it's a metal, use their albedo color as F0 (metallic workflow)    
+    vec3 F0 = vec3(0.04); 
+    F0 = mix(F0, f_albedo, f_metallic);
+
+    // reflectance equation
+    vec3 Lo = vec3(0.0);
+    for(int i = 0; i < num_lights; ++i) 
+    {
+        // calculate per-light radiance
+        vec3 L;
+        vec3 radiance = lights[i].color;
+        if (lights[i].kind == LIGHT_DIRECTIONAL) {
+            L = -lights[i].direction;
+        } else {
+            vec3 to_light = lights[i].pos - v_worldpos;
+            float distance = length(to_light);
+            L = to_light / distance;
+            radiance *= range_attenuation(distance, lights[i].range);
+            if (lights[i].kind == LIGHT_SPOT) {
+                radiance *= spot_falloff(dot(-L, lights[i].direction), lights[i].cos_inner, lights[i].cos_outer);
+            }
+        }
+        radiance *= shadow_factor(lights[i], N, L);
+        vec3 H = normalize(V + L);
+
+        // Cook-Torrance BRDF
+        float NDF = DistributionGGX(N, H, f_roughness);
+        float G   = GeometrySmith(N, V, L, f_roughness);
+        vec3 F    = fresnelSchlick(max(dot(H, V), 0.0), F0);
+           
+        vec3 nominator    = NDF * G * F; 
+        float denominator = 4.0 * max(dot(V, N), 0.0) * max(dot(L, N), 0.0) + 0.001f; // 0.001 to prevent divide by zero.
+        vec3 brdf = nominator / denominator;
+        
+        // kS is equal to Fresnel
+        vec3 kS = F;
+        // for energy conservation, the diffuse and specular light can't
+        // be above 1.0 (unless the surface emits light); to preserve this
+        // relationship the diffuse component (kD) should equal 1.0 - kS.
+        vec3 kD = vec3(1.0) - kS;
+        // multiply kD by the inverse metalness such that only non-metals 
+        // have diffuse lighting, or a linear blend if partly metal (pure metals
+        // have no diffuse light).
+        kD *= 1.0 - f_metallic;
+
+        // scale light by NdotL
+        float NdotL = max(dot(N, L), 0.0);
+
+        // add to outgoing radiance Lo
+        Lo += (kD * f_albedo / PI + brdf) * radiance * NdotL;  // note that we already multiplied the BRDF by the Fresnel (kS) so we won't multiply by kS again
+    }   
+    
+    // ambient lighting, from the environment when there is one
+    float f_ao = texture(ao, v_tex).x;
+    vec3 ambient = vec3(0.03) * f_albedo * f_ao;
+    if (ibl_enabled != 0) {
+        float NdotV = max(dot(N, V), 0.0);
+        vec3 F = fresnelSchlickRoughness(NdotV, F0, f_roughness);
+        vec3 kD = (1.0 - F) * (1.0 - f_metallic);
+        vec3 diffuse = texture(irradiance_map, N).rgb * f_albedo;
+
+        vec3 prefiltered = textureLod(prefiltered_map, R, f_roughness * prefiltered_max_lod).rgb;
+        vec2 brdf = texture(brdf_lut, vec2(NdotV, f_roughness)).rg;
+        vec3 specular = prefiltered * (F * brdf.x + brdf.y);
+
+        ambient = (kD * diffuse + specular) * f_ao;
+    }
+
+    // Linear HDR, tonemapped in the composition pass
+    vec3 color = ambient + Lo;
+
+    fragColor = vec4(color, 1.0);
+    fragPick = v_ent;
+}
\ No newline at end of file
@@ -1,5 +1,3 @@
-#version 450
-
 layout(location=0) in vec3 position;
 layout(location=1) in vec2 texcoord;
 layout(location=2) in vec3 normal;
@@ -9,21 +7,28 @@ layout(location=3) in vec3 tangent;
 layout(location = 4) in mat4 world_matrix;
 layout(location = 8) in uint entity;
 
-layout(location=0) out vec3 v_worldpos;
-layout(location=1) out vec2 v_tex;
-layout(location=2) out vec3 v_tangent;
-layout(location=3) out vec3 v_norm;
-layout(location=4) out uint v_ent;
+// The depth-only variant, for shadow maps, only needs the position
+#ifndef DEPTH_ONLY
+LOCATION(0) out vec3 v_worldpos;
+LOCATION(1) out vec2 v_tex;
+LOCATION(2) out vec3 v_tangent;
+LOCATION(3) out vec3 v_norm;
+LOCATION(4) flat out uint v_ent;
+#endif
 
 // constants
 uniform mat4 proj;
 uniform mat4 view;
 
 void main() {
+#ifdef DEPTH_ONLY
+    gl_Position = proj * view * world_matrix * vec4(position, 1.0);
+#else
     v_tex = texcoord;
     v_ent = entity;
     v_tangent = (world_matrix * vec4(tangent, 0.0)).xyz;
     v_norm = (world_matrix * vec4(normal, 0.0)).xyz;
     v_worldpos = (world_matrix * vec4(position, 1.0)).xyz;
     gl_Position = proj * view * vec4(v_worldpos, 1.0);
-}
\ No newline at end of file
+#endif
+}
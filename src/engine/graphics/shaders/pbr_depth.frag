@@ -0,0 +1,3 @@
+// Shadow map passes only write depth
+void main() {
+}
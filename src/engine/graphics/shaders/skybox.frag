@@ -0,0 +1,11 @@
+uniform samplerCube tex;
+
+in vec3 out_direction;
+
+out vec4 fragColor;
+out uint fragPick;
+
+void main() {
+    fragColor = texture(tex, out_direction);
+    fragPick = 0u;
+}
@@ -0,0 +1,12 @@
+// constants
+uniform mat4 proj;
+uniform mat4 view;
+
+in vec3 position;
+
+out vec3 out_direction;
+
+void main() {
+    out_direction = position;
+    gl_Position = proj * view * vec4(position, 1.0);
+}
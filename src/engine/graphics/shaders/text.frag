@@ -0,0 +1,10 @@
+// The atlas holds each glyph's coverage
+uniform sampler2D atlas;
+
+in vec2 frag_texcoord;
+in vec4 frag_color;
+out vec4 fragColor;
+
+void main() {
+    fragColor = vec4(frag_color.rgb, frag_color.a * texture(atlas, frag_texcoord).r);
+}
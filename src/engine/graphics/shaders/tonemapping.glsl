@@ -0,0 +1,47 @@
+// Tonemapping operators, mapping HDR colors into [0, 1]
+#define TONEMAP_NONE 0
+#define TONEMAP_REINHARD 1
+#define TONEMAP_ACES 2
+#define TONEMAP_FILMIC 3
+
+// ----------------------------------------------------------------------------
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+vec3 tonemap_aces(vec3 x)
+{
+    const float a = 2.51;
+    const float b = 0.03;
+    const float c = 2.43;
+    const float d = 0.59;
+    const float e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
+}
+// ----------------------------------------------------------------------------
+// John Hable's Uncharted 2 curve
+vec3 hable(vec3 x)
+{
+    const float A = 0.15;
+    const float B = 0.50;
+    const float C = 0.10;
+    const float D = 0.20;
+    const float E = 0.02;
+    const float F = 0.30;
+    return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
+}
+
+vec3 tonemap_filmic(vec3 x)
+{
+    const float white_point = 11.2;
+    return hable(2.0 * x) / hable(vec3(white_point));
+}
+// ----------------------------------------------------------------------------
+vec3 tonemap(vec3 color, int tonemapper)
+{
+    if (tonemapper == TONEMAP_REINHARD) {
+        return color / (color + vec3(1.0));
+    } else if (tonemapper == TONEMAP_ACES) {
+        return tonemap_aces(color);
+    } else if (tonemapper == TONEMAP_FILMIC) {
+        return tonemap_filmic(color);
+    }
+    return clamp(color, 0.0, 1.0);
+}
@@ -0,0 +1,12 @@
+// Textures are sRGB, so sampling linearizes them. The target is already gamma corrected,
+// which means they have to be encoded again.
+uniform sampler2D tex;
+
+in vec2 frag_texcoord;
+in vec4 frag_color;
+out vec4 fragColor;
+
+void main() {
+    vec4 texel = texture(tex, frag_texcoord);
+    fragColor = vec4(pow(texel.rgb, vec3(1.0 / 2.2)), texel.a) * frag_color;
+}
@@ -0,0 +1,15 @@
+in vec2 position; // in pixels from the top left
+in vec2 texcoord;
+in vec4 color;
+
+uniform vec2 resolution;
+
+out vec2 frag_texcoord;
+out vec4 frag_color;
+
+void main() {
+    frag_texcoord = texcoord;
+    frag_color = color;
+    vec2 ndc = position / resolution * 2.0 - 1.0;
+    gl_Position = vec4(ndc.x, -ndc.y, 0.0, 1.0);
+}
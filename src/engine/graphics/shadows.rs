@@ -0,0 +1,404 @@
+use crate::engine::prelude::*;
+use crate::engine::systems::LightInstance;
+use glium::backend::Facade;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::{DepthTexture2d, DepthFormat, MipmapsOption};
+use glium::uniforms::{SamplerBehavior, UniformValue};
+use nalgebra::{Matrix4, Point3, Vector3};
+use super::shaders::{self, ShaderProgram};
+
+/// How much the renderer spends on shadows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowQuality {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl ShadowQuality {
+    /// The side of the depth atlas that every shadow view of a frame shares
+    fn atlas_size(self) -> u32 {
+        match self {
+            ShadowQuality::Off => 1,
+            ShadowQuality::Low => 2048,
+            ShadowQuality::Medium => 4096,
+            ShadowQuality::High => 6144,
+        }
+    }
+
+    fn cascades(self) -> usize {
+        match self {
+            ShadowQuality::Off => 0,
+            ShadowQuality::Low => 2,
+            ShadowQuality::Medium => 3,
+            ShadowQuality::High => consts::MAX_SHADOW_CASCADES,
+        }
+    }
+
+    fn point_lights(self) -> usize {
+        match self {
+            ShadowQuality::Off => 0,
+            ShadowQuality::Low => 1,
+            ShadowQuality::Medium => 2,
+            ShadowQuality::High => consts::MAX_SHADOWED_POINT_LIGHTS,
+        }
+    }
+
+    /// Texels sampled on each side of a lookup, on top of the hardware's 2x2 filtering
+    fn pcf_radius(self) -> i32 {
+        match self {
+            ShadowQuality::Off | ShadowQuality::Low => 0,
+            ShadowQuality::Medium => 1,
+            ShadowQuality::High => 2,
+        }
+    }
+}
+
+impl std::str::FromStr for ShadowQuality {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ShadowQuality::Off),
+            "low" => Ok(ShadowQuality::Low),
+            "medium" => Ok(ShadowQuality::Medium),
+            "high" => Ok(ShadowQuality::High),
+            _ => Err(anyhow!("Unknown shadow quality {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ShadowQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            ShadowQuality::Off => "off",
+            ShadowQuality::Low => "low",
+            ShadowQuality::Medium => "medium",
+            ShadowQuality::High => "high",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowSettings {
+    pub quality: ShadowQuality,
+
+    /// Subtracted from a surface's depth before it's compared, so it doesn't shadow itself
+    pub bias: f32,
+
+    /// How far lookups are pushed off the surface along its normal, in world units.
+    /// Surfaces facing the light get none of it, grazing ones all of it.
+    pub normal_bias: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            quality: ShadowQuality::Medium,
+            bias: consts::DEFAULT_SHADOW_BIAS,
+            normal_bias: consts::DEFAULT_SHADOW_NORMAL_BIAS,
+        }
+    }
+}
+
+/// One depth rendering of the scene, into a tile of the atlas
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowView {
+    /// The light's projection * view, that casters are culled and drawn with
+    pub view_proj: Matrix4<f32>,
+
+    /// From world space to the tile's atlas coordinates and depth, for the lookups
+    atlas_matrix: Matrix4<f32>,
+
+    /// Where lookups are clamped to so filtering doesn't read the neighbouring tiles,
+    /// in atlas coordinates: min x, min y, max x, max y
+    bounds: [f32; 4],
+    pub viewport: glium::Rect,
+}
+
+impl ShadowView {
+    fn new(view_proj: Matrix4<f32>, tile: glium::Rect, atlas_size: u32) -> ShadowView {
+        let size = atlas_size as f32;
+        let (left, bottom) = (tile.left as f32 / size, tile.bottom as f32 / size);
+        let (width, height) = (tile.width as f32 / size, tile.height as f32 / size);
+
+        // Clip space [-1, 1] to the tile, and depth to [0, 1]
+        let to_tile = Matrix4::new_translation(&Vector3::new(left + width / 2.0, bottom + height / 2.0, 0.5))
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(width / 2.0, height / 2.0, 0.5));
+        let half_texel = 0.5 / size;
+        ShadowView {
+            view_proj,
+            atlas_matrix: to_tile * view_proj,
+            bounds: [
+                left + half_texel,
+                bottom + half_texel,
+                left + width - half_texel,
+                bottom + height - half_texel,
+            ],
+            viewport: tile,
+        }
+    }
+}
+
+/// The view distances where each cascade ends, spread between even and logarithmic
+/// steps by `lambda`, so the cascades near the camera are small and sharp.
+pub fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    (1..=count)
+        .map(|i| {
+            let f = i as f32 / count as f32;
+            let logarithmic = near * (far / near).powf(f);
+            let uniform = near + (far - near) * f;
+            lambda * logarithmic + (1.0 - lambda) * uniform
+        })
+        .collect()
+}
+
+/// Square tiles for `count` views, in a grid over an atlas `size` texels wide
+pub fn atlas_tiles(count: usize, size: u32) -> Vec<glium::Rect> {
+    let grid = ((count as f32).sqrt().ceil() as u32).max(1);
+    let tile = size / grid;
+    (0..count as u32)
+        .map(|i| glium::Rect {
+            left: (i % grid) * tile,
+            bottom: (i / grid) * tile,
+            width: tile,
+            height: tile,
+        })
+        .collect()
+}
+
+/// The corners of the camera's view between two view distances. Every corner moves
+/// linearly along its ray between the near and the far clip plane.
+fn frustum_slice(inverse_view_proj: &Matrix4<f32>, from: f32, to: f32) -> Vec<Point3<f32>> {
+    let (near, far) = (consts::DEFAULT_NEAR_CLIP, consts::DEFAULT_FAR_CLIP);
+    let mut corners = Vec::with_capacity(8);
+    for &(x, y) in &[(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+        let ray_near = inverse_view_proj.transform_point(&Point3::new(x, y, -1.0));
+        let ray_far = inverse_view_proj.transform_point(&Point3::new(x, y, 1.0));
+        for &distance in &[from, to] {
+            corners.push(ray_near + (ray_far - ray_near) * ((distance - near) / (far - near)));
+        }
+    }
+    corners
+}
+
+/// An orthographic view down `direction` covering `corners`. It's fit to their bounding
+/// sphere so it keeps its size as the camera turns, and snapped to whole texels so shadow
+/// edges don't crawl as the camera moves.
+fn cascade_view(corners: &[Point3<f32>], direction: &Vector3<f32>, tile_size: u32) -> Matrix4<f32> {
+    let center = corners.iter().fold(Vector3::zeros(), |sum, p| sum + p.coords) / corners.len() as f32;
+    let radius = corners.iter().map(|p| (p.coords - center).norm()).fold(0.0, f32::max);
+    let up = if direction.y.abs() > 0.99 { Vector3::x() } else { Vector3::y() };
+
+    let rotation = Matrix4::look_at_rh(&Point3::origin(), &Point3::from(*direction), &up);
+    let texel = 2.0 * radius / tile_size as f32;
+    let mut snapped = rotation.transform_point(&Point3::from(center));
+    snapped.x = (snapped.x / texel).floor() * texel;
+    snapped.y = (snapped.y / texel).floor() * texel;
+    let center = rotation.transpose().transform_point(&snapped);
+
+    let eye = center - direction * (radius + consts::SHADOW_CASTER_DISTANCE);
+    let view = Matrix4::look_at_rh(&eye, &center, &up);
+    let proj = Matrix4::new_orthographic(
+        -radius, radius, -radius, radius,
+        0.0, 2.0 * radius + consts::SHADOW_CASTER_DISTANCE,
+    );
+    proj * view
+}
+
+/// The six 90 degree views around a point light, in the order pbr.frag picks them by the
+/// largest axis of the direction from the light: +X, -X, +Y, -Y, +Z, -Z
+fn point_light_views(position: [f32; 3], range: f32) -> Vec<Matrix4<f32>> {
+    let far = range.min(consts::POINT_SHADOW_MAX_RANGE).max(consts::POINT_SHADOW_NEAR_CLIP * 2.0);
+    let proj = Matrix4::new_perspective(1.0, std::f32::consts::FRAC_PI_2, consts::POINT_SHADOW_NEAR_CLIP, far);
+    let eye = Point3::from(position);
+    let faces = [
+        (Vector3::x(), -Vector3::y()),
+        (-Vector3::x(), -Vector3::y()),
+        (Vector3::y(), Vector3::z()),
+        (-Vector3::y(), -Vector3::z()),
+        (Vector3::z(), -Vector3::y()),
+        (-Vector3::z(), -Vector3::y()),
+    ];
+    faces.iter()
+        .map(|(direction, up)| proj * Matrix4::look_at_rh(&eye, &(eye + direction), up))
+        .collect()
+}
+
+/// The shadows of a frame: every shadowed light's views, rendered into one depth atlas
+/// with a depth-only variant of the PBR program.
+pub struct ShadowMaps {
+    settings: ShadowSettings,
+    program: ShaderProgram,
+    atlas: DepthTexture2d,
+    views: Vec<ShadowView>,
+
+    // Where each directional cascade ends
+    splits: Vec<f32>,
+
+    // The first view of each of the frame's lights, or -1 for unshadowed ones
+    light_views: Vec<i32>,
+}
+
+fn make_atlas(display: &dyn Facade, size: u32) -> anyhow::Result<DepthTexture2d> {
+    DepthTexture2d::empty_with_format(display, DepthFormat::I24, MipmapsOption::NoMipmap, size, size)
+        .map_err(|e| anyhow!("Failed to create a {}x{} shadow atlas: {}", size, size, e))
+}
+
+impl ShadowMaps {
+    pub fn new(display: &dyn Facade) -> anyhow::Result<ShadowMaps> {
+        let settings = ShadowSettings {
+            quality: ShadowQuality::Off,
+            ..Default::default()
+        };
+        Ok(ShadowMaps {
+            settings,
+            program: shaders::pbr_depth(display)?,
+            atlas: make_atlas(display, settings.quality.atlas_size())?,
+            views: Vec::new(),
+            splits: Vec::new(),
+            light_views: Vec::new(),
+        })
+    }
+
+    pub fn settings(&self) -> ShadowSettings {
+        self.settings
+    }
+
+    /// Changing the quality reallocates the atlas. When that fails shadows are turned off.
+    pub fn set_settings(&mut self, display: &dyn Facade, settings: ShadowSettings) {
+        if settings.quality.atlas_size() != self.settings.quality.atlas_size() {
+            match make_atlas(display, settings.quality.atlas_size()) {
+                Ok(atlas) => self.atlas = atlas,
+                Err(e) => {
+                    log::err(&e);
+                    self.settings = ShadowSettings { quality: ShadowQuality::Off, ..settings };
+                    self.atlas = make_atlas(display, ShadowQuality::Off.atlas_size())
+                        .expect("Failed to create the empty shadow atlas");
+                    return;
+                }
+            }
+        }
+        self.settings = settings;
+    }
+
+    /// Picks the shadowed lights among the frame's lights and places their views: the first
+    /// shadow casting directional light gets the cascades, then as many shadow casting point
+    /// lights as the quality allows get a view per cube face.
+    pub fn plan(&mut self, lights: &[LightInstance], camera_view: &Matrix4<f32>, projection: &Matrix4<f32>) {
+        let quality = self.settings.quality;
+        let directional = lights.iter()
+            .position(|l| l.casts_shadows && l.is_directional())
+            .filter(|_| quality.cascades() > 0);
+        let points: Vec<usize> = lights.iter().enumerate()
+            .filter(|(_, l)| l.casts_shadows && l.is_point())
+            .map(|(i, _)| i)
+            .take(quality.point_lights())
+            .collect();
+
+        let count = directional.map_or(0, |_| quality.cascades()) + points.len() * 6;
+        let atlas_size = quality.atlas_size();
+        let mut tiles = atlas_tiles(count, atlas_size).into_iter();
+
+        self.views.clear();
+        self.splits.clear();
+        self.light_views.clear();
+        self.light_views.resize(lights.len(), -1);
+
+        if let Some(i) = directional {
+            self.light_views[i] = self.views.len() as i32;
+            self.splits = cascade_splits(consts::DEFAULT_NEAR_CLIP, consts::SHADOW_DISTANCE, quality.cascades(),
+                consts::SHADOW_CASCADE_SPLIT_LAMBDA);
+            let inverse = (projection * camera_view).try_inverse().unwrap_or_else(Matrix4::identity);
+            let direction = Vector3::from(lights[i].direction);
+            let mut from = consts::DEFAULT_NEAR_CLIP;
+            for &to in self.splits.iter() {
+                let tile = tiles.next().unwrap();
+                let view_proj = cascade_view(&frustum_slice(&inverse, from, to), &direction, tile.width);
+                self.views.push(ShadowView::new(view_proj, tile, atlas_size));
+                from = to;
+            }
+        }
+        for i in points {
+            self.light_views[i] = self.views.len() as i32;
+            for view_proj in point_light_views(lights[i].position, lights[i].range) {
+                self.views.push(ShadowView::new(view_proj, tiles.next().unwrap(), atlas_size));
+            }
+        }
+    }
+
+    pub fn views(&self) -> &[ShadowView] {
+        &self.views
+    }
+
+    pub fn program(&self) -> &ShaderProgram {
+        &self.program
+    }
+
+    pub fn framebuffer<'a>(&'a self, display: &dyn Facade) -> SimpleFrameBuffer<'a> {
+        SimpleFrameBuffer::depth_only(display, &self.atlas).unwrap()
+    }
+
+    /// The first shadow view of the frame's `light`th light, or -1
+    pub fn light_view(&self, light: usize) -> i32 {
+        self.light_views.get(light).cloned().unwrap_or(-1)
+    }
+
+    /// Sets the lookup uniforms of pbr.frag
+    pub fn visit_values<'a, F: FnMut(&str, UniformValue<'a>)>(&'a self, f: &mut F) {
+        f("shadow_atlas", UniformValue::DepthTexture2d(&self.atlas, Some(SamplerBehavior {
+            magnify_filter: glium::uniforms::MagnifySamplerFilter::Linear,
+            minify_filter: glium::uniforms::MinifySamplerFilter::Linear,
+            wrap_function: (
+                glium::uniforms::SamplerWrapFunction::Clamp,
+                glium::uniforms::SamplerWrapFunction::Clamp,
+                glium::uniforms::SamplerWrapFunction::Clamp,
+            ),
+            depth_texture_comparison: Some(glium::uniforms::DepthTextureComparison::LessOrEqual),
+            ..Default::default()
+        })));
+        f("shadow_texel", UniformValue::Float(1.0 / self.settings.quality.atlas_size() as f32));
+        f("shadow_bias", UniformValue::Float(self.settings.bias));
+        f("shadow_normal_bias", UniformValue::Float(self.settings.normal_bias));
+        f("pcf_radius", UniformValue::SignedInt(self.settings.quality.pcf_radius()));
+        f("num_cascades", UniformValue::SignedInt(self.splits.len() as i32));
+        for (i, split) in self.splits.iter().enumerate() {
+            f(&format!("cascade_splits[{}]", i), UniformValue::Float(*split));
+        }
+        for (i, view) in self.views.iter().enumerate() {
+            f(&format!("shadow_matrices[{}]", i), UniformValue::Mat4(view.atlas_matrix.into()));
+            f(&format!("shadow_bounds[{}]", i), UniformValue::Vec4(view.bounds));
+        }
+    }
+
+    pub fn hot_reload(&mut self, display: &dyn Facade) {
+        self.program.reload_if_changed(display);
+    }
+}
+
+#[test]
+fn cascades_split_the_shadow_distance() {
+    let splits = cascade_splits(0.1, 100.0, 4, 0.75);
+    assert_eq!(splits.len(), 4);
+    assert!(splits.windows(2).all(|w| w[0] < w[1]));
+    assert!((splits[3] - 100.0).abs() < 0.001);
+
+    // Logarithmic splits keep the first cascade much smaller than an even split
+    assert!(splits[0] < 25.0);
+}
+
+#[test]
+fn atlas_tiles_fit_without_overlapping() {
+    for count in 1..=consts::MAX_SHADOW_VIEWS {
+        let tiles = atlas_tiles(count, 4096);
+        assert_eq!(tiles.len(), count);
+        for (i, a) in tiles.iter().enumerate() {
+            assert!(a.left + a.width <= 4096 && a.bottom + a.height <= 4096);
+            for b in tiles[i + 1..].iter() {
+                assert!(a.left + a.width <= b.left || b.left + b.width <= a.left
+                    || a.bottom + a.height <= b.bottom || b.bottom + b.height <= a.bottom);
+            }
+        }
+    }
+}
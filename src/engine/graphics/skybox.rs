@@ -1,9 +1,9 @@
-use glium::Display;
+use glium::backend::Facade;
 use super::vertex::VertexSkybox;
 use super::models::{Model, CachedModel, CachedPrimitive};
 
 impl super::Renderer {
-    pub fn create_skybox_vbuffer(display: &Display) -> Model<VertexSkybox> {
+    pub fn create_skybox_vbuffer(display: &dyn Facade) -> Model<VertexSkybox> {
         Model::from_data(
         CachedModel { primitives: vec![CachedPrimitive {
             vertices: vec![
@@ -106,7 +106,9 @@ impl super::Renderer {
             normalmap: None,
             metal_roughness: None,
             occlusion: None,
-        }]},
-        display).expect("Failed to create skybox vbuffer")
+        }],
+        // The skybox is drawn around the camera, so it's never culled
+        bounds: Default::default()},
+        display, None).expect("Failed to create skybox vbuffer")
     }
 }
@@ -0,0 +1,273 @@
+use crate::engine::prelude::*;
+use crate::engine::components::{TextAlign, TextAnchor};
+use crate::engine::systems::TextInstance;
+use glium::backend::Facade;
+use glium::texture::{Texture2d, RawImage2d, ClientFormat, UncompressedFloatFormat, MipmapsOption};
+use glium::{Surface, VertexBuffer, uniform};
+use rusttype::{Font, Scale, PositionedGlyph, gpu_cache::{Cache, CacheWriteErr}};
+use nalgebra::{Matrix4, Vector4};
+use super::shaders::{self, ShaderProgram};
+use super::vertex::VertexText;
+
+/// Breaks text into lines no wider than `max_width`, at spaces. Newlines always break,
+/// and a word wider than a whole line gets a line of its own.
+pub fn wrap_lines<F: Fn(&str) -> f32>(text: &str, max_width: Option<f32>, measure: F) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let max_width = match max_width {
+            Some(w) => w,
+            None => {
+                lines.push(String::from(paragraph));
+                continue;
+            }
+        };
+
+        let mut line = String::new();
+        for word in paragraph.split(' ') {
+            if line.is_empty() {
+                line.push_str(word);
+                continue;
+            }
+            let candidate = format!("{} {}", line, word);
+            if measure(&candidate) <= max_width {
+                line = candidate;
+            } else {
+                lines.push(std::mem::replace(&mut line, String::from(word)));
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn line_width(font: &Font, scale: Scale, line: &str) -> f32 {
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in line.chars() {
+        let glyph = font.glyph(c);
+        if let Some(previous) = previous {
+            width += font.pair_kerning(scale, previous, glyph.id());
+        }
+        previous = Some(glyph.id());
+        width += glyph.scaled(scale).h_metrics().advance_width;
+    }
+    width
+}
+
+fn load_font() -> anyhow::Result<Font<'static>> {
+    use anyhow::Context;
+    let bytes = std::fs::read(consts::FONT_PATH)
+        .context(format!("Failed to read font {}", consts::FONT_PATH))?;
+    Font::try_from_vec(bytes).ok_or_else(|| anyhow!("Invalid font {}", consts::FONT_PATH))
+}
+
+/// Screen position in pixels of a world position, or None when it's behind the camera
+fn project(view_proj: &Matrix4<f32>, position: [f32; 3], resolution: [u32; 2]) -> Option<[f32; 2]> {
+    let clip = view_proj * Vector4::new(position[0], position[1], position[2], 1.0);
+    if clip.w <= 0.0 {
+        return None;
+    }
+    Some([
+        (clip.x / clip.w + 1.0) * 0.5 * resolution[0] as f32,
+        (1.0 - clip.y / clip.w) * 0.5 * resolution[1] as f32,
+    ])
+}
+
+fn build_atlas(display: &dyn Facade, size: u32) -> Texture2d {
+    Texture2d::empty_with_format(display, UncompressedFloatFormat::U8, MipmapsOption::NoMipmap, size, size)
+        .unwrap()
+}
+
+/// Draws labels with the glyphs rasterized on demand into an atlas texture.
+pub struct TextRenderer {
+    font: Font<'static>,
+    program: ShaderProgram,
+    cache: Cache<'static>,
+    atlas: Texture2d,
+    atlas_size: u32,
+
+    // This frame's glyph quads in pixels, by layer
+    layers: Vec<Option<VertexBuffer<VertexText>>>,
+}
+
+impl TextRenderer {
+    pub fn new(display: &dyn Facade) -> anyhow::Result<TextRenderer> {
+        let atlas_size = consts::TEXT_ATLAS_SIZE;
+        Ok(TextRenderer {
+            font: load_font()?,
+            program: shaders::text(display)?,
+            cache: Cache::builder().dimensions(atlas_size, atlas_size).build(),
+            atlas: build_atlas(display, atlas_size),
+            atlas_size,
+            layers: Vec::new(),
+        })
+    }
+
+    fn layout(&self, text: &TextInstance, origin: [f32; 2], ui_scale: f32,
+        glyphs: &mut Vec<(PositionedGlyph<'static>, [f32; 4])>) {
+        let scale = Scale::uniform(text.style.size * ui_scale);
+        let metrics = self.font.v_metrics(scale);
+        let line_height = metrics.ascent - metrics.descent + metrics.line_gap;
+
+        let wrap_width = text.style.wrap_width.map(|w| w * ui_scale);
+        let lines = wrap_lines(&text.text, wrap_width, |l| line_width(&self.font, scale, l));
+        for (i, line) in lines.iter().enumerate() {
+            let width = line_width(&self.font, scale, line);
+            let x = match text.style.align {
+                TextAlign::Left => origin[0],
+                TextAlign::Center => origin[0] - width / 2.0,
+                TextAlign::Right => origin[0] - width,
+            };
+            let baseline = origin[1] + metrics.ascent + i as f32 * line_height;
+            glyphs.extend(self.font.layout(line, scale, rusttype::point(x.round(), baseline.round()))
+                .map(|g| (g, text.style.color)));
+        }
+    }
+
+    /// Rasterizes the glyphs missing from the atlas. A full atlas is doubled in size.
+    fn cache_glyphs(&mut self, display: &dyn Facade, glyphs: &[(PositionedGlyph<'static>, [f32; 4])])
+    -> anyhow::Result<()> {
+        loop {
+            for (glyph, _) in glyphs {
+                self.cache.queue_glyph(0, glyph.clone());
+            }
+
+            let atlas = &self.atlas;
+            let result = self.cache.cache_queued(|rect, data| {
+                atlas.main_level().write(glium::Rect {
+                    left: rect.min.x,
+                    bottom: rect.min.y,
+                    width: rect.width(),
+                    height: rect.height(),
+                }, RawImage2d {
+                    data: std::borrow::Cow::Borrowed(data),
+                    width: rect.width(),
+                    height: rect.height(),
+                    format: ClientFormat::U8,
+                });
+            });
+
+            match result {
+                Ok(_) => return Ok(()),
+                Err(CacheWriteErr::NoRoomForWholeQueue) if self.atlas_size < consts::TEXT_ATLAS_MAX_SIZE => {
+                    self.atlas_size *= 2;
+                    self.cache.to_builder().dimensions(self.atlas_size, self.atlas_size).rebuild(&mut self.cache);
+                    self.atlas = build_atlas(display, self.atlas_size);
+                },
+                Err(e) => return Err(anyhow!("Failed to cache glyphs: {}", e)),
+            }
+        }
+    }
+
+    /// Lays out this frame's labels, in layers that are drawn separately. World anchored
+    /// labels are projected with `view_proj`. Sizes and screen positions are in logical
+    /// pixels, which are `ui_scale` physical pixels.
+    pub fn prepare(&mut self, display: &dyn Facade, layers: &[&[TextInstance]], resolution: [u32; 2],
+        ui_scale: f32, view_proj: &Matrix4<f32>) {
+        self.layers.clear();
+
+        let mut glyphs = Vec::new();
+        let mut layer_ends = Vec::with_capacity(layers.len());
+        for texts in layers {
+            for text in texts.iter() {
+                let origin = match text.anchor {
+                    TextAnchor::Screen(position) => [position[0] * ui_scale, position[1] * ui_scale],
+                    TextAnchor::World(position) => match project(view_proj, position, resolution) {
+                        Some(position) => position,
+                        None => continue,
+                    },
+                };
+                self.layout(text, origin, ui_scale, &mut glyphs);
+            }
+            layer_ends.push(glyphs.len());
+        }
+        if glyphs.is_empty() {
+            return;
+        }
+
+        // Every layer's glyphs must be in the atlas at once
+        if let Err(e) = self.cache_glyphs(display, &glyphs) {
+            log::err(&e);
+            return;
+        }
+
+        let mut start = 0;
+        for end in layer_ends {
+            let vertices = self.build_vertices(&glyphs[start..end]);
+            self.layers.push(if vertices.is_empty() {
+                None
+            } else {
+                VertexBuffer::new(display, &vertices).ok()
+            });
+            start = end;
+        }
+    }
+
+    fn build_vertices(&self, glyphs: &[(PositionedGlyph<'static>, [f32; 4])]) -> Vec<VertexText> {
+        let mut vertices = Vec::with_capacity(glyphs.len() * 6);
+        for (glyph, color) in glyphs.iter() {
+            // Whitespace has no rect
+            if let Ok(Some((uv, rect))) = self.cache.rect_for(0, glyph) {
+                let vertex = |x: i32, y: i32, u: f32, v: f32| VertexText {
+                    position: [x as f32, y as f32],
+                    texcoord: [u, v],
+                    color: *color,
+                };
+                vertices.extend_from_slice(&[
+                    vertex(rect.min.x, rect.min.y, uv.min.x, uv.min.y),
+                    vertex(rect.max.x, rect.min.y, uv.max.x, uv.min.y),
+                    vertex(rect.max.x, rect.max.y, uv.max.x, uv.max.y),
+                    vertex(rect.min.x, rect.min.y, uv.min.x, uv.min.y),
+                    vertex(rect.max.x, rect.max.y, uv.max.x, uv.max.y),
+                    vertex(rect.min.x, rect.max.y, uv.min.x, uv.max.y),
+                ]);
+            }
+        }
+        vertices
+    }
+
+    /// Draws a prepared layer over whatever is in `target`
+    pub fn draw<S: Surface>(&self, target: &mut S, layer: usize, resolution: [u32; 2]) {
+        let vertices = match self.layers.get(layer) {
+            Some(Some(vertices)) => vertices,
+            _ => return,
+        };
+        target.draw(
+            vertices,
+            glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+            &self.program,
+            &uniform! {
+                atlas: self.atlas.sampled()
+                    .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
+                    .minify_filter(glium::uniforms::MinifySamplerFilter::Nearest),
+                resolution: [resolution[0] as f32, resolution[1] as f32],
+            },
+            &glium::DrawParameters {
+                blend: glium::Blend::alpha_blending(),
+                ..Default::default()
+            }
+        ).unwrap();
+    }
+
+    pub fn hot_reload(&mut self, display: &dyn Facade) {
+        self.program.reload_if_changed(display);
+    }
+}
+
+#[test]
+fn wrap_lines_breaks_at_spaces() {
+    // Every character is 1 wide
+    let measure = |s: &str| s.chars().count() as f32;
+    assert_eq!(wrap_lines("one two three", Some(7.0), measure), vec!["one two", "three"]);
+    assert_eq!(wrap_lines("one two three", None, measure), vec!["one two three"]);
+    assert_eq!(wrap_lines("one\n\ntwo", Some(100.0), measure), vec!["one", "", "two"]);
+    assert_eq!(wrap_lines("a incomprehensibly b", Some(5.0), measure), vec!["a", "incomprehensibly", "b"]);
+}
+
+#[test]
+fn bundled_font_is_monospaced() {
+    let font = load_font().unwrap();
+    let scale = Scale::uniform(consts::DEFAULT_TEXT_SIZE);
+    assert!(line_width(&font, scale, "iii") > 0.0);
+    assert_eq!(line_width(&font, scale, "iii"), line_width(&font, scale, "MMM"));
+}
@@ -0,0 +1,349 @@
+//! Textures as the GPU wants them: block compressed, with every mip level. Decoding and
+//! compressing an image is slow, so the result is cached in `consts::TEXTURE_CACHE_PATH`
+//! (under the configured cache directory, if there is one), named after a hash of the
+//! encoded image. That works the same for image files and the
+//! images embedded in models, and a changed image just gets a new cache file.
+use crate::engine::prelude::*;
+use glium::backend::Facade;
+use glium::texture::{CompressedSrgbTexture2d, CompressedTexture2d, CompressedFormat, CompressedSrgbFormat,
+    CompressedMipmapsOption, RawImage2d};
+
+/// S3TC block formats. Both encode 4x4 texel blocks, BC3 with a separate alpha block.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+enum BlockFormat {
+    Bc1,
+    Bc3,
+}
+
+impl BlockFormat {
+    fn block_bytes(self) -> usize {
+        match self {
+            BlockFormat::Bc1 => 8,
+            BlockFormat::Bc3 => 16,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedTexture {
+    format: BlockFormat,
+    width: u32,
+    height: u32,
+
+    /// Every mip level down to 1x1, rows from the top like the source image
+    levels: Vec<Vec<u8>>,
+}
+
+impl CachedTexture {
+    /// Caches cut short are rebuilt. Other versions are caught by the cache header.
+    fn validate(&self) -> anyhow::Result<()> {
+        let sizes = mip_sizes(self.width, self.height);
+        if sizes.len() != self.levels.len() {
+            return Err(anyhow!("The cached texture is missing mip levels"));
+        }
+        for (level, (size, data)) in sizes.iter().zip(self.levels.iter()).enumerate() {
+            if data.len() != compressed_size(*size, self.format) {
+                return Err(anyhow!("Level {} of the cached texture is incomplete", level));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The size of every mip level of a texture, from the full size down to 1x1
+fn mip_sizes(width: u32, height: u32) -> Vec<[u32; 2]> {
+    let mut sizes = vec![[width, height]];
+    while sizes.last().unwrap() != &[1, 1] {
+        let [w, h] = *sizes.last().unwrap();
+        sizes.push([(w / 2).max(1), (h / 2).max(1)]);
+    }
+    sizes
+}
+
+fn compressed_size(size: [u32; 2], format: BlockFormat) -> usize {
+    (((size[0] + 3) / 4) * ((size[1] + 3) / 4)) as usize * format.block_bytes()
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    (c as f32 / 255.0).powf(2.2)
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    (c.powf(1.0 / 2.2) * 255.0).round() as u8
+}
+
+/// Halves an image with a box filter. Colors are averaged as light, not as sRGB values,
+/// so albedo mips don't darken.
+fn downsample(image: &image::RgbaImage, srgb: bool) -> image::RgbaImage {
+    let (width, height) = image.dimensions();
+    let (half_width, half_height) = ((width / 2).max(1), (height / 2).max(1));
+    image::RgbaImage::from_fn(half_width, half_height, |x, y| {
+        let mut sum = [0.0f32; 4];
+        for (dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let texel = image.get_pixel((x * 2 + dx).min(width - 1), (y * 2 + dy).min(height - 1));
+            for (c, s) in sum.iter_mut().enumerate() {
+                *s += if srgb && c < 3 { srgb_to_linear(texel[c]) } else { texel[c] as f32 };
+            }
+        }
+        let mut result = [0u8; 4];
+        for (c, r) in result.iter_mut().enumerate() {
+            *r = if srgb && c < 3 { linear_to_srgb(sum[c] / 4.0) } else { (sum[c] / 4.0).round() as u8 };
+        }
+        image::Rgba(result)
+    })
+}
+
+fn to_565(c: [f32; 3]) -> u16 {
+    let r = (c[0] * 31.0 / 255.0).round() as u16;
+    let g = (c[1] * 63.0 / 255.0).round() as u16;
+    let b = (c[2] * 31.0 / 255.0).round() as u16;
+    (r << 11) | (g << 5) | b
+}
+
+fn from_565(c: u16) -> [f32; 3] {
+    let (r, g, b) = ((c >> 11) & 31, (c >> 5) & 63, c & 31);
+    [
+        ((r << 3) | (r >> 2)) as f32,
+        ((g << 2) | (g >> 4)) as f32,
+        ((b << 3) | (b >> 2)) as f32,
+    ]
+}
+
+/// A BC1 color block: two endpoints from the block's bounding box, inset a little since
+/// the extremes are rarely worth a whole endpoint, and the closest of the four colors
+/// between them for every texel.
+fn encode_color_block(texels: &[[u8; 4]; 16], out: &mut Vec<u8>) {
+    let mut min = [255.0f32; 3];
+    let mut max = [0.0f32; 3];
+    for t in texels.iter() {
+        for (c, value) in t[..3].iter().enumerate() {
+            min[c] = min[c].min(*value as f32);
+            max[c] = max[c].max(*value as f32);
+        }
+    }
+    let inset = |c: usize| (max[c] - min[c]) / 16.0;
+    let mut c0 = to_565([max[0] - inset(0), max[1] - inset(1), max[2] - inset(2)]);
+    let mut c1 = to_565([min[0] + inset(0), min[1] + inset(1), min[2] + inset(2)]);
+    // c0 > c1 selects the four color mode
+    if c0 < c1 {
+        std::mem::swap(&mut c0, &mut c1);
+    }
+
+    let mut indices = 0u32;
+    if c0 != c1 {
+        let (e0, e1) = (from_565(c0), from_565(c1));
+        let mix = |a: f32, b: f32| [
+            (e0[0] * a + e1[0] * b) / 3.0,
+            (e0[1] * a + e1[1] * b) / 3.0,
+            (e0[2] * a + e1[2] * b) / 3.0,
+        ];
+        let palette = [e0, e1, mix(2.0, 1.0), mix(1.0, 2.0)];
+        for (i, t) in texels.iter().enumerate() {
+            let distance = |p: &[f32; 3]| (0..3).map(|c| (p[c] - t[c] as f32).powi(2)).sum::<f32>();
+            let closest = (0..4)
+                .min_by(|a, b| distance(&palette[*a]).partial_cmp(&distance(&palette[*b])).unwrap())
+                .unwrap();
+            indices |= (closest as u32) << (i * 2);
+        }
+    }
+
+    out.extend_from_slice(&c0.to_le_bytes());
+    out.extend_from_slice(&c1.to_le_bytes());
+    out.extend_from_slice(&indices.to_le_bytes());
+}
+
+/// A BC3 alpha block: the block's extremes, with six steps between them
+fn encode_alpha_block(texels: &[[u8; 4]; 16], out: &mut Vec<u8>) {
+    let a0 = texels.iter().map(|t| t[3]).max().unwrap();
+    let a1 = texels.iter().map(|t| t[3]).min().unwrap();
+
+    let mut indices = 0u64;
+    if a0 != a1 {
+        let mut palette = [a0 as f32, a1 as f32, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        for (k, p) in palette.iter_mut().enumerate().skip(2) {
+            *p = ((8 - k) as f32 * a0 as f32 + (k - 1) as f32 * a1 as f32) / 7.0;
+        }
+        for (i, t) in texels.iter().enumerate() {
+            let closest = (0..8)
+                .min_by(|a, b| (palette[*a] - t[3] as f32).abs()
+                    .partial_cmp(&(palette[*b] - t[3] as f32).abs()).unwrap())
+                .unwrap();
+            indices |= (closest as u64) << (i * 3);
+        }
+    }
+
+    out.push(a0);
+    out.push(a1);
+    out.extend_from_slice(&indices.to_le_bytes()[..6]);
+}
+
+/// Compresses an image block by block. Blocks past the edges repeat the last row and column.
+fn compress(image: &image::RgbaImage, format: BlockFormat) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let mut out = Vec::with_capacity(compressed_size([width, height], format));
+    let mut texels = [[0u8; 4]; 16];
+    for block_y in 0..(height + 3) / 4 {
+        for block_x in 0..(width + 3) / 4 {
+            for (i, texel) in texels.iter_mut().enumerate() {
+                let x = (block_x * 4 + i as u32 % 4).min(width - 1);
+                let y = (block_y * 4 + i as u32 / 4).min(height - 1);
+                *texel = image.get_pixel(x, y).0;
+            }
+            if format == BlockFormat::Bc3 {
+                encode_alpha_block(&texels, &mut out);
+            }
+            encode_color_block(&texels, &mut out);
+        }
+    }
+    out
+}
+
+fn build(source: &[u8], srgb: bool) -> anyhow::Result<CachedTexture> {
+    use anyhow::Context;
+    let image = image::load_from_memory(source).context("Failed to decode texture")?.to_rgba();
+    let (width, height) = image.dimensions();
+    let format = if image.pixels().any(|p| p[3] < 255) { BlockFormat::Bc3 } else { BlockFormat::Bc1 };
+
+    let mut levels = vec![compress(&image, format)];
+    let mut level = image;
+    for _ in 1..mip_sizes(width, height).len() {
+        level = downsample(&level, srgb);
+        levels.push(compress(&level, format));
+    }
+    Ok(CachedTexture {
+        format,
+        width,
+        height,
+        levels,
+    })
+}
+
+/// The compressed texture of an encoded image, from the cache or built and cached now
+fn cached(source: &[u8], srgb: bool, cache_dir: Option<&std::path::Path>) -> anyhow::Result<CachedTexture> {
+    let hash = utils::content_hash(source);
+    let path = utils::cache_location(consts::TEXTURE_CACHE_PATH, cache_dir).join(format!(
+        "{:016x}{}{}", hash, if srgb { "_srgb" } else { "" }, consts::TEXTURE_CACHE_EXTENSION
+    ));
+    let header = utils::CacheHeader::new(consts::TEXTURE_CACHE_VERSION, hash);
+    match utils::read_cache::<CachedTexture, _>(&path, consts::TEXTURE_CACHE_MAGIC, &header)
+        .and_then(|c| c.validate().map(|_| c)) {
+        Ok(cached) => Ok(cached),
+        Err(_) => {
+            let cached = build(source, srgb)?;
+            if let Err(e) = utils::write_cache(&path, consts::TEXTURE_CACHE_MAGIC, &header, &cached) {
+                log::warning(&format!("Failed to cache a texture: {:#}", e));
+            }
+            Ok(cached)
+        },
+    }
+}
+
+/// Uploads a cached texture along with its mip levels, or the source image uncompressed if
+/// the driver can't take the compressed data. The closures make and fill the texture type.
+fn upload<T, C, M, U>(source: &[u8], cached: &CachedTexture, create: C, write_mip: M, uncompressed: U)
+-> anyhow::Result<T>
+where
+    C: FnOnce(CompressedMipmapsOption) -> anyhow::Result<T>,
+    M: Fn(&T, u32, [u32; 2], &[u8]) -> anyhow::Result<()>,
+    U: FnOnce(RawImage2d<u8>) -> anyhow::Result<T>,
+{
+    use anyhow::Context;
+    let uploaded = create(CompressedMipmapsOption::EmptyMipmapsMax(cached.levels.len() as u32 - 1))
+        .and_then(|texture| {
+            for (level, (size, data)) in mip_sizes(cached.width, cached.height).into_iter()
+                .zip(cached.levels.iter()).enumerate().skip(1) {
+                write_mip(&texture, level as u32, size, data)?;
+            }
+            Ok(texture)
+        });
+
+    match uploaded {
+        Ok(texture) => Ok(texture),
+        Err(e) => {
+            // Drivers without S3TC get the image as is, and compress it themselves
+            log::warning(&format!("{:#}, uploading it uncompressed", e));
+            let image = image::load_from_memory(source).context("Failed to decode texture")?.to_rgba();
+            let dims = image.dimensions();
+            uncompressed(RawImage2d::from_raw_rgba(image.into_raw(), dims))
+        },
+    }
+}
+
+/// Loads an encoded image as a color texture, sampled in sRGB
+pub fn load_srgb(display: &dyn Facade, source: &[u8], cache_dir: Option<&std::path::Path>)
+-> anyhow::Result<CompressedSrgbTexture2d> {
+    use anyhow::Context;
+    let cached = cached(source, true, cache_dir)?;
+    let format = match cached.format {
+        BlockFormat::Bc1 => CompressedSrgbFormat::S3tcDxt1NoAlpha,
+        BlockFormat::Bc3 => CompressedSrgbFormat::S3tcDxt5Alpha,
+    };
+    upload(source, &cached,
+        |mipmaps| CompressedSrgbTexture2d::with_compressed_data(
+            display, &cached.levels[0], cached.width, cached.height, format, mipmaps,
+        ).context("Failed to create texture"),
+        |texture, level, [width, height], data| texture.mipmap(level).context("Missing mip level")?
+            .write_compressed_data(glium::Rect { left: 0, bottom: 0, width, height }, data, width, height, format)
+            .map_err(|_| anyhow!("Failed to upload mip level {}", level)),
+        |image| CompressedSrgbTexture2d::new(display, image).context("Failed to create texture"),
+    )
+}
+
+/// Loads an encoded image as a data texture (normals, roughness, occlusion), sampled as is
+pub fn load_linear(display: &dyn Facade, source: &[u8], cache_dir: Option<&std::path::Path>)
+-> anyhow::Result<CompressedTexture2d> {
+    use anyhow::Context;
+    let cached = cached(source, false, cache_dir)?;
+    let format = match cached.format {
+        BlockFormat::Bc1 => CompressedFormat::S3tcDxt1NoAlpha,
+        BlockFormat::Bc3 => CompressedFormat::S3tcDxt5Alpha,
+    };
+    upload(source, &cached,
+        |mipmaps| CompressedTexture2d::with_compressed_data(
+            display, &cached.levels[0], cached.width, cached.height, format, mipmaps,
+        ).context("Failed to create texture"),
+        |texture, level, [width, height], data| texture.mipmap(level).context("Missing mip level")?
+            .write_compressed_data(glium::Rect { left: 0, bottom: 0, width, height }, data, width, height, format)
+            .map_err(|_| anyhow!("Failed to upload mip level {}", level)),
+        |image| CompressedTexture2d::new(display, image).context("Failed to create texture"),
+    )
+}
+
+#[test]
+fn solid_blocks_compress_exactly() {
+    let mut out = Vec::new();
+    encode_color_block(&[[255, 0, 0, 255]; 16], &mut out);
+    assert_eq!(out, vec![0x00, 0xf8, 0x00, 0xf8, 0, 0, 0, 0]);
+
+    out.clear();
+    encode_alpha_block(&[[0, 0, 0, 128]; 16], &mut out);
+    assert_eq!(out, vec![128, 128, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn texels_pick_the_closest_endpoint() {
+    // Left half black, right half white
+    let mut texels = [[0u8, 0, 0, 255]; 16];
+    for (i, t) in texels.iter_mut().enumerate() {
+        if i % 4 >= 2 {
+            *t = [255, 255, 255, 255];
+        }
+    }
+    let mut out = Vec::new();
+    encode_color_block(&texels, &mut out);
+    let indices = u32::from_le_bytes([out[4], out[5], out[6], out[7]]);
+    for i in 0..16 {
+        let index = (indices >> (i * 2)) & 3;
+        // Index 0 is the brighter endpoint, the inset keeps both ends of the range closest
+        assert_eq!(index, if i % 4 >= 2 { 0 } else { 1 });
+    }
+}
+
+#[test]
+fn mip_chains_end_at_one_texel() {
+    assert_eq!(mip_sizes(5, 3), vec![[5, 3], [2, 1], [1, 1]]);
+    assert_eq!(mip_sizes(1, 1), vec![[1, 1]]);
+    assert_eq!(mip_sizes(256, 256).len(), 9);
+    assert_eq!(compressed_size([5, 3], BlockFormat::Bc1), 2 * 8);
+    assert_eq!(compressed_size([1, 1], BlockFormat::Bc3), 16);
+}
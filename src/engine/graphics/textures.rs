@@ -1,6 +1,17 @@
 use crate::engine::prelude::*;
 use std::collections::HashMap;
-use glium::{Display, texture::{CompressedTexture2d, CompressedSrgbTexture2d, RawImage2d, SrgbCubemap}};
+use glium::{backend::Facade, texture::{CompressedTexture2d, CompressedSrgbTexture2d, RawImage2d, SrgbCubemap,
+    CubeLayer}};
+
+/// Which image suffix goes to each layer of a cubemap
+pub const CUBEMAP_FACES: [(CubeLayer, &str); 6] = [
+    (CubeLayer::NegativeX, "_right"),
+    (CubeLayer::NegativeY, "_up"),
+    (CubeLayer::NegativeZ, "_back"),
+    (CubeLayer::PositiveX, "_left"),
+    (CubeLayer::PositiveY, "_down"),
+    (CubeLayer::PositiveZ, "_front"),
+];
 
 pub enum Texture {
     Albedo(CompressedSrgbTexture2d),
@@ -11,69 +22,67 @@ pub enum Texture {
 }
 
 impl Texture {
+    // The defaults are a whole 4x4 block of the compressed formats, since some drivers
+    // (Mesa's) leave compressed textures smaller than a block empty
+
     /// This is the "invalid texture" texture - a pink-yellow grid
-    pub fn new_default_albedo(display: &Display) -> CompressedSrgbTexture2d {
-        let mut imgbuf = image::ImageBuffer::new(2, 2);
-        imgbuf.put_pixel(0, 0, image::Rgb([255, 0, 255]));
-        imgbuf.put_pixel(1, 1, image::Rgb([255, 0, 255]));
-        imgbuf.put_pixel(1, 0, image::Rgb([255, 255, 0]));
-        imgbuf.put_pixel(0, 1, image::Rgb([255, 255, 0]));
-
-        let tex = RawImage2d::from_raw_rgb(imgbuf.into_raw(), (2, 2));
+    pub fn new_default_albedo(display: &dyn Facade) -> CompressedSrgbTexture2d {
+        let imgbuf = image::ImageBuffer::from_fn(4, 4, |x, y| if (x / 2 + y / 2) % 2 == 0 {
+            image::Rgb([255u8, 0, 255])
+        } else {
+            image::Rgb([255, 255, 0])
+        });
+
+        let tex = RawImage2d::from_raw_rgb(imgbuf.into_raw(), (4, 4));
         CompressedSrgbTexture2d::new(display, tex).expect(
             "Failed to create default albedo texture"
         )
     }
 
     /// This is the default roughness/metallic map (rough and non metallic)
-    pub fn new_default_rough_metal(display: &Display) -> CompressedTexture2d {
-        let mut imgbuf = image::ImageBuffer::new(1, 1);
-        imgbuf.put_pixel(0, 0, image::Rgb([0, 255, 0]));
+    pub fn new_default_rough_metal(display: &dyn Facade) -> CompressedTexture2d {
+        let imgbuf = image::ImageBuffer::from_pixel(4, 4, image::Rgb([0u8, 255, 0]));
 
-        let tex = RawImage2d::from_raw_rgb(imgbuf.into_raw(), (1, 1));
+        let tex = RawImage2d::from_raw_rgb(imgbuf.into_raw(), (4, 4));
         CompressedTexture2d::new(display, tex).expect(
             "Failed to create default roughness/metal texture"
         )
     }
 
     /// This is the default normal map (all +Z, toward surface normal)
-    pub fn new_default_normal(display: &Display) -> CompressedTexture2d {
-        let mut imgbuf = image::ImageBuffer::new(1, 1);
-        imgbuf.put_pixel(0, 0, image::Rgb([128, 128, 255]));
+    pub fn new_default_normal(display: &dyn Facade) -> CompressedTexture2d {
+        let imgbuf = image::ImageBuffer::from_pixel(4, 4, image::Rgb([128u8, 128, 255]));
 
-        let tex = RawImage2d::from_raw_rgb(imgbuf.into_raw(), (1, 1));
+        let tex = RawImage2d::from_raw_rgb(imgbuf.into_raw(), (4, 4));
         CompressedTexture2d::new(display, tex).expect(
             "Failed to create default normal map"
         )
     }
 
     /// This is the default occlusion map (all ones - fully lit)
-    pub fn new_default_occ(display: &Display) -> CompressedTexture2d {
-        let mut imgbuf = image::ImageBuffer::new(1, 1);
-        imgbuf.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+    pub fn new_default_occ(display: &dyn Facade) -> CompressedTexture2d {
+        let imgbuf = image::ImageBuffer::from_pixel(4, 4, image::Rgb([255u8, 255, 255]));
 
-        let tex = RawImage2d::from_raw_rgb(imgbuf.into_raw(), (1, 1));
+        let tex = RawImage2d::from_raw_rgb(imgbuf.into_raw(), (4, 4));
         CompressedTexture2d::new(display, tex).expect(
             "Failed to create default AO map"
         )
     }
 
 
-    pub fn from<P: AsRef<std::path::Path>>(filename: P, display: &Display)
+    pub fn from<P: AsRef<std::path::Path>>(filename: P, display: &dyn Facade, cache_dir: Option<&std::path::Path>)
     -> anyhow::Result<Texture> {
         use anyhow::Context;
-        let tex = utils::load_image(filename)?.to_rgba();
-        let dims = tex.dimensions();
-        let tex = RawImage2d::from_raw_rgba(tex.into_raw(), dims);
+        let source = std::fs::read(filename.as_ref())
+            .context(format!("Failed opening image {}", filename.as_ref().to_string_lossy()))?;
         Ok(Texture::Albedo(
-            CompressedSrgbTexture2d::new(display, tex)
-            .context("Failed to load texture")?
+            super::texture_cache::load_srgb(display, &source, cache_dir)
+            .context(format!("Failed to load texture {}", filename.as_ref().to_string_lossy()))?
         ))
     }
 
-    pub fn cubemap<P: AsRef<std::path::Path>>(filename: P, display: &Display)
+    pub fn cubemap<P: AsRef<std::path::Path>>(filename: P, display: &dyn Facade)
     -> anyhow::Result<Texture> {
-        use glium::texture::CubeLayer;
         use glium::Surface;
         use image::GenericImageView;
         use anyhow::Context;
@@ -88,15 +97,7 @@ impl Texture {
         let tex = SrgbCubemap::empty(display, size)
         .context("Failed to create cubemap")?;
 
-        let layers = [
-            (CubeLayer::NegativeX, "_right"),
-            (CubeLayer::NegativeY, "_up"),
-            (CubeLayer::NegativeZ, "_back"),
-            (CubeLayer::PositiveX, "_left"),
-            (CubeLayer::PositiveY, "_down"),
-            (CubeLayer::PositiveZ, "_front")
-        ];
-        for (layer, suffix) in layers.iter() {
+        for (layer, suffix) in CUBEMAP_FACES.iter() {
             let framebuffer = glium::framebuffer::SimpleFrameBuffer::new(
                 display,
                 tex.main_level().image(*layer)
@@ -140,19 +141,26 @@ pub struct TexturesManager {
     default_rough_metal: CompressedTexture2d,
     default_normal: CompressedTexture2d,
     default_occ: CompressedTexture2d,
+    cache_dir: Option<std::path::PathBuf>,
 }
 
 impl TexturesManager {
-    pub fn new(display: &Display) -> TexturesManager {
+    pub fn new(display: &dyn Facade) -> TexturesManager {
         TexturesManager {
             textures: HashMap::new(),
             default_albedo: Texture::new_default_albedo(display),
             default_rough_metal: Texture::new_default_rough_metal(display),
             default_normal: Texture::new_default_normal(display),
-            default_occ: Texture::new_default_occ(display)
+            default_occ: Texture::new_default_occ(display),
+            cache_dir: None,
         }
     }
 
+    /// Keeps texture caches in their own folder instead of `consts::TEXTURE_CACHE_PATH`
+    pub fn set_cache_dir(&mut self, cache_dir: Option<std::path::PathBuf>) {
+        self.cache_dir = cache_dir;
+    }
+
     pub fn get(&self, name: &str) -> Option<&Texture> {
         self.textures.get(name)
     }
@@ -170,17 +178,17 @@ impl TexturesManager {
         &self.default_occ
     }
 
-    pub fn try_load(&mut self, display: &Display, name: &str) -> anyhow::Result<()> {
+    pub fn try_load(&mut self, display: &dyn Facade, name: &str) -> anyhow::Result<()> {
         match self.textures.get(name) {
             Some(_) => Ok(()),
-            None => match Texture::from(name, display) {
+            None => match Texture::from(name, display, self.cache_dir.as_deref()) {
                 Ok(t) => {self.textures.insert(String::from(name), t); Ok(())},
                 Err(e) => Err(e)
             }
         }
     }
 
-    pub fn try_load_cubemap(&mut self, display: &Display, name: &str) -> anyhow::Result<()> {
+    pub fn try_load_cubemap(&mut self, display: &dyn Facade, name: &str) -> anyhow::Result<()> {
         match self.textures.get(name) {
             Some(Texture::Cubemap(_)) => Ok(()),
             Some(_) => Err(anyhow!("{} is already loaded as a non-cubemap", name)),
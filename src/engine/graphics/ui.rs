@@ -0,0 +1,131 @@
+use crate::engine::prelude::*;
+use crate::engine::ui::UiLayer;
+use glium::backend::Facade;
+use glium::texture::{CompressedSrgbTexture2d, RawImage2d};
+use glium::{Surface, VertexBuffer, uniform};
+use std::collections::HashSet;
+use std::ops::Range;
+use super::shaders::{self, ShaderProgram};
+use super::vertex::VertexUi;
+use super::{Texture, TexturesManager};
+
+/// Consecutive quads that use the same texture
+struct Batch {
+    texture: Option<String>,
+    range: Range<usize>,
+}
+
+/// Draws the UI's quads. Its text goes through the `TextRenderer`.
+pub struct UiRenderer {
+    program: ShaderProgram,
+
+    // Flat quads sample this, so they share the shader with textured ones
+    white: CompressedSrgbTexture2d,
+    layers: Vec<Option<(VertexBuffer<VertexUi>, Vec<Batch>)>>,
+
+    // Logged once, rather than every frame
+    failed_textures: HashSet<String>,
+}
+
+impl UiRenderer {
+    pub fn new(display: &dyn Facade) -> anyhow::Result<UiRenderer> {
+        use anyhow::Context;
+        Ok(UiRenderer {
+            program: shaders::ui(display)?,
+            white: CompressedSrgbTexture2d::new(display, RawImage2d::from_raw_rgba(vec![255u8; 4], (1, 1)))
+                .context("Failed to create the UI's white texture")?,
+            layers: Vec::new(),
+            failed_textures: HashSet::new(),
+        })
+    }
+
+    /// Builds this frame's quads, loading the textures they use. The quads are in logical
+    /// pixels, which are `ui_scale` physical pixels.
+    pub fn prepare(&mut self, display: &dyn Facade, layers: &[UiLayer], ui_scale: f32,
+        textures: &mut TexturesManager) {
+        self.layers.clear();
+        for layer in layers {
+            let mut vertices = Vec::with_capacity(layer.quads.len() * 6);
+            let mut batches: Vec<Batch> = Vec::new();
+            for quad in layer.quads.iter() {
+                if let Some(texture) = &quad.texture {
+                    if !self.failed_textures.contains(texture) {
+                        if let Err(e) = textures.try_load(display, texture) {
+                            log::err(&e);
+                            self.failed_textures.insert(texture.clone());
+                        }
+                    }
+                }
+
+                let start = vertices.len();
+                let r = quad.rect.scaled(ui_scale);
+                let vertex = |x: f32, y: f32, u: f32, v: f32| VertexUi {
+                    position: [x, y],
+                    texcoord: [u, v],
+                    color: quad.color,
+                };
+                vertices.extend_from_slice(&[
+                    vertex(r.x, r.y, 0.0, 0.0),
+                    vertex(r.x + r.w, r.y, 1.0, 0.0),
+                    vertex(r.x + r.w, r.y + r.h, 1.0, 1.0),
+                    vertex(r.x, r.y, 0.0, 0.0),
+                    vertex(r.x + r.w, r.y + r.h, 1.0, 1.0),
+                    vertex(r.x, r.y + r.h, 0.0, 1.0),
+                ]);
+
+                match batches.last_mut() {
+                    Some(batch) if batch.texture == quad.texture => batch.range.end = vertices.len(),
+                    _ => batches.push(Batch {
+                        texture: quad.texture.clone(),
+                        range: start..vertices.len(),
+                    }),
+                }
+            }
+
+            self.layers.push(if vertices.is_empty() {
+                None
+            } else {
+                VertexBuffer::new(display, &vertices).ok().map(|buffer| (buffer, batches))
+            });
+        }
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Draws a prepared layer over whatever is in `target`
+    pub fn draw<S: Surface>(&self, target: &mut S, layer: usize, resolution: [u32; 2], textures: &TexturesManager) {
+        let (vertices, batches) = match self.layers.get(layer) {
+            Some(Some(layer)) => layer,
+            _ => return,
+        };
+        let params = glium::DrawParameters {
+            blend: glium::Blend::alpha_blending(),
+            ..Default::default()
+        };
+
+        for batch in batches {
+            let texture = match batch.texture.as_ref().and_then(|t| textures.get(t)) {
+                Some(Texture::Albedo(texture)) => texture,
+                _ => &self.white,
+            };
+            target.draw(
+                vertices.slice(batch.range.clone()).unwrap(),
+                glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+                &self.program,
+                &uniform! {
+                    tex: texture.sampled()
+                        .magnify_filter(glium::uniforms::MagnifySamplerFilter::Linear)
+                        .minify_filter(glium::uniforms::MinifySamplerFilter::Linear),
+                    resolution: [resolution[0] as f32, resolution[1] as f32],
+                },
+                &params
+            ).unwrap();
+        }
+    }
+
+    pub fn hot_reload(&mut self, display: &dyn Facade) {
+        self.program.reload_if_changed(display);
+    }
+}
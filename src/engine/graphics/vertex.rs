@@ -20,4 +20,20 @@ glium::implement_vertex!(Vertex2d, position, texcoord);
 pub struct VertexSkybox {
     pub position: [f32; 3],
 }
-glium::implement_vertex!(VertexSkybox, position);
\ No newline at end of file
+glium::implement_vertex!(VertexSkybox, position);
+
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct VertexText {
+    pub position: [f32; 2],
+    pub texcoord: [f32; 2],
+    pub color: [f32; 4],
+}
+glium::implement_vertex!(VertexText, position, texcoord, color);
+
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct VertexUi {
+    pub position: [f32; 2],
+    pub texcoord: [f32; 2],
+    pub color: [f32; 4],
+}
+glium::implement_vertex!(VertexUi, position, texcoord, color);
\ No newline at end of file
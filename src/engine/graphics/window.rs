@@ -1,11 +1,163 @@
 use crate::engine::prelude::*;
-use glium::glutin::{ContextBuilder, window::{WindowBuilder, Icon}};
+use glium::glutin::{ContextBuilder, window::{WindowBuilder, Icon, Window, Fullscreen}};
 use winit::{
-    dpi::LogicalSize,
+    dpi::{PhysicalSize, PhysicalPosition},
     event_loop::EventLoop,
+    monitor::{MonitorHandle, VideoMode},
 };
 
-pub fn make_window(eventloop: &EventLoop<()>) -> glium::Display {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowMode {
+    Windowed,
+
+    /// A window without decorations covering the whole monitor, at its current video mode
+    Borderless,
+
+    /// Exclusive fullscreen, switching the monitor to the video mode closest to the resolution
+    Fullscreen,
+}
+
+impl std::str::FromStr for WindowMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "windowed" => WindowMode::Windowed,
+            "borderless" => WindowMode::Borderless,
+            "fullscreen" => WindowMode::Fullscreen,
+            _ => return Err(anyhow!("Unknown window mode {}", s)),
+        })
+    }
+}
+
+impl std::fmt::Display for WindowMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            WindowMode::Windowed => "windowed",
+            WindowMode::Borderless => "borderless",
+            WindowMode::Fullscreen => "fullscreen",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindowSettings {
+    pub mode: WindowMode,
+
+    /// The window size in physical pixels, or the video mode in exclusive fullscreen.
+    /// Borderless windows always cover their monitor.
+    pub resolution: [u32; 2],
+
+    /// Index into the available monitors
+    pub monitor: usize,
+
+    /// Whether windowed mode can be resized by the user
+    pub resizable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoModeInfo {
+    pub size: [u32; 2],
+    pub bit_depth: u16,
+    pub refresh_rate: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorInfo {
+    pub name: String,
+    pub size: [u32; 2],
+    pub position: [i32; 2],
+    pub video_modes: Vec<VideoModeInfo>,
+}
+
+fn video_mode_info(mode: &VideoMode) -> VideoModeInfo {
+    VideoModeInfo {
+        size: [mode.size().width, mode.size().height],
+        bit_depth: mode.bit_depth(),
+        refresh_rate: mode.refresh_rate(),
+    }
+}
+
+fn monitor_info(monitor: &MonitorHandle) -> MonitorInfo {
+    let mut video_modes: Vec<VideoModeInfo> = monitor.video_modes().map(|m| video_mode_info(&m)).collect();
+    video_modes.sort_by_key(|m| (std::cmp::Reverse(m.size[0] * m.size[1]), std::cmp::Reverse(m.refresh_rate)));
+    video_modes.dedup();
+    MonitorInfo {
+        name: monitor.name().unwrap_or_else(|| String::from("Unknown monitor")),
+        size: [monitor.size().width, monitor.size().height],
+        position: [monitor.position().x, monitor.position().y],
+        video_modes,
+    }
+}
+
+/// Every monitor connected, in the order `WindowSettings::monitor` indexes them
+pub fn monitors(window: &Window) -> Vec<MonitorInfo> {
+    window.available_monitors().map(|m| monitor_info(&m)).collect()
+}
+
+/// The video mode closest in size to `resolution`, at the highest refresh rate and bit depth
+pub fn best_video_mode(modes: &[VideoModeInfo], resolution: [u32; 2]) -> Option<usize> {
+    modes.iter().enumerate().min_by_key(|(_, m)| (
+        (m.size[0] as i64 - resolution[0] as i64).abs() + (m.size[1] as i64 - resolution[1] as i64).abs(),
+        std::cmp::Reverse(m.refresh_rate),
+        std::cmp::Reverse(m.bit_depth),
+    )).map(|(i, _)| i)
+}
+
+/// Switches the window to `settings`, returning the size it will end up with
+pub fn apply_settings(window: &Window, settings: &WindowSettings) -> [u32; 2] {
+    window.set_resizable(settings.resizable);
+    let monitor = match window.available_monitors().nth(settings.monitor) {
+        Some(monitor) => Some(monitor),
+        None => {
+            log::warning(&format!("There's no monitor {}, using the current one instead", settings.monitor));
+            window.current_monitor()
+        },
+    };
+
+    match (settings.mode, monitor) {
+        (WindowMode::Borderless, Some(monitor)) => {
+            let size = monitor.size();
+            window.set_fullscreen(Some(Fullscreen::Borderless(Some(monitor))));
+            [size.width, size.height]
+        },
+        (WindowMode::Fullscreen, Some(monitor)) => {
+            let modes: Vec<VideoMode> = monitor.video_modes().collect();
+            let infos: Vec<VideoModeInfo> = modes.iter().map(video_mode_info).collect();
+            match best_video_mode(&infos, settings.resolution) {
+                Some(i) => {
+                    window.set_fullscreen(Some(Fullscreen::Exclusive(modes[i].clone())));
+                    infos[i].size
+                },
+                None => {
+                    log::warning("The monitor reports no video modes, going borderless instead");
+                    let size = monitor.size();
+                    window.set_fullscreen(Some(Fullscreen::Borderless(Some(monitor))));
+                    [size.width, size.height]
+                },
+            }
+        },
+        (_, monitor) => {
+            window.set_fullscreen(None);
+            window.set_decorations(true);
+            window.set_inner_size(PhysicalSize::new(settings.resolution[0], settings.resolution[1]));
+
+            // Centered on the chosen monitor
+            if let Some(monitor) = monitor {
+                let (position, size) = (monitor.position(), monitor.size());
+                window.set_outer_position(PhysicalPosition::new(
+                    position.x + (size.width as i32 - settings.resolution[0] as i32).max(0) / 2,
+                    position.y + (size.height as i32 - settings.resolution[1] as i32).max(0) / 2,
+                ));
+            }
+            settings.resolution
+        },
+    }
+}
+
+/// Creates a window for `settings.resolution`. `apply_settings` switches it to the
+/// rest of the settings.
+pub fn make_window(eventloop: &EventLoop<()>, settings: &WindowSettings) -> anyhow::Result<glium::Display> {
     // Load the icon
     use image::GenericImageView;
     let icon = match utils::load_image(consts::ICON_PATH) {
@@ -18,17 +170,35 @@ pub fn make_window(eventloop: &EventLoop<()>) -> glium::Display {
             }
         },
         Err(_) => None
-    };    
-    
+    };
+
     glium::Display::new(
         WindowBuilder::new()
         .with_title(consts::WINDOW_NAME)
-        .with_inner_size(LogicalSize::new(
-            consts::DEFAULT_RESOLUTION[0], 
-            consts::DEFAULT_RESOLUTION[1]))
+        .with_inner_size(PhysicalSize::new(
+            settings.resolution[0],
+            settings.resolution[1]))
         .with_window_icon(icon)
-        .with_resizable(false),
+        .with_resizable(settings.resizable),
         ContextBuilder::new().with_depth_buffer(24),
         &eventloop
-    ).expect("Failed to create window and OpenGL display")
+    ).map_err(|e| anyhow!("Failed to create the window and OpenGL display: {}", e))
+}
+
+#[test]
+fn best_video_mode_prefers_exact_size_then_refresh_rate() {
+    let mode = |w, h, refresh_rate| VideoModeInfo { size: [w, h], bit_depth: 32, refresh_rate };
+    let modes = vec![mode(1920, 1080, 60), mode(1920, 1080, 144), mode(1280, 720, 240), mode(800, 600, 60)];
+    assert_eq!(best_video_mode(&modes, [1920, 1080]), Some(1));
+    assert_eq!(best_video_mode(&modes, [1280, 720]), Some(2));
+    assert_eq!(best_video_mode(&modes, [832, 624]), Some(3));
+    assert_eq!(best_video_mode(&[], [1920, 1080]), None);
+}
+
+#[test]
+fn window_mode_round_trips_through_config_strings() {
+    for mode in vec![WindowMode::Windowed, WindowMode::Borderless, WindowMode::Fullscreen] {
+        assert_eq!(mode.to_string().parse::<WindowMode>().unwrap(), mode);
+    }
+    assert!("maximized".parse::<WindowMode>().is_err());
 }
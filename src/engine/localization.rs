@@ -1,23 +1,123 @@
 use crate::engine::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+use lazy_static::lazy_static;
 
+/// A single localized string. Plural strings map a plural category ("one", "few",
+/// "other", ...) to the text for it, e.g. {"one": "{count} star", "other": "{count} stars"}.
 #[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Text(String),
+    Plural(HashMap<String, String>),
+}
+
 pub struct Localization {
-    dict: std::collections::HashMap<String, String>,
+    // The requested language first, followed by the languages it falls back to
+    chain: Vec<(String, HashMap<String, Entry>)>,
+    reported_missing: Mutex<HashSet<String>>,
+
+    // Plural keys formatted without a count, reported once each like missing keys
+    reported_uncounted: Mutex<HashSet<String>>,
 }
 
 impl Localization {
-    pub fn from(language: &str) -> anyhow::Result<Localization> {
+    fn load_language(language: &str) -> anyhow::Result<HashMap<String, Entry>> {
         use anyhow::Context;
-        Ok(serde_json::from_str::<'_, Localization>(
+        serde_json::from_str(
             &std::fs::read_to_string(
                 std::path::Path::new(consts::LOCALIZATION_PATH)
                 .join(language).with_extension(consts::LOCALIZATION_EXTENSION)
             ).context(format!("Localization for {} not found", language))?
-        )?)
+        ).context(format!("Localization for {} is invalid", language))
+    }
+
+    /// The languages to try in order. A regional variant like "English-UK" falls back to
+    /// "English", and everything falls back to the default language.
+    pub fn fallback_chain(language: &str) -> Vec<String> {
+        let mut result = vec![String::from(language)];
+        let mut rest = language;
+        while let Some(idx) = rest.rfind(|c| c == '-' || c == '_') {
+            rest = &rest[..idx];
+            result.push(String::from(rest));
+        }
+        if !result.iter().any(|l| l == consts::DEFAULT_LANGUAGE) {
+            result.push(String::from(consts::DEFAULT_LANGUAGE));
+        }
+        result
+    }
+
+    pub fn from(language: &str) -> anyhow::Result<Localization> {
+        let mut chain = vec![(String::from(language), Localization::load_language(language)?)];
+        for fallback in Localization::fallback_chain(language).into_iter().skip(1) {
+            match Localization::load_language(&fallback) {
+                Ok(dict) => chain.push((fallback, dict)),
+                Err(e) => log::warning(&format!("{:#}", e)),
+            }
+        }
+
+        Ok(Localization {
+            chain,
+            reported_missing: Mutex::new(HashSet::new()),
+            reported_uncounted: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// A localization with no strings, so every key translates to itself.
+    fn empty(language: &str) -> Localization {
+        Localization {
+            chain: vec![(String::from(language), HashMap::new())],
+            reported_missing: Mutex::new(HashSet::new()),
+            reported_uncounted: Mutex::new(HashSet::new()),
+        }
     }
 
-    pub fn get<'a>(&'a self, key: &'a str) -> &'a str {
-        self.dict.get(key).map(|s| &s[..]).unwrap_or(key)
+    pub fn language(&self) -> &str {
+        &self.chain[0].0
+    }
+
+    fn lookup(&self, key: &str) -> Option<(&str, &Entry)> {
+        let result = self.chain.iter()
+            .find_map(|(lang, dict)| dict.get(key).map(|e| (&lang[..], e)));
+
+        if result.is_none() && self.reported_missing.lock().unwrap().insert(String::from(key)) {
+            log::warning(&format!("Missing localization for {} in {}", key, self.language()));
+        }
+        result
+    }
+
+    /// The text of a key as it is. Plural strings need a count, so they must go through `format`.
+    pub fn get<'a>(&'a self, key: &'a str) -> anyhow::Result<&'a str> {
+        match self.lookup(key) {
+            Some((_, Entry::Text(s))) => Ok(s),
+            Some((_, Entry::Plural(_))) => Err(anyhow!("{} is a plural string, format it with a count", key)),
+            None => Ok(key),
+        }
+    }
+
+    /// Looks up `key` and fills its `{name}` placeholders from `args`.
+    /// Plural strings pick their form by the `count` argument, which may be any number.
+    pub fn format(&self, key: &str, args: &HashMap<String, String>) -> String {
+        let template = match self.lookup(key) {
+            Some((_, Entry::Text(s))) => &s[..],
+            Some((lang, Entry::Plural(forms))) => {
+                let category = match args.get("count").map(|c| c.parse::<f64>()) {
+                    Some(Ok(count)) => plural_category_of(lang, count),
+                    _ => {
+                        if self.reported_uncounted.lock().unwrap().insert(String::from(key)) {
+                            log::warning(&format!("{} is a plural string, but has no numeric count", key));
+                        }
+                        "other"
+                    },
+                };
+                forms.get(category)
+                    .or_else(|| forms.get("other"))
+                    .map(|s| &s[..])
+                    .unwrap_or(key)
+            },
+            None => key,
+        };
+        fill_placeholders(template, args)
     }
 
     pub fn get_available_languages() -> Vec<String> {
@@ -28,3 +128,111 @@ impl Localization {
         .collect()
     }
 }
+
+/// Replaces every `{name}` with args[name]. Unknown placeholders are left as they are.
+pub fn fill_placeholders(template: &str, args: &HashMap<String, String>) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match rest.find('}') {
+            Some(end) => {
+                match args.get(&rest[1..end]) {
+                    Some(value) => result.push_str(value),
+                    None => result.push_str(&rest[..=end]),
+                }
+                rest = &rest[end + 1..];
+            },
+            None => break,
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// The CLDR plural category of `n` in the given language. Regional variants use the
+/// rules of their base language; unknown languages use the English rules.
+pub fn plural_category(language: &str, n: i64) -> &'static str {
+    let base = language.split(|c| c == '-' || c == '_').next().unwrap_or(language);
+    let n = n.abs();
+
+    match base {
+        "Japanese" | "Chinese" | "Korean" | "Turkish" => "other",
+        "French" | "Portuguese" => if n == 0 || n == 1 { "one" } else { "other" },
+        "Russian" | "Ukrainian" => {
+            if n % 10 == 1 && n % 100 != 11 {
+                "one"
+            } else if (2..=4).contains(&(n % 10)) && !(12..=14).contains(&(n % 100)) {
+                "few"
+            } else {
+                "many"
+            }
+        },
+        "Polish" => {
+            if n == 1 {
+                "one"
+            } else if (2..=4).contains(&(n % 10)) && !(12..=14).contains(&(n % 100)) {
+                "few"
+            } else {
+                "many"
+            }
+        },
+        "Hebrew" => match n {
+            1 => "one",
+            2 => "two",
+            _ => "other",
+        },
+        _ => if n == 1 { "one" } else { "other" },
+    }
+}
+
+/// Like `plural_category`, for counts that come from scripts as any number. Whole numbers
+/// like 1.0 count as integers, and fractions use the "other" form.
+pub fn plural_category_of(language: &str, n: f64) -> &'static str {
+    if n.fract() == 0.0 && n.abs() <= i64::MAX as f64 {
+        plural_category(language, n as i64)
+    } else {
+        "other"
+    }
+}
+
+lazy_static! {
+    static ref CURRENT: RwLock<Option<Localization>> = RwLock::new(None);
+}
+
+/// Makes `localization` the one used by `tr`.
+pub fn set_current(localization: Localization) {
+    *CURRENT.write().unwrap() = Some(localization);
+}
+
+/// Loads a language and makes it the current one. The current language is kept on failure.
+pub fn set_language(language: &str) -> anyhow::Result<()> {
+    set_current(Localization::from(language)?);
+    Ok(())
+}
+
+pub fn current_language() -> String {
+    match CURRENT.read().unwrap().as_ref() {
+        Some(l) => String::from(l.language()),
+        None => String::from(consts::DEFAULT_LANGUAGE),
+    }
+}
+
+/// Translates a key with the current localization, loading the default language
+/// if none was set yet.
+pub fn tr(key: &str, args: &HashMap<String, String>) -> String {
+    if CURRENT.read().unwrap().is_none() {
+        set_current(Localization::from(consts::DEFAULT_LANGUAGE).unwrap_or_else(|e| {
+            log::err(&e);
+            Localization::empty(consts::DEFAULT_LANGUAGE)
+        }));
+    }
+
+    match CURRENT.read().unwrap().as_ref() {
+        Some(l) => l.format(key, args),
+        None => fill_placeholders(key, args),
+    }
+}
@@ -0,0 +1,287 @@
+//! Checks that every `tr("...")` key used by the scripts exists in every language,
+//! that translations use the same placeholders as the reference language, and that
+//! plural strings have the forms their language needs.
+//! Run with `space_war_supreme --check-localization`. The report is also written to
+//! LOCALIZATION_REPORT_PATH, since release builds on Windows have no console.
+use crate::engine::prelude::*;
+use crate::engine::localization;
+use std::collections::{BTreeMap, BTreeSet};
+
+type Placeholders = BTreeSet<String>;
+
+/// What the checker knows about a key in one language
+struct KeyInfo {
+    placeholders: Placeholders,
+
+    /// The plural categories of a plural string
+    plural_forms: Option<BTreeSet<String>>,
+}
+
+/// Finds the keys of every `tr("key"...)` call with a literal key.
+pub fn find_tr_keys(source: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut rest = source;
+
+    while let Some(idx) = rest.find("tr(") {
+        let preceded_by_ident = rest[..idx].chars().next_back()
+            .map_or(false, |c| c.is_alphanumeric() || c == '_');
+        rest = &rest[idx + "tr(".len()..];
+        if preceded_by_ident {
+            continue;
+        }
+
+        let trimmed = rest.trim_start();
+        if !trimmed.starts_with('"') {
+            continue;
+        }
+        if let Some(key) = read_string_literal(&trimmed[1..]) {
+            result.push(key);
+        }
+    }
+
+    result
+}
+
+/// The contents of a string literal, from just after its opening quote. Only `\"` and
+/// `\\` are unescaped, so literals with other escapes are skipped like non-literal keys.
+fn read_string_literal(source: &str) -> Option<String> {
+    let mut result = String::new();
+    let mut chars = source.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(result),
+            '\\' => match chars.next() {
+                Some(escaped) if escaped == '"' || escaped == '\\' => result.push(escaped),
+                _ => return None,
+            },
+            _ => result.push(c),
+        }
+    }
+    None
+}
+
+fn find_placeholders(text: &str) -> Placeholders {
+    let mut result = Placeholders::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        match rest.find('}') {
+            Some(end) => {
+                result.insert(String::from(&rest[..end]));
+                rest = &rest[end + 1..];
+            },
+            None => break,
+        }
+    }
+    result
+}
+
+/// Key -> placeholders and plural forms for one language file. Plural forms contribute
+/// all of their placeholders.
+fn load_language(path: &std::path::Path) -> anyhow::Result<BTreeMap<String, KeyInfo>> {
+    use anyhow::Context;
+    let json: serde_json::Map<String, serde_json::Value> = serde_json::from_str(
+        &std::fs::read_to_string(path).context(format!("Failed to read {:?}", path))?
+    ).context(format!("{:?} is not a valid localization file", path))?;
+
+    let mut result = BTreeMap::new();
+    for (key, value) in json {
+        let info = match value {
+            serde_json::Value::String(s) => KeyInfo {
+                placeholders: find_placeholders(&s),
+                plural_forms: None,
+            },
+            serde_json::Value::Object(forms) => KeyInfo {
+                placeholders: forms.values()
+                    .filter_map(|f| f.as_str())
+                    .flat_map(find_placeholders)
+                    .collect(),
+                plural_forms: Some(forms.keys().cloned().collect()),
+            },
+            _ => return Err(anyhow!("{:?}: {} is neither a string nor plural forms", path, key)),
+        };
+        result.insert(key, info);
+    }
+    Ok(result)
+}
+
+/// The plural categories a language's rules pick from. "other" is always among them,
+/// since it's the fallback of every plural string.
+pub fn plural_categories(language: &str) -> BTreeSet<&'static str> {
+    let mut result: BTreeSet<&'static str> = (0..=1000)
+        .map(|n| localization::plural_category(language, n))
+        .collect();
+    result.insert("other");
+    result
+}
+
+/// Keys allowed to go untranslated, e.g. ones the scripts look up to test the fallback.
+fn load_allowlist() -> BTreeSet<String> {
+    utils::read_file_lines(consts::LOCALIZATION_ALLOWLIST_PATH)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|line| String::from(line.trim()))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+pub struct CoverageReport {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Errors for the keys scripts use that some language lacks. Allowlisted keys may go
+/// untranslated everywhere, but once a language defines one, every language needs it.
+fn missing_used_keys(
+    used: &BTreeMap<String, BTreeSet<String>>,
+    languages: &BTreeMap<String, BTreeMap<String, KeyInfo>>,
+    allowlist: &BTreeSet<String>,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (key, scripts) in used.iter() {
+        if allowlist.contains(key) && !languages.values().any(|keys| keys.contains_key(key)) {
+            continue;
+        }
+        for (language, keys) in languages.iter() {
+            if !keys.contains_key(key) {
+                errors.push(format!("{}: missing {} (used in {})",
+                    language, key, scripts.iter().cloned().collect::<Vec<_>>().join(", ")));
+            }
+        }
+    }
+    errors
+}
+
+pub fn check() -> CoverageReport {
+    let mut report = CoverageReport { errors: Vec::new(), warnings: Vec::new() };
+
+    // key -> scripts using it
+    let mut used: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for script in utils::get_files_with_extension_recursive(
+            consts::SCRIPTS_PATH, vec![consts::SCRIPT_FILE_EXTENSION]) {
+        match std::fs::read_to_string(&script) {
+            Ok(source) => for key in find_tr_keys(&source) {
+                used.entry(key).or_default().insert(script.to_string_lossy().into_owned());
+            },
+            Err(e) => report.errors.push(format!("Failed to read {:?}: {}", script, e)),
+        }
+    }
+
+    let mut languages = BTreeMap::new();
+    for path in utils::get_files_with_extension_from(
+            consts::LOCALIZATION_PATH, vec![consts::LOCALIZATION_EXTENSION]) {
+        let name = String::from(path.file_stem().unwrap().to_string_lossy());
+        match load_language(&path) {
+            Ok(keys) => { languages.insert(name, keys); },
+            Err(e) => report.errors.push(format!("{:#}", e)),
+        }
+    }
+
+    let reference = match languages.get(consts::DEFAULT_LANGUAGE) {
+        Some(r) => r,
+        None => {
+            report.errors.push(format!("Reference language {} is missing", consts::DEFAULT_LANGUAGE));
+            return report;
+        },
+    };
+
+    report.errors.extend(missing_used_keys(&used, &languages, &load_allowlist()));
+
+    for (language, keys) in languages.iter() {
+        for (key, info) in reference.iter() {
+            match keys.get(key) {
+                None if !used.contains_key(key) => report.errors.push(
+                    format!("{}: missing {}", language, key)),
+                Some(translated) if translated.placeholders != info.placeholders => report.errors.push(format!(
+                    "{}: {} uses placeholders {:?}, but {} uses {:?}",
+                    language, key, translated.placeholders, consts::DEFAULT_LANGUAGE, info.placeholders)),
+                _ => (),
+            }
+        }
+
+        let categories = plural_categories(language);
+        for (key, info) in keys.iter() {
+            let forms = match &info.plural_forms {
+                Some(forms) => forms,
+                None => continue,
+            };
+            let missing: Vec<&str> = categories.iter()
+                .filter(|c| !forms.contains(**c))
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                report.errors.push(format!("{}: {} has no {:?} plural forms", language, key, missing));
+            }
+            for form in forms.iter().filter(|f| !categories.contains(f.as_str())) {
+                report.warnings.push(format!("{}: {} has a {} form, which {} never uses",
+                    language, key, form, language));
+            }
+        }
+
+        for key in keys.keys() {
+            if !reference.contains_key(key) {
+                report.warnings.push(format!("{}: {} is not in {}", language, key, consts::DEFAULT_LANGUAGE));
+            }
+        }
+    }
+
+    // Keys may also be used by the engine itself, so unused keys are only a warning
+    for key in reference.keys() {
+        if !used.contains_key(key) {
+            report.warnings.push(format!("{} is not used by any script", key));
+        }
+    }
+
+    report
+}
+
+impl CoverageReport {
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+        for w in self.warnings.iter() {
+            result += &format!("WARNING - {}\n", w);
+        }
+        for e in self.errors.iter() {
+            result += &format!("ERROR - {}\n", e);
+        }
+        result += &format!("{} errors, {} warnings\n", self.errors.len(), self.warnings.len());
+        result
+    }
+}
+
+/// Prints the coverage report, writes it to LOCALIZATION_REPORT_PATH and returns the
+/// process exit code, which is non-zero if there are errors or the report can't be written.
+pub fn run() -> i32 {
+    let report = check();
+    let text = report.to_text();
+    print!("{}", text);
+    if let Err(e) = std::fs::write(consts::LOCALIZATION_REPORT_PATH, &text) {
+        log::error(&format!("Failed to write {}: {}", consts::LOCALIZATION_REPORT_PATH, e));
+        return 1;
+    }
+
+    if report.errors.is_empty() { 0 } else { 1 }
+}
+
+#[test]
+fn tr_keys_keep_escaped_quotes() {
+    let source = r#"tr("plain"); tr("say \"hi\"", #{}); tr("new\nline"); tr(name); str("not_tr")"#;
+    assert_eq!(find_tr_keys(source), vec![String::from("plain"), String::from("say \"hi\"")]);
+}
+
+#[test]
+fn allowlisted_keys_must_be_in_every_language_that_has_them() {
+    let language = |keys: &[&str]| keys.iter()
+        .map(|k| (String::from(*k), KeyInfo { placeholders: Placeholders::new(), plural_forms: None }))
+        .collect::<BTreeMap<_, _>>();
+    let used: BTreeMap<String, BTreeSet<String>> = vec!["untranslated", "partly_translated"].into_iter()
+        .map(|k| (String::from(k), vec![String::from("test.rhai")].into_iter().collect()))
+        .collect();
+    let mut languages = BTreeMap::new();
+    languages.insert(String::from("English"), language(&["partly_translated"]));
+    languages.insert(String::from("Hebrew"), language(&[]));
+    let allowlist = vec![String::from("untranslated"), String::from("partly_translated")].into_iter().collect();
+
+    assert_eq!(missing_used_keys(&used, &languages, &allowlist),
+        vec![String::from("Hebrew: missing partly_translated (used in test.rhai)")]);
+}
@@ -10,7 +10,9 @@ pub mod log;
 pub mod utils;
 pub mod consts;
 pub mod localization;
+pub mod localization_coverage;
 pub mod scripting;
+pub mod ui;
 pub mod prelude;
 use crate::gameplay::levels::Level;
 
@@ -28,6 +30,8 @@ pub struct Engine {
     system_keyboard: systems::KeyboardSystem,
     system_preload: systems::PreloadSystem,
     system_skybox: systems::StaticSkyboxSystem,
+    system_lights: systems::LightSystem,
+    system_text: systems::TextSystem,
     pub input: input::InputInfo,
     pub cfg: config::Config,
     pub audio: audio::AudioManager,
@@ -35,8 +39,10 @@ pub struct Engine {
 }
 
 impl Engine {
-    pub fn new(eventloop: &winit::event_loop::EventLoop<()>, level: Box<dyn Level>) -> Engine {
-        let renderer = graphics::Renderer::new(eventloop);
+    pub fn new(eventloop: &winit::event_loop::EventLoop<()>, level: Box<dyn Level>)
+    -> anyhow::Result<Engine> {
+        let cfg = config::Config::load();
+        let renderer = graphics::Renderer::new(eventloop, &cfg.window_settings())?;
         let mut result = Engine {
             level,
             last_tick: std::time::Instant::now(),
@@ -46,12 +52,26 @@ impl Engine {
             system_keyboard: systems::KeyboardSystem::new(),
             system_preload: systems::PreloadSystem::new(),
             system_skybox: systems::StaticSkyboxSystem::new(),
+            system_lights: systems::LightSystem::new(),
+            system_text: systems::TextSystem::new(),
             input: input::InputInfo::new(),
-            cfg: config::Config::load(),
+            cfg,
             audio: audio::AudioManager::new(),
             renderer,
         };
-        result.renderer.resize_window([result.cfg.resolution_x, result.cfg.resolution_y]);
+        *result.system_scripting.get_game_context().monitors.write().unwrap() = result.renderer.get_monitors();
+        if let Err(e) = result.renderer.set_max_lights(result.cfg.max_lights) {
+            log::err(&e);
+        }
+        result.renderer.set_post_process(result.cfg.post_process());
+        result.renderer.set_shadows(result.cfg.shadows());
+        result.renderer.set_cache_dir(result.cfg.cache_dir());
+        result.renderer.set_render_scale(result.cfg.render_scale);
+        result.system_scripting.get_game_context().events.set_trace(result.cfg.trace_game_events);
+        if let Err(e) = localization::set_language(&result.cfg.language) {
+            log::err(&e);
+            result.cfg.language = localization::current_language();
+        }
 
         for space in result.level.iter_spaces() {
             result.system_preload.run_now(space);
@@ -75,27 +95,26 @@ impl Engine {
             }
         }
 
-        result
+        Ok(result)
     }
 
     pub fn tick(&mut self) -> TickResult {
         let dt = self.last_tick.elapsed();
         self.last_tick = std::time::Instant::now();
-        let asd = self.input.drain_kb_events();
+        let mut asd = self.input.drain_kb_events();
         for k in asd.iter() {
-            if k.is_down {
-                match &k.key[..] {
-                    "X" => self.renderer.light_pos[0] += 0.03,
-                    "Y" => self.renderer.light_pos[1] += 0.03,
-                    "Z" => self.renderer.light_pos[2] += 0.03,
-                    "SHIFT+X" => self.renderer.light_pos[0] -= 0.03,
-                    "SHIFT+Y" => self.renderer.light_pos[1] -= 0.03,
-                    "SHIFT+Z" => self.renderer.light_pos[2] -= 0.03,
-                    _ => (),
-                }
+            if k.is_down && k.key == "F12" {
+                self.take_screenshot();
             }
         }
 
+        // The UI gets the keys it navigates with first
+        {
+            let context = self.system_scripting.get_game_context();
+            let mut ui = context.ui.lock();
+            asd.retain(|k| !(k.is_down && ui.key(&k.key)));
+        }
+
         // Keyboard input
         {
             self.system_keyboard.new_frame(asd);
@@ -110,6 +129,11 @@ impl Engine {
         }
         
         
+        if cfg!(debug_assertions) {
+            self.system_scripting.hot_reload();
+            self.renderer.hot_reload_shaders();
+        }
+        self.system_scripting.new_tick();
         for space in self.level.iter_spaces() {
             self.system_scripting.run_now(space);
         }
@@ -124,19 +148,71 @@ impl Engine {
                     return TickResult::Exit
                 },
                 EngineEvent::ChangeResolution(x, y) => {
-                    self.renderer.resize_window([x, y]);
                     self.cfg.resolution_x = x;
                     self.cfg.resolution_y = y;
+                    self.renderer.set_window_settings(&self.cfg.window_settings());
+                },
+                EngineEvent::SetRenderScale(render_scale) => {
+                    self.renderer.set_render_scale(render_scale);
+                    self.cfg.render_scale = self.renderer.get_render_scale();
+                },
+                EngineEvent::SetWindowMode(mode) => {
+                    self.cfg.window_mode = mode;
+                    self.renderer.set_window_settings(&self.cfg.window_settings());
+                },
+                EngineEvent::SetMonitor(monitor) => {
+                    self.cfg.monitor = monitor;
+                    self.renderer.set_window_settings(&self.cfg.window_settings());
+                },
+                EngineEvent::SetResizable(resizable) => {
+                    self.cfg.resizable = resizable;
+                    self.renderer.set_window_settings(&self.cfg.window_settings());
                 },
                 EngineEvent::SetActiveSpace(space) => {
                     self.level.set_active_space(&space);
-                }
+                },
+                EngineEvent::SetLanguage(language) => {
+                    match localization::set_language(&language) {
+                        Ok(()) => {
+                            self.cfg.language = language.clone();
+                            let mut args = rhai::Map::new();
+                            args.insert("language".into(), rhai::Dynamic::from(language));
+                            self.system_scripting.get_game_context()
+                                .broadcast_event("language_changed", args);
+                        },
+                        Err(e) => log::err(&e),
+                    }
+                },
+                EngineEvent::Screenshot => self.take_screenshot(),
             }
         }
 
         TickResult::Continue
     }
 
+    /// Follows the window to a display with another scale factor, and the size the
+    /// window system suggests for it there
+    pub fn scale_factor_changed(&mut self, scale_factor: f64, dims: [u32; 2]) {
+        self.renderer.set_scale_factor(scale_factor);
+        self.window_resized(dims);
+    }
+
+    /// Follows the window's new size, in physical pixels. Windowed sizes are remembered in the config.
+    pub fn window_resized(&mut self, dims: [u32; 2]) {
+        self.renderer.resize(dims);
+        if self.cfg.window_mode == graphics::WindowMode::Windowed && dims[0] > 0 && dims[1] > 0 {
+            self.cfg.resolution_x = dims[0];
+            self.cfg.resolution_y = dims[1];
+        }
+    }
+
+    /// Saves the next frame as a timestamped PNG in the screenshots folder.
+    pub fn take_screenshot(&mut self) {
+        self.renderer.request_screenshot(std::path::Path::new(consts::SCREENSHOTS_PATH).join(
+            chrono::Local::now().format("%Y-%m-%d %H-%M-%S%.3f.png").to_string()
+        ));
+    }
+
     pub fn draw_frame(&mut self) {
         // Save CPU/GPU when game is minimized
         if !self.input.is_focused {
@@ -144,24 +220,46 @@ impl Engine {
             return;
         }
 
+        // The UI gets the mouse first, and hides the scene under it from picking
+        let context = self.system_scripting.get_game_context();
+        let mut mouse_events = self.input.drain_mouse_events();
+        let (ui_layers, over_ui) = {
+            let mut ui = context.ui.lock();
+            // The UI is in logical pixels, the cursor in physical ones
+            let scale_factor = self.renderer.get_scale_factor();
+            ui.layout(self.renderer.get_logical_size());
+            ui.mouse_moved([(self.input.mousex / scale_factor) as f32, (self.input.mousey / scale_factor) as f32]);
+            mouse_events.retain(|e| !(e.key == input::MouseClickType::Left && ui.mouse_button(e.is_down)));
+            for event in ui.take_events() {
+                context.game_event_tx.send(event).unwrap();
+            }
+            (ui.draw(), ui.is_hovered())
+        };
+
         for space in self.level.iter_spaces() {
             self.system_static_mesh.run_now(&space);
             self.system_skybox.run_now(&space);
+            self.system_lights.run_now(&space);
+            self.system_text.run_now(&space);
         }
         let (instances, pickables) = self.system_static_mesh.get_instances_and_flush();
 
         let mut framebuilder = graphics::FrameBuilder::new();
         framebuilder.with_meshes(instances);
         framebuilder.with_skybox(self.system_skybox.get_and_flush());
+        framebuilder.with_lights(self.system_lights.get_lights_and_flush());
+        framebuilder.with_texts(self.system_text.get_texts_and_flush());
+        framebuilder.with_ui(ui_layers);
 
         let result = self.renderer.draw_frame(
             &framebuilder,
-            &self.system_scripting.get_game_context().camera.get(),
+            &context.camera.get(),
             [self.input.mousex as u32,
              self.input.mousey as u32],
         );
 
         let picked_index = match self.renderer.get_pick_result() {
+            Some(_) if over_ui => None,
             Some(i) => {
                 if pickables.len() >= i as usize {
                     Some(*pickables.get((i - 1) as usize).unwrap())
@@ -171,7 +269,7 @@ impl Engine {
             },
             None => None
         };
-        self.system_mouse.new_frame(picked_index, self.input.drain_mouse_events());
+        self.system_mouse.new_frame(picked_index, mouse_events);
         for space in self.level.iter_spaces() {
             self.system_mouse.run_now(space);
         }
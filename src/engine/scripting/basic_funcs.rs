@@ -12,6 +12,28 @@ pub fn info(x: String) {
     log::info(&x);
 }
 
+pub fn tr(key: String) -> String {
+    crate::engine::localization::tr(&key, &std::collections::HashMap::new())
+}
+
+pub fn tr_with_args(key: String, args: rhai::Map) -> String {
+    let args = args.into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    crate::engine::localization::tr(&key, &args)
+}
+
+pub fn current_language() -> String {
+    crate::engine::localization::current_language()
+}
+
+pub fn available_languages() -> rhai::Array {
+    crate::engine::localization::Localization::get_available_languages()
+        .into_iter()
+        .map(rhai::Dynamic::from)
+        .collect()
+}
+
 pub fn vec3(x: f64, y: f64, z: f64) -> nalgebra::Vector3<f32> {
     nalgebra::Vector3::new(x as f32, y as f32, z as f32)
 }
@@ -0,0 +1,135 @@
+use crate::engine::prelude::*;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::HashMap;
+use super::ScriptId;
+
+/// An event sent between entities (for example, "lclick", "kill_all_zombies", etc)
+#[derive(Debug, Clone)]
+pub struct GameEvent {
+    pub name: String,
+    pub args: rhai::Map,
+
+    /// Targeted events go only to this script, whether or not it subscribed.
+    pub target: Option<ScriptId>,
+}
+
+/// When a delayed event should be released to its subscribers
+#[derive(Debug, Clone, Copy)]
+pub enum EventDue {
+    At(std::time::Instant),
+    Tick(u64),
+}
+
+#[derive(Debug)]
+struct DelayedEvent {
+    event: GameEvent,
+    due: EventDue,
+}
+
+/// Holds the subscriptions and pending events of a GameContext.
+#[derive(Debug)]
+pub struct EventBus {
+    // Kept sorted by descending priority, so handlers run in that order
+    subscribers: Mutex<HashMap<String, Vec<(i64, ScriptId)>>>,
+    delayed: Mutex<Vec<DelayedEvent>>,
+    tick: AtomicU64,
+    propagation_stopped: AtomicBool,
+    trace: AtomicBool,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            subscribers: Mutex::new(HashMap::new()),
+            delayed: Mutex::new(Vec::new()),
+            tick: AtomicU64::new(0),
+            propagation_stopped: AtomicBool::new(false),
+            trace: AtomicBool::new(false),
+        }
+    }
+
+    /// The scripts an event should be dispatched to, in dispatch order. They may be in
+    /// different spaces.
+    pub fn recipients(&self, event: &GameEvent) -> Vec<ScriptId> {
+        if let Some(target) = event.target {
+            return vec![target];
+        }
+
+        self.subscribers.lock().unwrap()
+            .get(&event.name)
+            .map(|subs| subs.iter().map(|&(_, id)| id).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn subscribe(&self, id: ScriptId, name: String, priority: i64) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let subs = subscribers.entry(name).or_default();
+        subs.retain(|&(_, sub)| sub != id);
+
+        // Equal priorities keep subscription order
+        let index = subs.iter().position(|&(p, _)| p < priority).unwrap_or(subs.len());
+        subs.insert(index, (priority, id));
+    }
+
+    pub fn unsubscribe(&self, id: ScriptId, name: &str) {
+        if let Some(subs) = self.subscribers.lock().unwrap().get_mut(name) {
+            subs.retain(|&(_, sub)| sub != id);
+        }
+    }
+
+    pub fn delay(&self, event: GameEvent, due: EventDue) {
+        self.delayed.lock().unwrap().push(DelayedEvent { event, due });
+    }
+
+    pub fn current_tick(&self) -> u64 {
+        self.tick.load(Ordering::SeqCst)
+    }
+
+    /// Starts a new tick, and returns the delayed events that became due.
+    pub fn next_tick(&self) -> Vec<GameEvent> {
+        let tick = self.tick.fetch_add(1, Ordering::SeqCst) + 1;
+        let now = std::time::Instant::now();
+        let mut delayed = self.delayed.lock().unwrap();
+        let (due, pending): (Vec<DelayedEvent>, Vec<DelayedEvent>) = delayed.drain(..)
+            .partition(|d| match d.due {
+                EventDue::At(t) => t <= now,
+                EventDue::Tick(t) => t <= tick,
+            });
+        *delayed = pending;
+        due.into_iter().map(|d| d.event).collect()
+    }
+
+    /// Makes the event currently being dispatched skip its remaining handlers.
+    pub fn stop_propagation(&self) {
+        self.propagation_stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether a handler stopped propagation, and resets it for the next handler.
+    pub fn take_propagation_stopped(&self) -> bool {
+        self.propagation_stopped.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn set_trace(&self, enabled: bool) {
+        self.trace.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Writes a dispatch to the log, if tracing is enabled.
+    pub fn trace(&self, event: &GameEvent, recipients: &[ScriptId]) {
+        if !self.trace.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if recipients.is_empty() {
+            log::info(&format!("Event {} {:?} has no recipients", event.name, event.args));
+        } else {
+            log::info(&format!("Event {} {:?} -> {:?}", event.name, event.args, recipients));
+        }
+    }
+
+    pub fn trace_stopped(&self, event: &GameEvent, by: ScriptId, skipped: &[ScriptId]) {
+        if self.trace.load(Ordering::SeqCst) && !skipped.is_empty() {
+            log::info(&format!("Event {} stopped by {:?}, skipping {:?}", event.name, by, skipped));
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Runs gameplay scripts against a bare space, without a window or renderer.
+//! Test scripts in `scripts/tests` are discovered and run by `cargo test`.
+use crate::engine::prelude::*;
+use crate::engine::components::{ScriptingComponent, KeyboardComponent, MouseComponent};
+use crate::engine::systems::ScriptingSystem;
+use crate::engine::scripting::{EngineEvent, GameEvent, ScriptId, SpaceId};
+use specs::{Builder, Entity, RunNow, World, WorldExt};
+
+pub struct ScriptHarness {
+    pub world: World,
+    pub system: ScriptingSystem,
+}
+
+impl ScriptHarness {
+    pub fn new() -> ScriptHarness {
+        ScriptHarness {
+            world: crate::gameplay::levels::create_space(),
+            system: ScriptingSystem::new(),
+        }
+    }
+
+    /// Creates an entity running `script`, able to receive keyboard and mouse input.
+    pub fn spawn(&mut self, script: &str, keys: Vec<String>) -> Entity {
+        self.world.create_entity()
+        .with(ScriptingComponent::new(script))
+        .with(KeyboardComponent::new(keys))
+        .with(MouseComponent::new())
+        .build()
+    }
+
+    pub fn press_key(&mut self, ent: Entity, key: &str, is_down: bool) {
+        if let Some(keyb) = self.world.write_storage::<KeyboardComponent>().get_mut(ent) {
+            keyb.events.push((String::from(key), is_down));
+        }
+    }
+
+    pub fn click(&mut self, ent: Entity) {
+        if let Some(mouse) = self.world.write_storage::<MouseComponent>().get_mut(ent) {
+            mouse.l_is_clicked = true;
+        }
+    }
+
+    /// What the script of an entity knows itself by
+    pub fn script_id(&self, ent: Entity) -> ScriptId {
+        ScriptId {
+            space: *self.world.read_resource::<SpaceId>(),
+            entity: ent,
+        }
+    }
+
+    pub fn send_event(&mut self, name: &str, args: rhai::Map, target: Option<Entity>) {
+        let target = target.map(|ent| self.script_id(ent));
+        self.system.get_game_context().game_event_tx.send(GameEvent {
+            name: String::from(name),
+            args,
+            target,
+        }).unwrap();
+    }
+
+    pub fn run_ticks(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.system.new_tick();
+            self.system.run_now(&self.world);
+            self.world.maintain();
+        }
+    }
+
+    pub fn object_self(&self, ent: Entity) -> Option<rhai::Map> {
+        self.world.read_storage::<ScriptingComponent>().get(ent).map(|s| s.object_self.clone())
+    }
+
+    /// The last error a script raised, if it failed at all.
+    pub fn script_error(&self, ent: Entity) -> Option<String> {
+        self.world.read_storage::<ScriptingComponent>().get(ent).and_then(|s| s.last_error.clone())
+    }
+
+    pub fn drain_engine_events(&mut self) -> Vec<EngineEvent> {
+        self.system.get_game_context().engine_event_rx.try_iter().collect()
+    }
+
+    pub fn entity_count(&self) -> usize {
+        use specs::Join;
+        self.world.entities().join().count()
+    }
+}
+
+/// Runs a test script. The script is spawned and sent a `start` event, then the space is
+/// ticked `test_ticks` times (read from the object returned by `on_spawn`, 1 by default),
+/// and finally the script gets a `check` event. It fails the test by throwing from any handler.
+pub fn run_test_script(name: &str) -> anyhow::Result<()> {
+    let mut harness = ScriptHarness::new();
+    let ent = harness.spawn(name, vec![]);
+    harness.run_ticks(1);
+
+    let ticks = harness.object_self(ent)
+        .and_then(|s| s.get("test_ticks").and_then(|t| t.clone().try_cast::<i64>()))
+        .unwrap_or(1);
+    harness.send_event("start", rhai::Map::new(), Some(ent));
+    harness.run_ticks(ticks.max(1) as usize);
+
+    harness.send_event("check", rhai::Map::new(), Some(ent));
+    harness.run_ticks(1);
+
+    match harness.script_error(ent) {
+        Some(e) => Err(anyhow!("{}", e)),
+        None => Ok(()),
+    }
+}
+
+pub fn get_test_scripts() -> Vec<String> {
+    let folder = std::path::Path::new(consts::SCRIPTS_PATH).join(consts::SCRIPT_TESTS_FOLDER);
+    super::get_scripts_in_folder(folder).into_iter()
+        .filter_map(|p| p.file_name().map(|f| format!("{}/{}",
+            consts::SCRIPT_TESTS_FOLDER, f.to_string_lossy())))
+        .collect()
+}
+
+#[test]
+fn script_tests() {
+    let mut failed = Vec::new();
+    for script in get_test_scripts() {
+        if let Err(e) = run_test_script(&script) {
+            failed.push(format!("{}: {:#}", script, e));
+        }
+    }
+    assert!(failed.is_empty(), "Script tests failed:\n{}", failed.join("\n"));
+}
+
+#[test]
+fn keyboard_and_events_reach_scripts() {
+    let mut harness = ScriptHarness::new();
+    let ent = harness.spawn("tests/fixtures/input.rhai", vec![String::from("Space")]);
+    harness.run_ticks(1);
+
+    harness.press_key(ent, "Space", true);
+    harness.send_event("ping", rhai::Map::new(), None);
+    harness.run_ticks(2);
+
+    let object_self = harness.object_self(ent).unwrap();
+    assert_eq!(object_self.get("keys").unwrap().clone().cast::<i64>(), 1);
+    assert_eq!(object_self.get("pings").unwrap().clone().cast::<i64>(), 1);
+    assert!(harness.script_error(ent).is_none());
+}
+
+#[test]
+fn missing_handlers_are_not_failures() {
+    let mut harness = ScriptHarness::new();
+    let ent = harness.spawn("tests/fixtures/counter.rhai", vec![String::from("Space")]);
+    harness.send_event("start", rhai::Map::new(), Some(ent));
+    harness.run_ticks(2);
+
+    // The counter has no on_kb, lclick or unhandled()
+    for _ in 0..consts::SCRIPT_MAX_FAILURES + 1 {
+        harness.press_key(ent, "Space", true);
+        harness.click(ent);
+        harness.send_event("unhandled", rhai::Map::new(), Some(ent));
+        harness.run_ticks(1);
+    }
+    harness.send_event("ping", rhai::Map::new(), None);
+    harness.run_ticks(2);
+
+    assert!(harness.script_error(ent).is_none());
+    assert_eq!(harness.object_self(ent).unwrap().get("pings").unwrap().clone().cast::<i64>(), 1);
+}
+
+#[test]
+fn scripts_send_engine_events() {
+    let mut harness = ScriptHarness::new();
+    let ent = harness.spawn("tests/fixtures/exit.rhai", vec![]);
+    harness.run_ticks(1);
+    assert!(harness.drain_engine_events().is_empty());
+
+    harness.send_event("start", rhai::Map::new(), Some(ent));
+    harness.run_ticks(1);
+    let events = harness.drain_engine_events();
+    assert_eq!(events.len(), 1);
+    assert!(matches!(events[0], EngineEvent::ExitGame));
+    assert!(harness.drain_engine_events().is_empty());
+}
+
+#[test]
+fn spawned_entities_run_their_scripts() {
+    let mut harness = ScriptHarness::new();
+    assert_eq!(harness.entity_count(), 0);
+    let first = harness.spawn("tests/fixtures/counter.rhai", vec![]);
+    let second = harness.spawn("tests/fixtures/counter.rhai", vec![]);
+    harness.run_ticks(1);
+
+    assert_eq!(harness.entity_count(), 2);
+    assert_ne!(first, second);
+    assert!(harness.object_self(first).unwrap().contains_key("pings"));
+    assert!(harness.object_self(second).unwrap().contains_key("pings"));
+}
+
+#[test]
+fn events_sent_in_a_loop_spread_over_ticks() {
+    let mut harness = ScriptHarness::new();
+    let ent = harness.spawn("tests/fixtures/echo.rhai", vec![]);
+    harness.run_ticks(1);
+
+    let echoes = |harness: &ScriptHarness| harness.object_self(ent).unwrap().get("echoes").unwrap().clone()
+        .cast::<i64>() as usize;
+    harness.send_event("echo", rhai::Map::new(), Some(ent));
+    harness.run_ticks(1);
+    assert_eq!(echoes(&harness), consts::SCRIPT_MAX_EVENTS_PER_TICK);
+    harness.run_ticks(1);
+    assert_eq!(echoes(&harness), 2 * consts::SCRIPT_MAX_EVENTS_PER_TICK);
+}
+
+#[test]
+fn sequences_end_with_their_owner() {
+    let mut harness = ScriptHarness::new();
+    let ent = harness.spawn("tests/fixtures/waiting_sequence.rhai", vec![]);
+    harness.run_ticks(1);
+    harness.send_event("start", rhai::Map::new(), Some(ent));
+    harness.run_ticks(1);
+    let sequences = harness.system.get_game_context().sequences.clone();
+    assert!(!sequences.is_idle());
+
+    harness.world.delete_entity(ent).unwrap();
+    harness.run_ticks(1);
+    assert!(sequences.is_idle());
+}
+
+#[test]
+fn events_stay_in_their_space() {
+    fn run_ticks(harness: &mut ScriptHarness, galaxy: &World, ticks: usize) {
+        for _ in 0..ticks {
+            harness.system.new_tick();
+            harness.system.run_now(&harness.world);
+            harness.system.run_now(galaxy);
+        }
+    }
+
+    let mut harness = ScriptHarness::new();
+    let mut galaxy = crate::gameplay::levels::create_space();
+    let menu_ent = harness.spawn("tests/fixtures/counter.rhai", vec![]);
+    let galaxy_ent = galaxy.create_entity()
+        .with(ScriptingComponent::new("tests/fixtures/counter.rhai"))
+        .build();
+    // Entity ids are only unique within a space
+    assert_eq!(menu_ent, galaxy_ent);
+    let galaxy_id = ScriptId { space: *galaxy.read_resource::<SpaceId>(), entity: galaxy_ent };
+    run_ticks(&mut harness, &galaxy, 1);
+
+    // Only the galaxy's script subscribes
+    harness.system.get_game_context().game_event_tx.send(GameEvent {
+        name: String::from("start"),
+        args: rhai::Map::new(),
+        target: Some(galaxy_id),
+    }).unwrap();
+    run_ticks(&mut harness, &galaxy, 1);
+    harness.send_event("ping", rhai::Map::new(), None);
+    run_ticks(&mut harness, &galaxy, 1);
+
+    let pings = |object_self: Option<rhai::Map>| object_self.unwrap()["pings"].clone().cast::<i64>();
+    let galaxy_self = galaxy.read_storage::<ScriptingComponent>().get(galaxy_ent).map(|s| s.object_self.clone());
+    assert_eq!(pings(harness.object_self(menu_ent)), 0);
+    assert_eq!(pings(galaxy_self), 1);
+    assert!(harness.script_error(menu_ent).is_none());
+}
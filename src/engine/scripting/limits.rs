@@ -0,0 +1,41 @@
+use crate::engine::prelude::*;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use rhai::Engine;
+
+thread_local! {
+    // Deadline of the script call currently running on this thread, if any.
+    static CALL_DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// Caps what a single script call may consume, so a runaway loop can't freeze the engine.
+pub fn apply(engine: &mut Engine) {
+    engine.set_max_operations(consts::SCRIPT_MAX_OPERATIONS);
+    engine.set_max_call_levels(consts::SCRIPT_MAX_CALL_LEVELS);
+    engine.set_max_string_size(consts::SCRIPT_MAX_STRING_SIZE);
+    engine.set_max_array_size(consts::SCRIPT_MAX_ARRAY_SIZE);
+    engine.set_max_map_size(consts::SCRIPT_MAX_MAP_SIZE);
+
+    // Returning a value aborts the script with a termination error carrying it.
+    // Reading the clock on every operation is slow, so it's only read now and then.
+    engine.on_progress(|ops| {
+        if ops % consts::SCRIPT_DEADLINE_CHECK_INTERVAL != 0 {
+            return None;
+        }
+        CALL_DEADLINE.with(|d| match d.get() {
+            Some(deadline) if Instant::now() >= deadline => Some("time limit exceeded".into()),
+            _ => None,
+        })
+    });
+}
+
+/// Starts the time budget of a script call. Must be paired with `end_call`.
+pub fn begin_call() {
+    CALL_DEADLINE.with(|d| d.set(Some(
+        Instant::now() + Duration::from_millis(consts::SCRIPT_MAX_CALL_DURATION_MS)
+    )));
+}
+
+pub fn end_call() {
+    CALL_DEADLINE.with(|d| d.set(None));
+}
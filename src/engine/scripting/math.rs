@@ -0,0 +1,250 @@
+//! Vector, quaternion and matrix functions for scripts, on top of the nalgebra types
+//! the engine uses. Scripts work in f64, so every scalar is converted at the boundary.
+use rhai::Engine;
+use nalgebra::{Matrix4, Point3, Unit, UnitQuaternion, Vector3};
+
+type Vec3 = Vector3<f32>;
+type Quat = UnitQuaternion<f32>;
+type Mat4 = Matrix4<f32>;
+
+// Vectors
+fn vec3_add(a: Vec3, b: Vec3) -> Vec3 {
+    a + b
+}
+
+fn vec3_sub(a: Vec3, b: Vec3) -> Vec3 {
+    a - b
+}
+
+fn vec3_neg(a: Vec3) -> Vec3 {
+    -a
+}
+
+fn vec3_mul(a: Vec3, s: f64) -> Vec3 {
+    a * s as f32
+}
+
+fn vec3_mul_rev(s: f64, a: Vec3) -> Vec3 {
+    a * s as f32
+}
+
+fn vec3_mul_vec3(a: Vec3, b: Vec3) -> Vec3 {
+    a.component_mul(&b)
+}
+
+fn vec3_div(a: Vec3, s: f64) -> Vec3 {
+    a / s as f32
+}
+
+fn vec3_eq(a: Vec3, b: Vec3) -> bool {
+    a == b
+}
+
+fn vec3_dot(a: Vec3, b: Vec3) -> f64 {
+    a.dot(&b) as f64
+}
+
+fn vec3_cross(a: Vec3, b: Vec3) -> Vec3 {
+    a.cross(&b)
+}
+
+fn vec3_length(a: Vec3) -> f64 {
+    a.norm() as f64
+}
+
+fn vec3_length_squared(a: Vec3) -> f64 {
+    a.norm_squared() as f64
+}
+
+fn vec3_distance(a: Vec3, b: Vec3) -> f64 {
+    (a - b).norm() as f64
+}
+
+fn vec3_lerp(a: Vec3, b: Vec3, t: f64) -> Vec3 {
+    a.lerp(&b, t as f32)
+}
+
+fn vec3_to_string(a: Vec3) -> String {
+    format!("vec3({}, {}, {})", a.x, a.y, a.z)
+}
+
+/// Normalizing a zero vector yields zero instead of NaNs
+fn vec3_normalize(a: Vec3) -> Vec3 {
+    a.try_normalize(std::f32::EPSILON).unwrap_or_else(Vec3::zeros)
+}
+
+fn vec3_get_x(a: &mut Vec3) -> f64 {
+    a.x as f64
+}
+
+fn vec3_get_y(a: &mut Vec3) -> f64 {
+    a.y as f64
+}
+
+fn vec3_get_z(a: &mut Vec3) -> f64 {
+    a.z as f64
+}
+
+fn vec3_set_x(a: &mut Vec3, v: f64) {
+    a.x = v as f32;
+}
+
+fn vec3_set_y(a: &mut Vec3, v: f64) {
+    a.y = v as f32;
+}
+
+fn vec3_set_z(a: &mut Vec3, v: f64) {
+    a.z = v as f32;
+}
+
+// Quaternions
+fn quat_identity() -> Quat {
+    Quat::identity()
+}
+
+fn quat_mul(a: Quat, b: Quat) -> Quat {
+    a * b
+}
+
+fn quat_rotate(q: Quat, v: Vec3) -> Vec3 {
+    q * v
+}
+
+fn quat_inverse(q: Quat) -> Quat {
+    q.inverse()
+}
+
+fn quat_angle(q: Quat) -> f64 {
+    q.angle() as f64
+}
+
+fn quat_to_string(q: Quat) -> String {
+    format!("quat({}, {}, {}, {})", q.i, q.j, q.k, q.w)
+}
+
+/// Rotation of `angle` radians around `axis`. A zero axis gives no rotation.
+fn quat_from_axis_angle(axis: Vec3, angle: f64) -> Quat {
+    match Unit::try_new(axis, std::f32::EPSILON) {
+        Some(axis) => Quat::from_axis_angle(&axis, angle as f32),
+        None => Quat::identity(),
+    }
+}
+
+fn quat_from_euler(roll: f64, pitch: f64, yaw: f64) -> Quat {
+    Quat::from_euler_angles(roll as f32, pitch as f32, yaw as f32)
+}
+
+/// Falls back to the target rotation when the two are opposite and slerp is undefined
+fn quat_slerp(a: Quat, b: Quat, t: f64) -> Quat {
+    a.try_slerp(&b, t as f32, 0.0001).unwrap_or(b)
+}
+
+// Matrices
+fn mat4_identity() -> Mat4 {
+    Mat4::identity()
+}
+
+fn mat4_translation(v: Vec3) -> Mat4 {
+    Mat4::new_translation(&v)
+}
+
+fn mat4_rotation(q: Quat) -> Mat4 {
+    q.to_homogeneous()
+}
+
+fn mat4_scaling(s: f64) -> Mat4 {
+    Mat4::new_scaling(s as f32)
+}
+
+fn mat4_scaling_vec3(v: Vec3) -> Mat4 {
+    Mat4::new_nonuniform_scaling(&v)
+}
+
+fn mat4_mul(a: Mat4, b: Mat4) -> Mat4 {
+    a * b
+}
+
+fn mat4_translate(m: Mat4, v: Vec3) -> Mat4 {
+    Mat4::new_translation(&v) * m
+}
+
+fn mat4_rotate(m: Mat4, q: Quat) -> Mat4 {
+    q.to_homogeneous() * m
+}
+
+fn mat4_scale(m: Mat4, s: f64) -> Mat4 {
+    Mat4::new_scaling(s as f32) * m
+}
+
+/// Translation * rotation * scale, the usual way an object is placed in the world
+fn mat4_compose(translation: Vec3, rotation: Quat, scale: Vec3) -> Mat4 {
+    Mat4::new_translation(&translation) * rotation.to_homogeneous()
+        * Mat4::new_nonuniform_scaling(&scale)
+}
+
+/// Singular matrices have no inverse, so they give back the identity
+fn mat4_inverse(m: Mat4) -> Mat4 {
+    m.try_inverse().unwrap_or_else(Mat4::identity)
+}
+
+fn mat4_transform_point(m: Mat4, p: Vec3) -> Vec3 {
+    m.transform_point(&Point3::from(p)).coords
+}
+
+fn mat4_transform_vector(m: Mat4, v: Vec3) -> Vec3 {
+    m.transform_vector(&v)
+}
+
+fn mat4_get_translation(m: &mut Mat4) -> Vec3 {
+    Vec3::new(m[(0, 3)], m[(1, 3)], m[(2, 3)])
+}
+
+pub fn register(engine: &mut Engine) {
+    engine.register_fn("+", vec3_add);
+    engine.register_fn("-", vec3_sub);
+    engine.register_fn("-", vec3_neg);
+    engine.register_fn("*", vec3_mul);
+    engine.register_fn("*", vec3_mul_rev);
+    engine.register_fn("*", vec3_mul_vec3);
+    engine.register_fn("/", vec3_div);
+    engine.register_fn("==", vec3_eq);
+    engine.register_fn("dot", vec3_dot);
+    engine.register_fn("cross", vec3_cross);
+    engine.register_fn("length", vec3_length);
+    engine.register_fn("length_squared", vec3_length_squared);
+    engine.register_fn("distance", vec3_distance);
+    engine.register_fn("normalize", vec3_normalize);
+    engine.register_fn("lerp", vec3_lerp);
+    engine.register_fn("to_string", vec3_to_string);
+    engine.register_get_set("x", vec3_get_x, vec3_set_x);
+    engine.register_get_set("y", vec3_get_y, vec3_set_y);
+    engine.register_get_set("z", vec3_get_z, vec3_set_z);
+
+    engine.register_type::<Quat>();
+    engine.register_fn("quat", quat_identity);
+    engine.register_fn("quat_axis_angle", quat_from_axis_angle);
+    engine.register_fn("quat_euler", quat_from_euler);
+    engine.register_fn("*", quat_mul);
+    engine.register_fn("*", quat_rotate);
+    engine.register_fn("rotate", quat_rotate);
+    engine.register_fn("inverse", quat_inverse);
+    engine.register_fn("angle", quat_angle);
+    engine.register_fn("slerp", quat_slerp);
+    engine.register_fn("to_string", quat_to_string);
+
+    engine.register_type::<Mat4>();
+    engine.register_fn("mat4", mat4_identity);
+    engine.register_fn("mat4_translation", mat4_translation);
+    engine.register_fn("mat4_rotation", mat4_rotation);
+    engine.register_fn("mat4_scaling", mat4_scaling);
+    engine.register_fn("mat4_scaling", mat4_scaling_vec3);
+    engine.register_fn("mat4_compose", mat4_compose);
+    engine.register_fn("*", mat4_mul);
+    engine.register_fn("translate", mat4_translate);
+    engine.register_fn("rotate", mat4_rotate);
+    engine.register_fn("scale", mat4_scale);
+    engine.register_fn("inverse", mat4_inverse);
+    engine.register_fn("transform_point", mat4_transform_point);
+    engine.register_fn("transform_vector", mat4_transform_vector);
+    engine.register_get("translation", mat4_get_translation);
+}
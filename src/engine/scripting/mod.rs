@@ -1,12 +1,56 @@
 use crate::engine::prelude::*;
 use crate::engine::camera::Camera;
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use rhai::{Engine, RegisterFn};
+use std::sync::{Arc, RwLock};
+use rhai::Engine;
 use nalgebra::{Point3, Vector3};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::engine::ui;
+use crate::engine::graphics::{WindowMode, MonitorInfo};
 
 mod basic_funcs;
+mod math;
 pub mod interpolate;
+pub mod limits;
+pub mod modules;
+pub mod events;
+pub use events::GameEvent;
+pub mod sequences;
+#[cfg(test)]
+pub mod harness;
+
+/// Tells spaces apart. Entity ids are only unique within their space, so every space
+/// holds one of these as a resource. Ids aren't reused, even by a space created anew.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpaceId(u32);
+
+impl SpaceId {
+    pub fn new() -> SpaceId {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        SpaceId(NEXT.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+/// What scripts know themselves by (`self.id`): their entity along with its space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScriptId {
+    pub space: SpaceId,
+    pub entity: specs::Entity,
+}
+
+thread_local! {
+    // The script running on this thread, if any
+    static CALLER: Cell<Option<ScriptId>> = Cell::new(None);
+}
+
+/// Marks whose script runs next, so what it creates can belong to it
+pub fn set_caller(id: Option<ScriptId>) {
+    CALLER.with(|c| c.set(id));
+}
+
+pub fn caller() -> Option<ScriptId> {
+    CALLER.with(|c| c.get())
+}
 
 #[derive(Debug, Clone)]
 /// Game events that must affect the engine, and not just the game.
@@ -15,14 +59,14 @@ pub mod interpolate;
 /// This is equivalent to a system call in a regular application.
 pub enum EngineEvent {
     ChangeResolution(u32, u32),
+    SetRenderScale(f32),
+    SetWindowMode(WindowMode),
+    SetMonitor(usize),
+    SetResizable(bool),
     ExitGame,
     SetActiveSpace(String),
-}
-
-/// An event sent between entities (for example, "lclick", "kill_all_zombies", etc)
-pub struct GameEvent {
-    pub name: String,
-    pub args: rhai::Map,
+    SetLanguage(String),
+    Screenshot,
 }
 
 /// The entire game-only state that sits on top of the engine, not caring about
@@ -37,7 +81,12 @@ pub struct GameContext {
     pub game_event_rx: crossbeam_channel::Receiver<GameEvent>,
     pub game_event_tx: crossbeam_channel::Sender<GameEvent>,
     pub camera: interpolate::Interpolated<Camera>,
-    game_event_handlers: Arc<Mutex<HashMap<String, Vec<specs::Entity>>>>,
+    pub events: Arc<events::EventBus>,
+    pub sequences: Arc<sequences::SequenceRunner>,
+    pub ui: Arc<ui::Ui>,
+
+    // Filled in by the engine, which owns the window
+    pub monitors: Arc<RwLock<Vec<MonitorInfo>>>,
 }
 impl GameContext {
     pub fn new() -> GameContext {
@@ -55,7 +104,10 @@ impl GameContext {
                     Vector3::new(0.0, 1.0, 0.0),
                 )
             ),
-            game_event_handlers: Arc::new(Mutex::new(HashMap::new())),
+            events: Arc::new(events::EventBus::new()),
+            sequences: Arc::new(sequences::SequenceRunner::new()),
+            ui: Arc::new(ui::Ui::new()),
+            monitors: Arc::new(RwLock::new(Vec::new())),
         }
     }
 
@@ -63,15 +115,60 @@ impl GameContext {
         Point3::new(v.x, v.y, v.z)
     }
 
-    pub fn get_event_subscribers(&self, name: &str) -> Option<&Vec<specs::Entity>> {
-        self.game_event_handlers.get(name)
-    }
 
     /// Signals the engine to change the resolution
     pub fn change_resolution(self: &mut Arc<GameContext>, x: i64, y: i64) {
         self.engine_event_tx.send(EngineEvent::ChangeResolution(x as u32, y as u32)).unwrap();
     }
 
+    /// Signals the engine to render the scene at a fraction of the window's resolution
+    pub fn set_render_scale(self: &mut Arc<GameContext>, render_scale: f64) {
+        self.engine_event_tx.send(EngineEvent::SetRenderScale(render_scale as f32)).unwrap();
+    }
+
+    /// Signals the engine to switch between "windowed", "borderless" and "fullscreen"
+    pub fn set_window_mode(self: &mut Arc<GameContext>, mode: String) {
+        match mode.parse() {
+            Ok(mode) => self.engine_event_tx.send(EngineEvent::SetWindowMode(mode)).unwrap(),
+            Err(e) => log::err(&e),
+        }
+    }
+
+    /// Signals the engine to move the window to a monitor, by its index in `monitors`
+    pub fn set_monitor(self: &mut Arc<GameContext>, monitor: i64) {
+        self.engine_event_tx.send(EngineEvent::SetMonitor(monitor.max(0) as usize)).unwrap();
+    }
+
+    /// Signals the engine to allow or forbid resizing the window
+    pub fn set_resizable(self: &mut Arc<GameContext>, resizable: bool) {
+        self.engine_event_tx.send(EngineEvent::SetResizable(resizable)).unwrap();
+    }
+
+    /// Every monitor as a map of its name, position, width, height and video modes. Each video mode
+    /// is a map of its width, height and refresh_rate.
+    pub fn monitors(self: &mut Arc<GameContext>) -> rhai::Array {
+        let size = |map: &mut rhai::Map, size: [u32; 2]| {
+            map.insert("width".into(), rhai::Dynamic::from(size[0] as i64));
+            map.insert("height".into(), rhai::Dynamic::from(size[1] as i64));
+        };
+        self.monitors.read().unwrap().iter().map(|monitor| {
+            let modes: rhai::Array = monitor.video_modes.iter().map(|mode| {
+                let mut map = rhai::Map::new();
+                size(&mut map, mode.size);
+                map.insert("refresh_rate".into(), rhai::Dynamic::from(mode.refresh_rate as i64));
+                rhai::Dynamic::from(map)
+            }).collect();
+
+            let mut map = rhai::Map::new();
+            map.insert("name".into(), rhai::Dynamic::from(monitor.name.clone()));
+            map.insert("x".into(), rhai::Dynamic::from(monitor.position[0] as i64));
+            map.insert("y".into(), rhai::Dynamic::from(monitor.position[1] as i64));
+            size(&mut map, monitor.size);
+            map.insert("modes".into(), rhai::Dynamic::from(modes));
+            rhai::Dynamic::from(map)
+        }).collect()
+    }
+
     /// Signals the engine to exit
     pub fn exit_game(self: &mut Arc<GameContext>) {
         self.engine_event_tx.send(EngineEvent::ExitGame).unwrap();
@@ -82,6 +179,17 @@ impl GameContext {
         self.engine_event_tx.send(EngineEvent::SetActiveSpace(space)).unwrap();
     }
 
+    /// Signals the engine to switch language. Subscribers of `language_changed` are
+    /// notified once it's loaded.
+    pub fn set_language(self: &mut Arc<GameContext>, language: String) {
+        self.engine_event_tx.send(EngineEvent::SetLanguage(language)).unwrap();
+    }
+
+    /// Saves the next frame to the screenshots folder.
+    pub fn screenshot(self: &mut Arc<GameContext>) {
+        self.engine_event_tx.send(EngineEvent::Screenshot).unwrap();
+    }
+
     /// Interpolates the camera over a given time
     pub fn camera_smoothstep_lookat(
     self: &mut Arc<GameContext>,
@@ -101,38 +209,321 @@ impl GameContext {
         // );
     }
 
-    /// Tells the game to send `name` events to your entity
-    pub fn subscribe_event(self: &mut Arc<GameContext>, id: specs::Entity, name: String) {
-        self.game_event_handlers.lock().unwrap().entry(name).or_default().push(id);
+    /// Tells the game to send `name` events to your script
+    pub fn subscribe_event(self: &mut Arc<GameContext>, id: ScriptId, name: String) {
+        self.events.subscribe(id, name, 0);
+    }
+
+    /// Like `subscribe_event`, but higher priorities get the event first and may stop it
+    pub fn subscribe_event_with_priority(
+    self: &mut Arc<GameContext>,
+    id: ScriptId,
+    name: String,
+    priority: i64,
+    ) {
+        self.events.subscribe(id, name, priority);
+    }
+
+    /// Tells the game to stop sending `name` events to your script
+    pub fn unsubscribe_event(self: &mut Arc<GameContext>, id: ScriptId, name: String) {
+        self.events.unsubscribe(id, &name);
+    }
+
+    /// Sends an event to all of its subscribers, from outside of the scripts
+    pub fn broadcast_event(&self, name: &str, args: rhai::Map) {
+        self.post_event(String::from(name), args, None, None);
+    }
+
+    fn post_event(&self, name: String, args: rhai::Map, target: Option<ScriptId>, due: Option<events::EventDue>) {
+        let event = GameEvent { name, args, target };
+        match due {
+            Some(due) => self.events.delay(event, due),
+            None => self.game_event_tx.send(event).unwrap(),
+        }
+    }
+
+    /// Sends an event to all of its subscribers
+    pub fn send_event(self: &mut Arc<GameContext>, name: String, args: rhai::Map) {
+        self.post_event(name, args, None, None);
+    }
+
+    /// Sends an event to a single script, even if it isn't subscribed
+    pub fn send_event_to(self: &mut Arc<GameContext>, target: ScriptId, name: String, args: rhai::Map) {
+        self.post_event(name, args, Some(target), None);
+    }
+
+    /// Sends an event to its subscribers after `seconds` have passed
+    pub fn send_event_delayed(self: &mut Arc<GameContext>, name: String, args: rhai::Map, seconds: f64)
+    -> Result<(), Box<rhai::EvalAltResult>> {
+        let due = std::time::Instant::now() + script_delay(seconds)?;
+        self.post_event(name, args, None, Some(events::EventDue::At(due)));
+        Ok(())
+    }
+
+    /// Sends an event to a single script after `seconds` have passed
+    pub fn send_event_to_delayed(
+    self: &mut Arc<GameContext>,
+    target: ScriptId,
+    name: String,
+    args: rhai::Map,
+    seconds: f64,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        let due = std::time::Instant::now() + script_delay(seconds)?;
+        self.post_event(name, args, Some(target), Some(events::EventDue::At(due)));
+        Ok(())
+    }
+
+    /// Sends an event to its subscribers `ticks` engine ticks from now
+    pub fn send_event_after_ticks(self: &mut Arc<GameContext>, name: String, args: rhai::Map, ticks: i64) {
+        let due = self.events.current_tick() + ticks.max(0) as u64;
+        self.post_event(name, args, None, Some(events::EventDue::Tick(due)));
+    }
+
+    /// Sends an event to a single script `ticks` engine ticks from now
+    pub fn send_event_to_after_ticks(
+    self: &mut Arc<GameContext>,
+    target: ScriptId,
+    name: String,
+    args: rhai::Map,
+    ticks: i64,
+    ) {
+        let due = self.events.current_tick() + ticks.max(0) as u64;
+        self.post_event(name, args, Some(target), Some(events::EventDue::Tick(due)));
+    }
+
+    /// Called from an event handler - the remaining subscribers in its space won't get this event
+    pub fn stop_propagation(self: &mut Arc<GameContext>) {
+        self.events.stop_propagation();
+    }
+
+    /// Logs every event dispatch along with its arguments
+    pub fn set_event_trace(self: &mut Arc<GameContext>, enabled: bool) {
+        self.events.set_trace(enabled);
+    }
+
+    /// Runs a sequence on your entity, replacing its running sequence of the same name
+    pub fn start_sequence(
+    self: &mut Arc<GameContext>,
+    id: ScriptId,
+    name: String,
+    sequence: sequences::Sequence,
+    ) {
+        self.sequences.start(id, name, sequence);
+    }
+
+    /// Stops a sequence of your entity. Its remaining steps never run.
+    pub fn cancel_sequence(self: &mut Arc<GameContext>, id: ScriptId, name: String) {
+        self.sequences.cancel(id, &name);
+    }
+
+    /// Stops every sequence of your entity
+    pub fn cancel_all_sequences(self: &mut Arc<GameContext>, id: ScriptId) {
+        self.sequences.cancel_all(id);
     }
 
-    /// Tells the game to stop sending `name` events to your entity
-    pub fn unsubscribe_event(self: &mut Arc<GameContext>, id: specs::Entity, name: String) {
-        if let Some(subs) = self.game_event_handlers.lock().unwrap().get_mut(&name) {
-            subs.retain(|&ent| ent != id);
+    fn with_ui<F: FnOnce(&mut ui::UiTree) -> anyhow::Result<()>>(&self, f: F) {
+        if let Err(e) = f(&mut self.ui.lock()) {
+            log::err(&e);
         }
     }
+
+    /// Adds a "panel", "label", "button", "checkbox", "slider", "dropdown" or "list" widget
+    /// under `parent`, or at the top level if it's "". Its `ui_click` and `ui_changed`
+    /// events go to the script that added it, in that script's space.
+    pub fn ui_add(self: &mut Arc<GameContext>, id: String, kind: String, parent: String) {
+        self.with_ui(|ui| {
+            let kind = ui::WidgetKind::from_name(&kind)
+                .ok_or_else(|| anyhow!("Unknown UI widget kind {}", kind))?;
+            let parent = if parent.is_empty() { None } else { Some(parent.as_str()) };
+            ui.add(&id, kind, parent, caller())
+        });
+    }
+
+    /// Removes a widget along with its children
+    pub fn ui_remove(self: &mut Arc<GameContext>, id: String) {
+        self.with_ui(|ui| ui.remove(&id));
+    }
+
+    pub fn ui_set_text(self: &mut Arc<GameContext>, id: String, text: String) {
+        self.with_ui(|ui| ui.update(&id, |w| {
+            w.text = text;
+            w.localized = false;
+        }));
+    }
+
+    /// Shows the translation of a localization key, which follows language changes
+    pub fn ui_set_text_key(self: &mut Arc<GameContext>, id: String, key: String) {
+        self.with_ui(|ui| ui.update(&id, |w| {
+            w.text = key;
+            w.localized = true;
+        }));
+    }
+
+    /// Places the widget at a point of its parent ("top_left", "center", "bottom_right"...),
+    /// moved by an offset in pixels
+    pub fn ui_set_anchor(self: &mut Arc<GameContext>, id: String, anchor: String, x: f64, y: f64) {
+        self.with_ui(|ui| {
+            let anchor = anchor.parse()?;
+            ui.update(&id, |w| {
+                w.anchor = anchor;
+                w.offset = [x as f32, y as f32];
+            })
+        });
+    }
+
+    /// Size in pixels. A size of 0 fills the parent.
+    pub fn ui_set_size(self: &mut Arc<GameContext>, id: String, width: f64, height: f64) {
+        self.with_ui(|ui| ui.update(&id, |w| w.size = [width as f32, height as f32]));
+    }
+
+    /// Places the children "free"ly by their anchors, or stacks them "vertical"ly or "horizontal"ly
+    pub fn ui_set_layout(self: &mut Arc<GameContext>, id: String, layout: String, spacing: f64, padding: f64) {
+        self.with_ui(|ui| {
+            let layout = layout.parse()?;
+            ui.update(&id, |w| {
+                w.layout = layout;
+                w.spacing = spacing as f32;
+                w.padding = padding as f32;
+            })
+        });
+    }
+
+    pub fn ui_set_visible(self: &mut Arc<GameContext>, id: String, visible: bool) {
+        self.with_ui(|ui| ui.update(&id, |w| w.visible = visible));
+    }
+
+    pub fn ui_set_enabled(self: &mut Arc<GameContext>, id: String, enabled: bool) {
+        self.with_ui(|ui| ui.update(&id, |w| w.enabled = enabled));
+    }
+
+    /// 1 or 0 for checkboxes, the value of sliders and the selected index of dropdowns and lists
+    pub fn ui_get_value(self: &mut Arc<GameContext>, id: String) -> f64 {
+        self.ui.lock().value(&id).unwrap_or_else(|e| {
+            log::err(&e);
+            0.0
+        })
+    }
+
+    pub fn ui_set_value(self: &mut Arc<GameContext>, id: String, value: f64) {
+        self.with_ui(|ui| ui.set_value(&id, value));
+    }
+
+    pub fn ui_set_range(self: &mut Arc<GameContext>, id: String, min: f64, max: f64, step: f64) {
+        self.with_ui(|ui| match &mut ui.get_widget_mut(&id)?.kind {
+            ui::WidgetKind::Slider { value, min: old_min, max: old_max, step: old_step } => {
+                *old_min = min.min(max);
+                *old_max = max.max(min);
+                *old_step = step.max(0.0);
+                *value = utils::clamp(*value, *old_min, *old_max);
+                Ok(())
+            },
+            _ => Err(anyhow!("UI widget {} isn't a slider", id)),
+        });
+    }
+
+    /// The options of a dropdown or the items of a list
+    pub fn ui_set_options(self: &mut Arc<GameContext>, id: String, options: rhai::Array) {
+        self.with_ui(|ui| ui.set_options(&id, options.into_iter().map(|o| o.to_string()).collect()));
+    }
+
+    pub fn ui_focus(self: &mut Arc<GameContext>, id: String) {
+        self.with_ui(|ui| ui.set_focus(&id));
+    }
+
+    /// Starts a new engine tick, releasing the delayed events that are now due.
+    pub fn next_tick(&self) {
+        for event in self.events.next_tick() {
+            self.game_event_tx.send(event).unwrap();
+        }
+    }
+}
+
+/// A delay a script asked for. Negative delays are none at all, and ones the clock can't
+/// count to fail the script instead of the engine.
+pub fn script_delay(seconds: f64) -> Result<std::time::Duration, Box<rhai::EvalAltResult>> {
+    if seconds.is_nan() || seconds > consts::SCRIPT_MAX_DELAY_SECONDS {
+        return Err(format!("A delay can be at most {} seconds, not {}",
+            consts::SCRIPT_MAX_DELAY_SECONDS, seconds).into());
+    }
+    Ok(std::time::Duration::from_secs_f64(seconds.max(0.0)))
 }
 
-pub fn new_engine() -> Engine<'static> {
+pub fn new_engine() -> Engine {
+    new_engine_with_modules(modules::ScriptModuleResolver::new())
+}
+
+/// Creates an engine whose `import`s go through the given resolver, so the caller
+/// can invalidate its cached modules later.
+pub fn new_engine_with_modules(resolver: modules::ScriptModuleResolver) -> Engine {
     let mut engine = Engine::new();
+    limits::apply(&mut engine);
+    engine.set_module_resolver(resolver);
 
     engine.register_fn("error", basic_funcs::error);
     engine.register_fn("warning", basic_funcs::warning);
     engine.register_fn("info", basic_funcs::info);
+    engine.register_fn("tr", basic_funcs::tr);
+    engine.register_fn("tr", basic_funcs::tr_with_args);
     engine.register_fn("rand_range", basic_funcs::rand_range as fn(i64, i64) -> i64);
     engine.register_fn("rand_range", basic_funcs::rand_range as fn(f64, f64) -> f64);
 
     engine.register_type::<Arc<GameContext>>();
     engine.register_fn("change_resolution", GameContext::change_resolution);
+    engine.register_fn("set_render_scale", GameContext::set_render_scale);
+    engine.register_fn("set_window_mode", GameContext::set_window_mode);
+    engine.register_fn("set_monitor", GameContext::set_monitor);
+    engine.register_fn("set_resizable", GameContext::set_resizable);
+    engine.register_fn("monitors", GameContext::monitors);
     engine.register_fn("exit_game", GameContext::exit_game);
     engine.register_fn("camera_smoothstep_lookat", GameContext::camera_smoothstep_lookat);
     engine.register_fn("set_active_space", GameContext::set_active_space);
+    engine.register_fn("set_language", GameContext::set_language);
+    engine.register_fn("screenshot", GameContext::screenshot);
+    engine.register_fn("current_language", basic_funcs::current_language);
+    engine.register_fn("available_languages", basic_funcs::available_languages);
     engine.register_fn("subscribe_event", GameContext::subscribe_event);
     engine.register_fn("unsubscribe_event", GameContext::unsubscribe_event);
+    engine.register_fn("subscribe_event_with_priority", GameContext::subscribe_event_with_priority);
+    engine.register_fn("send_event", GameContext::send_event);
+    engine.register_fn("send_event_to", GameContext::send_event_to);
+    engine.register_fn("send_event_delayed", GameContext::send_event_delayed);
+    engine.register_fn("send_event_to_delayed", GameContext::send_event_to_delayed);
+    engine.register_fn("send_event_after_ticks", GameContext::send_event_after_ticks);
+    engine.register_fn("send_event_to_after_ticks", GameContext::send_event_to_after_ticks);
+    engine.register_fn("stop_propagation", GameContext::stop_propagation);
+    engine.register_fn("set_event_trace", GameContext::set_event_trace);
+
+    engine.register_fn("ui_add", GameContext::ui_add);
+    engine.register_fn("ui_remove", GameContext::ui_remove);
+    engine.register_fn("ui_set_text", GameContext::ui_set_text);
+    engine.register_fn("ui_set_text_key", GameContext::ui_set_text_key);
+    engine.register_fn("ui_set_anchor", GameContext::ui_set_anchor);
+    engine.register_fn("ui_set_size", GameContext::ui_set_size);
+    engine.register_fn("ui_set_layout", GameContext::ui_set_layout);
+    engine.register_fn("ui_set_visible", GameContext::ui_set_visible);
+    engine.register_fn("ui_set_enabled", GameContext::ui_set_enabled);
+    engine.register_fn("ui_get_value", GameContext::ui_get_value);
+    engine.register_fn("ui_set_value", GameContext::ui_set_value);
+    engine.register_fn("ui_set_range", GameContext::ui_set_range);
+    engine.register_fn("ui_set_options", GameContext::ui_set_options);
+    engine.register_fn("ui_focus", GameContext::ui_focus);
+
+    engine.register_fn("start_sequence", GameContext::start_sequence);
+    engine.register_fn("cancel_sequence", GameContext::cancel_sequence);
+    engine.register_fn("cancel_all_sequences", GameContext::cancel_all_sequences);
+
+    engine.register_type::<ScriptId>();
+
+    engine.register_type::<sequences::Sequence>();
+    engine.register_fn("sequence", sequences::Sequence::new);
+    // Not "call", which rhai keeps for calling function pointers
+    engine.register_fn("then_call", sequences::Sequence::call);
+    engine.register_fn("wait", sequences::Sequence::wait);
+    engine.register_fn("wait_for_event", sequences::Sequence::wait_for_event);
 
     engine.register_type::<Vector3<f32>>();
     engine.register_fn("vec3", basic_funcs::vec3);
+    math::register(&mut engine);
 
     engine
 }
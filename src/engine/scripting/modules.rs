@@ -0,0 +1,248 @@
+use crate::engine::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use rhai::{Engine, EvalAltResult, Module, ModuleResolver, Position, Scope};
+
+/// Turns a script name relative to the scripts folder ("lib/ui", "mapgen/names.rhai")
+/// into a path on disk. Every component is sanitized, so scripts can't escape the folder.
+pub fn script_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::from(consts::SCRIPTS_PATH);
+    for part in name.split(|c| c == '/' || c == '\\') {
+        if part.is_empty() || part == "." || part == ".." {
+            return None;
+        }
+        path.push(sanitize_filename::sanitize(part));
+    }
+
+    if path.extension().is_none() {
+        path.set_extension(consts::SCRIPT_FILE_EXTENSION);
+    }
+    Some(path)
+}
+
+/// The name a script is known by in the module cache - its path under the scripts
+/// folder without the extension. "lib/ui" and "lib/ui.rhai" are the same module.
+pub fn module_key(name: &str) -> String {
+    let name = name.replace('\\', "/");
+    match name.strip_suffix(&format!(".{}", consts::SCRIPT_FILE_EXTENSION)) {
+        Some(stem) => String::from(stem),
+        None => name,
+    }
+}
+
+fn modified_time(key: &str) -> Option<SystemTime> {
+    script_path(key)
+        .and_then(|p| std::fs::metadata(p).ok())
+        .and_then(|md| md.modified().ok())
+}
+
+/// Finds the modules a script imports, i.e. every `import "name"` statement in its source.
+/// Comments and string literals are skipped, so only real import statements count.
+pub fn find_imports(source: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    // Set after the `import` keyword, until the next token
+    let mut after_import = false;
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '/' if chars.peek().map(|&(_, n)| n) == Some('/') => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '/' if chars.peek().map(|&(_, n)| n) == Some('*') => {
+                // Block comments nest
+                chars.next();
+                let mut depth = 1;
+                let mut prev = '\0';
+                for (_, c) in chars.by_ref() {
+                    if prev == '/' && c == '*' {
+                        depth += 1;
+                        prev = '\0';
+                    } else if prev == '*' && c == '/' {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                        prev = '\0';
+                    } else {
+                        prev = c;
+                    }
+                }
+                continue;
+            }
+            '"' | '\'' => {
+                let mut literal = String::new();
+                while let Some((_, l)) = chars.next() {
+                    match l {
+                        '\\' => { chars.next(); },
+                        l if l == c => break,
+                        l => literal.push(l),
+                    }
+                }
+                if after_import && c == '"' {
+                    result.push(module_key(&literal));
+                }
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut end = idx + c.len_utf8();
+                while let Some(&(i, n)) = chars.peek() {
+                    if !(n.is_alphanumeric() || n == '_') {
+                        break;
+                    }
+                    end = i + n.len_utf8();
+                    chars.next();
+                }
+                after_import = &source[idx..end] == "import";
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            _ => (),
+        }
+        after_import = false;
+    }
+
+    result
+}
+
+/// Resolves `import` statements against the scripts folder and caches the compiled modules.
+/// Clones share the same cache, so the scripting system can invalidate modules the engine uses.
+#[derive(Clone)]
+pub struct ScriptModuleResolver {
+    cache: Arc<Mutex<HashMap<String, Arc<Module>>>>,
+
+    // module -> scripts and modules that import it
+    dependents: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+
+    // module -> modification time of the file it was last loaded from
+    mtimes: Arc<Mutex<HashMap<String, SystemTime>>>,
+}
+
+impl ScriptModuleResolver {
+    pub fn new() -> ScriptModuleResolver {
+        ScriptModuleResolver {
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            dependents: Arc::new(Mutex::new(HashMap::new())),
+            mtimes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Remembers the modification time of a script as it's loaded, so edits made
+    /// any time after that are picked up by `take_modified`.
+    pub fn record_mtime(&self, name: &str) {
+        let key = module_key(name);
+        if let Some(modified) = modified_time(&key) {
+            self.mtimes.lock().unwrap().insert(key, modified);
+        }
+    }
+
+    /// Whether a script changed on disk since it was loaded or last reported as modified.
+    pub fn take_modified(&self, name: &str) -> bool {
+        let key = module_key(name);
+        let modified = match modified_time(&key) {
+            Some(t) => t,
+            None => return false,
+        };
+        match self.mtimes.lock().unwrap().insert(key, modified) {
+            Some(previous) => previous != modified,
+            None => false,
+        }
+    }
+
+    /// Remembers that `importer` depends on everything it imports, so it can be
+    /// invalidated when one of those changes.
+    pub fn track_imports(&self, importer: &str, source: &str) {
+        let importer = module_key(importer);
+        let mut dependents = self.dependents.lock().unwrap();
+        for deps in dependents.values_mut() {
+            deps.remove(&importer);
+        }
+        for import in find_imports(source) {
+            dependents.entry(import).or_default().insert(importer.clone());
+        }
+    }
+
+    /// All the modules imported by some script so far.
+    pub fn imported_modules(&self) -> Vec<String> {
+        self.dependents.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Drops a module and everything that transitively imports it from the cache.
+    /// Returns the keys of all the invalidated scripts, including `name` itself.
+    pub fn invalidate(&self, name: &str) -> HashSet<String> {
+        let mut invalidated = HashSet::new();
+        let mut pending = vec![module_key(name)];
+        {
+            let dependents = self.dependents.lock().unwrap();
+            while let Some(key) = pending.pop() {
+                if !invalidated.insert(key.clone()) {
+                    continue;
+                }
+                if let Some(deps) = dependents.get(&key) {
+                    pending.extend(deps.iter().cloned());
+                }
+            }
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        for key in invalidated.iter() {
+            cache.remove(key);
+        }
+        invalidated
+    }
+
+    fn load(&self, engine: &Engine, key: &str, path: &Path, pos: Position)
+    -> Result<Module, Box<EvalAltResult>> {
+        self.record_mtime(key);
+        let source = std::fs::read_to_string(path).map_err(|_|
+            Box::new(EvalAltResult::ErrorModuleNotFound(String::from(key), pos))
+        )?;
+        self.track_imports(key, &source);
+
+        let ast = engine.compile(&source).map_err(|e|
+            Box::new(EvalAltResult::ErrorInModule(String::from(key), e.into(), pos))
+        )?;
+        Module::eval_ast_as_new(Scope::new(), &ast, engine).map_err(|e|
+            Box::new(EvalAltResult::ErrorInModule(String::from(key), e, pos))
+        )
+    }
+}
+
+impl ModuleResolver for ScriptModuleResolver {
+    fn resolve(&self, engine: &Engine, _source: Option<&str>, path: &str, pos: Position)
+    -> Result<Arc<Module>, Box<EvalAltResult>> {
+        let key = module_key(path);
+        if let Some(module) = self.cache.lock().unwrap().get(&key) {
+            return Ok(module.clone());
+        }
+
+        let file = script_path(&key).ok_or_else(||
+            Box::new(EvalAltResult::ErrorModuleNotFound(String::from(path), pos))
+        )?;
+
+        // The cache is unlocked while loading, since the module may import others.
+        let module = Arc::new(self.load(engine, &key, &file, pos)?);
+        self.cache.lock().unwrap().insert(key, module.clone());
+        Ok(module)
+    }
+}
+
+#[test]
+fn imports_in_comments_and_strings_are_ignored() {
+    let source = r#"
+        import "lib/ui" as ui;
+        // import "commented";
+        /* import "blocked" /* import "nested" */ import "still_blocked" */
+        let s = "import \"quoted\"";
+        let reimport = 1;
+        import
+            "mapgen/names.rhai" as names;
+    "#;
+    assert_eq!(find_imports(source), vec![String::from("lib/ui"), String::from("mapgen/names")]);
+}
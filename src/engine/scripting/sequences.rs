@@ -0,0 +1,179 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use super::{GameEvent, ScriptId};
+
+#[derive(Debug, Clone)]
+pub enum SequenceStep {
+    /// Calls `fn name(self)` on the owning script
+    Call(String),
+    Wait(Duration),
+    WaitForEvent(String),
+}
+
+/// A list of steps a script builds and hands to the engine, which then runs it over
+/// the following ticks. This is how scripts write multi-step behaviour without keeping
+/// a state machine in `object_self`:
+///
+/// ```ignore
+/// let intro = sequence();
+/// intro.then_call("pan_to_galaxy");
+/// intro.wait(2.0);
+/// intro.wait_for_event("lclick");
+/// intro.then_call("show_tutorial");
+/// game.start_sequence(self.id, "intro", intro);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Sequence {
+    steps: Vec<SequenceStep>,
+}
+
+impl Sequence {
+    pub fn new() -> Sequence {
+        Sequence { steps: Vec::new() }
+    }
+
+    pub fn call(&mut self, func: String) {
+        self.steps.push(SequenceStep::Call(func));
+    }
+
+    /// Fails the script for delays `script_delay` rejects, rather than when the step is reached
+    pub fn wait(&mut self, seconds: f64) -> Result<(), Box<rhai::EvalAltResult>> {
+        self.steps.push(SequenceStep::Wait(super::script_delay(seconds)?));
+        Ok(())
+    }
+
+    pub fn wait_for_event(&mut self, name: String) {
+        self.steps.push(SequenceStep::WaitForEvent(name));
+    }
+}
+
+#[derive(Debug)]
+enum WaitingFor {
+    Time(Instant),
+    Event(String),
+}
+
+#[derive(Debug)]
+struct RunningSequence {
+    owner: ScriptId,
+    name: String,
+    steps: Vec<SequenceStep>,
+    current: usize,
+    waiting: Option<WaitingFor>,
+}
+
+/// A step function that a sequence reached and should now be called on its owner
+#[derive(Debug)]
+pub struct SequenceCall {
+    pub owner: ScriptId,
+    pub sequence: String,
+    pub func: String,
+}
+
+/// Keeps track of every running sequence. Sequences only store the step index and the
+/// names of the functions they call, so they keep running across hot reloads as long as
+/// those functions still exist.
+#[derive(Debug)]
+pub struct SequenceRunner {
+    running: Mutex<Vec<RunningSequence>>,
+
+    // Sequences cancelled since the last advance, so their pending calls can be skipped
+    cancelled: Mutex<Vec<(ScriptId, String)>>,
+}
+
+impl SequenceRunner {
+    pub fn new() -> SequenceRunner {
+        SequenceRunner {
+            running: Mutex::new(Vec::new()),
+            cancelled: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Starts a sequence, replacing any running sequence of the same owner and name.
+    pub fn start(&self, owner: ScriptId, name: String, sequence: Sequence) {
+        self.cancelled.lock().unwrap().retain(|(o, n)| *o != owner || *n != name);
+        let mut running = self.running.lock().unwrap();
+        running.retain(|s| s.owner != owner || s.name != name);
+        running.push(RunningSequence {
+            owner,
+            name,
+            steps: sequence.steps,
+            current: 0,
+            waiting: None,
+        });
+    }
+
+    pub fn cancel(&self, owner: ScriptId, name: &str) {
+        self.cancelled.lock().unwrap().push((owner, String::from(name)));
+        self.running.lock().unwrap().retain(|s| s.owner != owner || s.name != name);
+    }
+
+    pub fn cancel_all(&self, owner: ScriptId) {
+        let mut running = self.running.lock().unwrap();
+        let mut cancelled = self.cancelled.lock().unwrap();
+        for s in running.iter().filter(|s| s.owner == owner) {
+            cancelled.push((owner, s.name.clone()));
+        }
+        running.retain(|s| s.owner != owner);
+    }
+
+    /// Drops the sequences whose owner doesn't pass `keep`, e.g. because it was deleted.
+    pub fn retain_owners<F: Fn(ScriptId) -> bool>(&self, keep: F) {
+        self.running.lock().unwrap().retain(|s| keep(s.owner));
+    }
+
+    /// Whether no sequence is running at all
+    pub fn is_idle(&self) -> bool {
+        self.running.lock().unwrap().is_empty()
+    }
+
+    /// Whether a call returned by the last `advance` should still be made.
+    pub fn is_cancelled(&self, call: &SequenceCall) -> bool {
+        self.cancelled.lock().unwrap().iter().any(|(o, n)| *o == call.owner && *n == call.sequence)
+    }
+
+    /// Resumes sequences waiting for this event. Targeted events only resume their target.
+    pub fn notify_event(&self, event: &GameEvent) {
+        for seq in self.running.lock().unwrap().iter_mut() {
+            if let Some(WaitingFor::Event(name)) = &seq.waiting {
+                if *name == event.name && event.target.map_or(true, |t| t == seq.owner) {
+                    seq.waiting = None;
+                }
+            }
+        }
+    }
+
+    /// Moves every sequence forward until it has to wait, and returns the functions
+    /// it passed on the way, in order.
+    pub fn advance(&self) -> Vec<SequenceCall> {
+        let now = Instant::now();
+        let mut calls = Vec::new();
+        self.cancelled.lock().unwrap().clear();
+        let mut running = self.running.lock().unwrap();
+
+        for seq in running.iter_mut() {
+            if let Some(WaitingFor::Time(until)) = seq.waiting {
+                if until <= now {
+                    seq.waiting = None;
+                }
+            }
+
+            while seq.waiting.is_none() && seq.current < seq.steps.len() {
+                match &seq.steps[seq.current] {
+                    SequenceStep::Call(func) => calls.push(SequenceCall {
+                        owner: seq.owner,
+                        sequence: seq.name.clone(),
+                        func: func.clone(),
+                    }),
+                    SequenceStep::Wait(duration) => seq.waiting = Some(WaitingFor::Time(now + *duration)),
+                    SequenceStep::WaitForEvent(name) => seq.waiting = Some(WaitingFor::Event(name.clone())),
+                }
+                seq.current += 1;
+            }
+        }
+
+        // Sequences that aren't waiting on anything ran their last step
+        running.retain(|s| s.waiting.is_some() || s.current < s.steps.len());
+        calls
+    }
+}
@@ -0,0 +1,101 @@
+use specs::ReadStorage;
+use nalgebra::{Matrix4, Point3, Vector3};
+use crate::engine::components::{LightComponent, LightKind, TransformComponent};
+
+const LIGHT_KIND_POINT: i32 = 0;
+const LIGHT_KIND_DIRECTIONAL: i32 = 1;
+const LIGHT_KIND_SPOT: i32 = 2;
+
+/// A light as the renderer sees it, in world space
+#[derive(Debug, Clone, Copy)]
+pub struct LightInstance {
+    pub kind: i32,
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+
+    // Color multiplied by intensity
+    pub color: [f32; 3],
+    pub range: f32,
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+    pub casts_shadows: bool,
+}
+
+impl LightInstance {
+    /// Places a light with its entity's transform. Lights point down their -Z axis.
+    pub fn from_component(light: &LightComponent, transform: &Matrix4<f32>) -> LightInstance {
+        let position = transform.transform_point(&Point3::origin());
+        let direction = transform.transform_vector(&-Vector3::z())
+            .try_normalize(std::f32::EPSILON)
+            .unwrap_or_else(|| -Vector3::z());
+        let (kind, cos_inner, cos_outer) = match light.kind {
+            LightKind::Point => (LIGHT_KIND_POINT, -1.0, -1.0),
+            LightKind::Directional => (LIGHT_KIND_DIRECTIONAL, -1.0, -1.0),
+            LightKind::Spot { inner_angle, outer_angle } =>
+                (LIGHT_KIND_SPOT, inner_angle.cos(), outer_angle.cos()),
+        };
+
+        LightInstance {
+            kind,
+            position: position.coords.into(),
+            direction: direction.into(),
+            color: [
+                light.color[0] * light.intensity,
+                light.color[1] * light.intensity,
+                light.color[2] * light.intensity,
+            ],
+            range: light.range,
+            cos_inner,
+            cos_outer,
+            casts_shadows: light.casts_shadows,
+        }
+    }
+
+    /// Whether this light has no position, so it reaches everywhere
+    pub fn is_directional(&self) -> bool {
+        self.kind == LIGHT_KIND_DIRECTIONAL
+    }
+
+    pub fn is_point(&self) -> bool {
+        self.kind == LIGHT_KIND_POINT
+    }
+}
+
+/// Collects the visible lights of every space into the next frame. When there are more
+/// than the renderer can shade, it picks the ones that matter most.
+pub struct LightSystem {
+    next_frame_lights: Vec<LightInstance>,
+}
+
+impl LightSystem {
+    pub fn new() -> LightSystem {
+        LightSystem {
+            next_frame_lights: Vec::new(),
+        }
+    }
+
+    pub fn get_lights_and_flush(&mut self) -> Vec<LightInstance> {
+        let desired_capacity = self.next_frame_lights.capacity();
+        std::mem::replace(&mut self.next_frame_lights, Vec::with_capacity(desired_capacity))
+    }
+}
+
+impl<'a> specs::System<'a> for LightSystem {
+    type SystemData = (
+        ReadStorage<'a, LightComponent>,
+        ReadStorage<'a, TransformComponent>,
+    );
+
+    fn run(&mut self, (lights, transforms): Self::SystemData) {
+        use specs::Join;
+
+        for (light, trans) in (&lights, transforms.maybe()).join() {
+            if !light.visible {
+                continue;
+            }
+
+            let transform = trans.map_or_else(Matrix4::identity, |t| t.transform);
+            self.next_frame_lights.push(LightInstance::from_component(light, &transform));
+        }
+    }
+}
@@ -10,3 +10,7 @@ mod preload;
 pub use preload::PreloadSystem;
 mod keyboard;
 pub use keyboard::{KeyboardSystem, KeyboardState};
+mod lights;
+pub use lights::{LightSystem, LightInstance};
+mod text;
+pub use text::{TextSystem, TextInstance};
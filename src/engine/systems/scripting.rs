@@ -1,27 +1,53 @@
 use crate::engine::prelude::*;
 use std::sync::Arc;
-use std::collections::{HashMap, HashSet};
-use specs::WriteStorage;
+use std::collections::{HashMap, HashSet, VecDeque};
+use specs::{WriteStorage, ReadExpect, Entities, Entity};
 use crate::engine::components::{ScriptingComponent, MouseComponent, KeyboardComponent};
-use crate::engine::scripting::{new_engine, GameContext};
-use rhai::{Engine, Scope, AST};
+use crate::engine::scripting::{self, new_engine_with_modules, GameContext, GameEvent, ScriptId, SpaceId};
+use crate::engine::scripting::modules::{self, ScriptModuleResolver};
+use crate::engine::scripting::sequences::SequenceCall;
+use rhai::{Engine, Scope, AST, FuncArgs};
 
+/// Runs entity scripts. The engine calls these functions when a script defines them:
+/// `on_spawn()` once, returning the script's `self`, `on_kb(self, key, pressed)` for its
+/// `KeyboardComponent` keys, `lclick(self)` for its `MouseComponent`, and for every event
+/// the entity gets, `fn <event name>(self, args)`. Each returns the new `self`.
 pub struct ScriptingSystem {
-    engine: Engine<'static>,
+    engine: Engine,
     scope: Scope<'static>,
     loaded_scripts: HashMap<String, AST>,
+    script_revisions: HashMap<String, u32>,
     bad_scripts: HashSet<String>,
+    module_resolver: ScriptModuleResolver,
+
+    last_reload_check: std::time::Instant,
+
+    // Sequence steps reached this tick. Each space runs the ones its entities own.
+    sequence_calls: Vec<SequenceCall>,
+
+    // Events waiting to be dispatched in each space, along with their recipients there
+    deliveries: HashMap<SpaceId, VecDeque<(GameEvent, Vec<Entity>)>>,
+
+    // Spaces the system ran on since the tick started
+    ran_spaces: HashSet<SpaceId>,
 }
 
 impl ScriptingSystem {
     pub fn new() -> ScriptingSystem {
         let mut scope = Scope::new();
         scope.push("game", Arc::new(GameContext::new()));
+        let module_resolver = ScriptModuleResolver::new();
         ScriptingSystem {
-            engine: new_engine(),
+            engine: new_engine_with_modules(module_resolver.clone()),
             scope,
             loaded_scripts: HashMap::new(),
+            script_revisions: HashMap::new(),
             bad_scripts: HashSet::new(),
+            module_resolver,
+            last_reload_check: std::time::Instant::now(),
+            sequence_calls: Vec::new(),
+            deliveries: HashMap::new(),
+            ran_spaces: HashSet::new(),
         }
     }
 
@@ -29,20 +55,147 @@ impl ScriptingSystem {
         self.scope.get_value("game").unwrap()
     }
 
+    /// Must be called once per engine tick, before running the system on any space.
+    pub fn new_tick(&mut self) {
+        let context = self.get_game_context();
+        context.next_tick();
+
+        // Every space with scripts runs each tick, so one that didn't is gone along with
+        // its events and sequences
+        let ran_spaces = std::mem::replace(&mut self.ran_spaces, HashSet::new());
+        self.deliveries.retain(|space, _| ran_spaces.contains(space));
+        context.sequences.retain_owners(|owner| ran_spaces.contains(&owner.space));
+        self.sequence_calls = context.sequences.advance();
+    }
+
+    /// Moves the events sent so far to the spaces of their recipients. A space dispatches
+    /// its share when it runs, so events between spaces arrive on the next tick at most.
+    fn route_events(&mut self, context: &GameContext) {
+        while let Ok(ev) = context.game_event_rx.try_recv() {
+            let recipients = context.events.recipients(&ev);
+            context.events.trace(&ev, &recipients);
+            context.sequences.notify_event(&ev);
+
+            // Recipients keep their dispatch order within each space
+            let mut by_space: Vec<(SpaceId, Vec<Entity>)> = Vec::new();
+            for id in recipients {
+                match by_space.iter_mut().find(|(space, _)| *space == id.space) {
+                    Some((_, ents)) => ents.push(id.entity),
+                    None => by_space.push((id.space, vec![id.entity])),
+                }
+            }
+            for (space, ents) in by_space {
+                self.deliveries.entry(space).or_default().push_back((ev.clone(), ents));
+            }
+        }
+    }
+
     pub fn add_script(&mut self, path: &str) -> bool {
-        match self.engine.compile_file(
-            std::path::PathBuf::from("./scripts/").join(&sanitize_filename::sanitize(path))) {
+        self.module_resolver.record_mtime(path);
+        let source = match modules::script_path(path).map(std::fs::read_to_string) {
+            Some(Ok(source)) => source,
+            _ => {
+                log::error(&format!("Failed to read script {}", path));
+                self.bad_scripts.insert(String::from(path));
+                return false;
+            }
+        };
+        self.module_resolver.track_imports(path, &source);
+
+        match self.engine.compile(&source) {
             Ok(ast) => {
                 self.loaded_scripts.insert(String::from(path), ast);
+                *self.script_revisions.entry(String::from(path)).or_insert(0) += 1;
                 true
             },
             Err(e) => {
-                log::error(&format!("Failed to compile script {}: {}", path, e));
+                // rhai reserves `spawn`, which scripts used to define as their entry point
+                let hint = match &*e.0 {
+                    rhai::ParseErrorType::Reserved(word) if word == "spawn" => ", rename spawn() to on_spawn()",
+                    _ => "",
+                };
+                log::error(&format!("Failed to compile script {}: {}{}", path, e, hint));
                 self.bad_scripts.insert(String::from(path));
                 false
             },
         }
     }
+
+    /// Recompiles scripts whose files changed on disk, along with every script that
+    /// imports them. Only checks the disk once every SCRIPT_HOT_RELOAD_INTERVAL_SECONDS.
+    pub fn hot_reload(&mut self) {
+        if self.last_reload_check.elapsed().as_secs_f32() < consts::SCRIPT_HOT_RELOAD_INTERVAL_SECONDS {
+            return;
+        }
+        self.last_reload_check = std::time::Instant::now();
+
+        let mut watched: HashSet<String> = self.loaded_scripts.keys()
+            .chain(self.bad_scripts.iter())
+            .map(|p| modules::module_key(p))
+            .collect();
+        watched.extend(self.module_resolver.imported_modules());
+
+        let mut invalidated = HashSet::new();
+        for key in watched {
+            if self.module_resolver.take_modified(&key) {
+                invalidated.extend(self.module_resolver.invalidate(&key));
+            }
+        }
+
+        let to_reload: Vec<String> = self.loaded_scripts.keys()
+            .chain(self.bad_scripts.iter())
+            .filter(|p| invalidated.contains(&modules::module_key(p)))
+            .cloned()
+            .collect();
+        for path in to_reload {
+            log::info(&format!("Reloading script {}", path));
+            self.loaded_scripts.remove(&path);
+            self.bad_scripts.remove(&path);
+            self.add_script(&path);
+        }
+    }
+}
+
+/// Calls a script function under the engine's execution limits, if the script defines it.
+/// Failures are counted against the entity, which is disabled after too many of them.
+fn call_script<A: FuncArgs>(
+    engine: &mut Engine,
+    scope: &mut Scope<'static>,
+    ast: &AST,
+    script: &mut ScriptingComponent,
+    id: ScriptId,
+    func: &str,
+    args: A,
+) -> Option<rhai::Map> {
+    // Callbacks are optional, so a script that doesn't define one isn't failing
+    if !ast.iter_functions().any(|f| f.name == func) {
+        return None;
+    }
+
+    scripting::limits::begin_call();
+    scripting::set_caller(Some(id));
+    let result = engine.call_fn::<rhai::Map>(scope, ast, func, args);
+    scripting::set_caller(None);
+    scripting::limits::end_call();
+
+    match result {
+        Ok(new_self) => Some(new_self),
+        Err(e) => {
+            let line = e.position().line().map_or(String::from("?"), |l| l.to_string());
+            let message = format!("{} failed in {}:{} (entity {:?}): {}",
+                func, script.path, line, id.entity, e);
+            log::error(&message);
+            script.last_error = Some(message);
+
+            script.failures += 1;
+            if script.failures >= consts::SCRIPT_MAX_FAILURES {
+                script.disabled = true;
+                log::error(&format!("Disabling {} on entity {:?} after {} failures (last at line {})",
+                    script.path, id.entity, script.failures, line));
+            }
+            None
+        }
+    }
 }
 
 impl<'a> specs::System<'a> for ScriptingSystem {
@@ -50,16 +203,36 @@ impl<'a> specs::System<'a> for ScriptingSystem {
         WriteStorage<'a, ScriptingComponent>,
         WriteStorage<'a, MouseComponent>,
         WriteStorage<'a, KeyboardComponent>,
+        Entities<'a>,
+        ReadExpect<'a, SpaceId>,
     );
 
-    fn run(&mut self, (mut scripts, mut mouses, mut keybs): Self::SystemData) {
+    fn run(&mut self, (mut scripts, mut mouses, mut keybs, ents, space): Self::SystemData) {
         use specs::Join;
+        let space = *space;
+        self.ran_spaces.insert(space);
 
-        for (script, mouse, keyb) in (
+        for (script, mouse, keyb, ent) in (
         &mut scripts,
         (&mut mouses).maybe(),
         (&mut keybs).maybe(),
+        &ents,
         ).join() {
+            // A reloaded script gets a fresh start, even if the old one was disabled
+            if let Some(&revision) = self.script_revisions.get(&script.path) {
+                if script.revision != revision {
+                    if script.revision != 0 {
+                        script.failures = 0;
+                        script.disabled = false;
+                        script.last_error = None;
+                    }
+                    script.revision = revision;
+                }
+            }
+            if script.disabled {
+                continue;
+            }
+
             // Best effort load of the script
             let ast = match self.loaded_scripts.get_mut(&script.path) {
                 Some(ast) => ast,
@@ -76,79 +249,119 @@ impl<'a> specs::System<'a> for ScriptingSystem {
                 }
             };
 
+            let id = ScriptId { space, entity: ent };
             if !script.initialized {
                 script.initialized = true;
-                match self.engine.call_fn::<(), rhai::Map>(
-                    &mut self.scope,
-                    ast,
-                    "spawn",
-                    (),
-                ) {
-                    Ok(new_self) => script.object_self = new_self,
-                    Err(e) => log::error(&format!("spawn failed: {:?}", e)),
+                if let Some(mut new_self) = call_script(
+                    &mut self.engine, &mut self.scope, ast, script, id, "on_spawn", ()) {
+                    // Lets the script refer to itself, e.g. game.subscribe_event(self.id, "lclick")
+                    new_self.entry("id".into()).or_insert_with(|| rhai::Dynamic::from(id));
+                    script.object_self = new_self;
                 }
             }
 
             // Call keyboard functions
             if let Some(keyb_some) = keyb {
                 for e in keyb_some.events.drain(..) {
-                    match self.engine.call_fn::<(rhai::Map, String, bool), rhai::Map>(
-                        &mut self.scope,
-                        ast,
-                        "on_kb",
-                        (script.object_self.clone(), e.0.clone(), e.1),
-                    ) {
-                        Ok(new_self) => script.object_self = new_self,
-                        Err(e) => log::error(&format!("on_kb failed: {:?}", e)),
+                    if script.disabled {
+                        break;
+                    }
+                    let args = (script.object_self.clone(), e.0.clone(), e.1);
+                    if let Some(new_self) = call_script(
+                        &mut self.engine, &mut self.scope, ast, script, id, "on_kb", args) {
+                        script.object_self = new_self;
                     }
                 }
             }
 
             // Call the mouse functions (lclick, rclick, etc.)
             if let Some(mouse_some) = mouse {
-                if mouse_some.l_is_clicked {
+                if mouse_some.l_is_clicked && !script.disabled {
                     mouse_some.l_is_clicked = false;
-                    match self.engine.call_fn::<(rhai::Map,), rhai::Map>(
-                        &mut self.scope,
-                        ast,
-                        "lclick",
-                        (script.object_self.clone(),),
-                    ) {
-                        Ok(new_self) => script.object_self = new_self,
-                        Err(e) => log::error(&format!("lclick failed: {:?}", e)),
+                    let args = (script.object_self.clone(),);
+                    if let Some(new_self) = call_script(
+                        &mut self.engine, &mut self.scope, ast, script, id, "lclick", args) {
+                        script.object_self = new_self;
                     }
                 }
             }
 
         }
-        // Dispatch events until there are none left
         let context = self.scope.get_value::<Arc<GameContext>>("game").unwrap();
-        loop {
-            let ev = context.game_event_rx.try_recv();
-            if ev.is_err() {
-                break;
-            }
-            let ev = ev.unwrap();
 
-            let subs = context.get_event_subscribers(&ev.name);
-            if subs.is_none() {
+        // Sequences of deleted entities never run again
+        context.sequences.retain_owners(|owner| owner.space != space || scripts.contains(owner.entity));
+
+        // Run the sequence steps owned by this space's entities
+        let calls = std::mem::replace(&mut self.sequence_calls, Vec::new());
+        for call in calls {
+            if call.owner.space != space {
+                self.sequence_calls.push(call);
                 continue;
             }
-            let subs = subs.unwrap();
+            let script = match scripts.get_mut(call.owner.entity) {
+                Some(script) => script,
+                None => continue,
+            };
+            if script.disabled || context.sequences.is_cancelled(&call) {
+                continue;
+            }
+            if let Some(ast) = self.loaded_scripts.get(&script.path) {
+                let args = (script.object_self.clone(),);
+                if let Some(new_self) = call_script(
+                    &mut self.engine, &mut self.scope, ast, script, call.owner, &call.func, args) {
+                    script.object_self = new_self;
+                }
+            }
+        }
+
+        // Dispatch this space's events until there are none left. Handlers can send more,
+        // so past a limit the rest wait for the next tick rather than stalling this one.
+        self.route_events(&context);
+        let mut dispatched = 0;
+        while let Some((ev, recipients)) = self.deliveries.get_mut(&space).and_then(|q| q.pop_front()) {
+            if dispatched == consts::SCRIPT_MAX_EVENTS_PER_TICK {
+                log::warning(&format!("Over {} events this tick, delaying the rest", dispatched));
+                self.deliveries.entry(space).or_default().push_front((ev, recipients));
+                break;
+            }
+            dispatched += 1;
+
+            // A handler from the last event may have stopped propagation after running last
+            context.events.take_propagation_stopped();
 
-            for sub in subs {
+            for (i, sub) in recipients.iter().enumerate() {
                 if let Some(subbed_script) = scripts.get_mut(*sub) {
-                    match self.engine.call_fn::<(rhai::Map, rhai::Map), rhai::Map>(
+                    if subbed_script.disabled {
+                        continue;
+                    }
+                    let ast = match self.loaded_scripts.get(&subbed_script.path) {
+                        Some(ast) => ast,
+                        None => continue,
+                    };
+                    let args = (subbed_script.object_self.clone(), ev.args.clone());
+                    if let Some(new_self) = call_script(
+                        &mut self.engine,
                         &mut self.scope,
-                        self.loaded_scripts.get(&subbed_script.path).unwrap(),
+                        ast,
+                        subbed_script,
+                        ScriptId { space, entity: *sub },
                         &ev.name,
-                        (subbed_script.object_self.clone(), ev.args.clone()),
+                        args,
                     ) {
-                        Ok(new_self) => subbed_script.object_self = new_self,
-                        Err(e) => log::error(&format!("Event {} in entity {:?} failed: {:?}", &ev.name, sub, e)),
+                        subbed_script.object_self = new_self;
                     }
                 }
+
+                if context.events.take_propagation_stopped() {
+                    let skipped: Vec<ScriptId> = recipients[i + 1..].iter()
+                        .map(|&entity| ScriptId { space, entity })
+                        .collect();
+                    context.events.trace_stopped(&ev, ScriptId { space, entity: *sub }, &skipped);
+                    break;
+                }
             }
+            self.route_events(&context);
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,63 @@
+use specs::ReadStorage;
+use nalgebra::Point3;
+use crate::engine::components::{TextComponent, TextAnchor, TextStyle, TransformComponent};
+use crate::engine::localization;
+
+/// A label as the renderer sees it. World anchors are in world space.
+#[derive(Debug, Clone)]
+pub struct TextInstance {
+    pub text: String,
+    pub anchor: TextAnchor,
+    pub style: TextStyle,
+}
+
+/// Collects the visible labels of every space into the next frame.
+pub struct TextSystem {
+    next_frame_texts: Vec<TextInstance>,
+}
+
+impl TextSystem {
+    pub fn new() -> TextSystem {
+        TextSystem {
+            next_frame_texts: Vec::new(),
+        }
+    }
+
+    pub fn get_texts_and_flush(&mut self) -> Vec<TextInstance> {
+        let desired_capacity = self.next_frame_texts.capacity();
+        std::mem::replace(&mut self.next_frame_texts, Vec::with_capacity(desired_capacity))
+    }
+}
+
+impl<'a> specs::System<'a> for TextSystem {
+    type SystemData = (
+        ReadStorage<'a, TextComponent>,
+        ReadStorage<'a, TransformComponent>,
+    );
+
+    fn run(&mut self, (texts, transforms): Self::SystemData) {
+        use specs::Join;
+
+        for (text, trans) in (&texts, transforms.maybe()).join() {
+            if !text.visible {
+                continue;
+            }
+
+            let anchor = match (text.anchor, trans) {
+                (TextAnchor::World(offset), Some(trans)) => TextAnchor::World(
+                    trans.transform.transform_point(&Point3::from(offset)).coords.into()
+                ),
+                (anchor, _) => anchor,
+            };
+            self.next_frame_texts.push(TextInstance {
+                text: if text.localized {
+                    localization::tr(&text.text, &Default::default())
+                } else {
+                    text.text.clone()
+                },
+                anchor,
+                style: text.style,
+            });
+        }
+    }
+}
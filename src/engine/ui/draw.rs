@@ -0,0 +1,160 @@
+use crate::engine::prelude::*;
+use crate::engine::components::{TextAnchor, TextAlign, TextStyle};
+use crate::engine::systems::TextInstance;
+use super::{UiTree, Rect, WidgetKind};
+
+const PANEL_COLOR: [f32; 4] = [0.04, 0.06, 0.11, 0.85];
+const BUTTON_COLOR: [f32; 4] = [0.14, 0.19, 0.3, 0.95];
+const BUTTON_HOVERED_COLOR: [f32; 4] = [0.21, 0.29, 0.44, 0.95];
+const BUTTON_PRESSED_COLOR: [f32; 4] = [0.09, 0.13, 0.22, 0.95];
+const TRACK_COLOR: [f32; 4] = [0.3, 0.3, 0.35, 1.0];
+const ACCENT_COLOR: [f32; 4] = [0.45, 0.65, 1.0, 1.0];
+const TEXT_COLOR: [f32; 4] = [0.92, 0.92, 0.95, 1.0];
+const DISABLED_TEXT_COLOR: [f32; 4] = [0.5, 0.5, 0.55, 1.0];
+
+const TEXT_MARGIN: f32 = 8.0;
+const CHECKBOX_SIZE: f32 = 24.0;
+const KNOB_WIDTH: f32 = 10.0;
+const FOCUS_OUTLINE: f32 = 2.0;
+
+/// A flat or textured rectangle
+#[derive(Debug, Clone)]
+pub struct UiQuad {
+    pub rect: Rect,
+
+    /// Multiplies the texture, if there is one
+    pub color: [f32; 4],
+    pub texture: Option<String>,
+}
+
+/// Quads with the text that goes over them
+#[derive(Debug, Clone, Default)]
+pub struct UiLayer {
+    pub quads: Vec<UiQuad>,
+    pub texts: Vec<TextInstance>,
+}
+
+impl UiLayer {
+    fn quad(&mut self, rect: Rect, color: [f32; 4]) {
+        self.quads.push(UiQuad { rect, color, texture: None });
+    }
+
+    fn outline(&mut self, r: Rect, color: [f32; 4], width: f32) {
+        self.quad(Rect::new(r.x - width, r.y - width, r.w + 2.0 * width, width), color);
+        self.quad(Rect::new(r.x - width, r.y + r.h, r.w + 2.0 * width, width), color);
+        self.quad(Rect::new(r.x - width, r.y, width, r.h), color);
+        self.quad(Rect::new(r.x + r.w, r.y, width, r.h), color);
+    }
+
+    /// A single line, vertically centered in `rect`
+    fn text(&mut self, text: String, rect: Rect, align: TextAlign, color: [f32; 4]) {
+        if text.is_empty() {
+            return;
+        }
+        let x = match align {
+            TextAlign::Left => rect.x + TEXT_MARGIN,
+            TextAlign::Center => rect.x + rect.w / 2.0,
+            TextAlign::Right => rect.x + rect.w - TEXT_MARGIN,
+        };
+        self.texts.push(TextInstance {
+            text,
+            anchor: TextAnchor::Screen([x, rect.y + (rect.h - consts::UI_TEXT_SIZE) / 2.0]),
+            style: TextStyle {
+                size: consts::UI_TEXT_SIZE,
+                color,
+                align,
+                wrap_width: None,
+            },
+        });
+    }
+}
+
+/// The part of a slider the knob moves along: its right half, leaving the left for the text
+pub fn slider_track(rect: Rect) -> Rect {
+    Rect::new(rect.x + rect.w / 2.0, rect.y, rect.w / 2.0 - TEXT_MARGIN, rect.h)
+}
+
+pub fn build(tree: &UiTree, shown: &[String]) -> Vec<UiLayer> {
+    let mut base = UiLayer::default();
+    let mut popups = UiLayer::default();
+
+    for id in shown {
+        let w = &tree.widgets[id];
+        let r = w.rect;
+        let hovered = w.enabled && tree.hovered.as_ref() == Some(id);
+        let pressed = tree.pressed.as_ref() == Some(id);
+        let text_color = if w.enabled { TEXT_COLOR } else { DISABLED_TEXT_COLOR };
+        let button_color = if pressed {
+            BUTTON_PRESSED_COLOR
+        } else if hovered {
+            BUTTON_HOVERED_COLOR
+        } else {
+            BUTTON_COLOR
+        };
+
+        match &w.kind {
+            WidgetKind::Panel => base.quad(r, PANEL_COLOR),
+            WidgetKind::Label => base.text(w.display_text(), r, TextAlign::Left, text_color),
+            WidgetKind::Button => {
+                base.quad(r, button_color);
+                base.text(w.display_text(), r, TextAlign::Center, text_color);
+            },
+            WidgetKind::Checkbox { checked } => {
+                base.quads.push(UiQuad {
+                    rect: Rect::new(r.x, r.y + (r.h - CHECKBOX_SIZE) / 2.0, CHECKBOX_SIZE, CHECKBOX_SIZE),
+                    color: text_color,
+                    texture: Some(String::from(if *checked {
+                        consts::UI_CHECKBOX_ON_PATH
+                    } else {
+                        consts::UI_CHECKBOX_OFF_PATH
+                    })),
+                });
+                let label = Rect::new(r.x + CHECKBOX_SIZE, r.y, r.w - CHECKBOX_SIZE, r.h);
+                base.text(w.display_text(), label, TextAlign::Left, text_color);
+            },
+            WidgetKind::Slider { value, min, max, .. } => {
+                base.text(w.display_text(), r, TextAlign::Left, text_color);
+                let track = slider_track(r);
+                let t = if max > min { ((value - min) / (max - min)) as f32 } else { 0.0 };
+                let knob_x = track.x + track.w * t;
+                base.quad(Rect::new(track.x, track.y + track.h / 2.0 - 2.0, track.w, 4.0), TRACK_COLOR);
+                base.quad(Rect::new(track.x, track.y + track.h / 2.0 - 2.0, knob_x - track.x, 4.0),
+                    if w.enabled { ACCENT_COLOR } else { DISABLED_TEXT_COLOR });
+                base.quad(Rect::new(knob_x - KNOB_WIDTH / 2.0, track.y + 4.0, KNOB_WIDTH, track.h - 8.0),
+                    if hovered { TEXT_COLOR } else { button_color });
+            },
+            WidgetKind::Dropdown { options, selected, .. } => {
+                base.quad(r, button_color);
+                let current = options.get(*selected).cloned().unwrap_or_default();
+                base.text(current, r, TextAlign::Left, text_color);
+                base.text(String::from("v"), r, TextAlign::Right, text_color);
+
+                for (i, option) in UiTree::option_rects(w).into_iter().enumerate() {
+                    let highlighted = i == *selected || option.contains(tree.mouse);
+                    popups.quad(option, if highlighted { BUTTON_HOVERED_COLOR } else { BUTTON_COLOR });
+                    popups.text(options[i].clone(), option, TextAlign::Left, TEXT_COLOR);
+                }
+            },
+            WidgetKind::List { items, selected } => {
+                base.quad(r, PANEL_COLOR);
+                for (i, item) in items.iter().enumerate() {
+                    let row = Rect::new(r.x, r.y + i as f32 * consts::UI_LIST_ROW_HEIGHT,
+                        r.w, consts::UI_LIST_ROW_HEIGHT);
+                    if row.y + row.h > r.y + r.h {
+                        break;
+                    }
+                    if *selected == Some(i) {
+                        base.quad(row, BUTTON_HOVERED_COLOR);
+                    }
+                    base.text(item.clone(), row, TextAlign::Left, text_color);
+                }
+            },
+        }
+
+        if tree.focus.as_ref() == Some(id) {
+            base.outline(r, ACCENT_COLOR, FOCUS_OUTLINE);
+        }
+    }
+
+    vec![base, popups]
+}
@@ -0,0 +1,626 @@
+//! A retained 2D UI, drawn over the scene. Scripts build it out of widgets through
+//! `game.ui_*`, and get `ui_click` and `ui_changed` events back from the widgets they
+//! created. The mouse and the keyboard (Tab, the arrows, Enter and Space) drive it before
+//! the scene sees any input.
+use crate::engine::prelude::*;
+use crate::engine::scripting::{GameEvent, ScriptId};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+mod widget;
+pub use widget::{Anchor, Layout, Rect, Widget, WidgetKind};
+mod draw;
+pub use draw::{UiLayer, UiQuad};
+
+/// The widget tree along with its input state
+#[derive(Debug)]
+pub struct UiTree {
+    widgets: HashMap<String, Widget>,
+    roots: Vec<String>,
+    focus: Option<String>,
+    hovered: Option<String>,
+
+    // The widget the left button went down on, which gets the click when it goes up on it
+    pressed: Option<String>,
+    dragging: Option<String>,
+    mouse: [f32; 2],
+    events: Vec<GameEvent>,
+}
+
+/// Where the mouse is over the UI
+#[derive(Debug, Clone, PartialEq)]
+enum Hit {
+    Widget(String),
+
+    /// One of the options of an open dropdown
+    Option(String, usize),
+}
+
+impl UiTree {
+    pub fn new() -> UiTree {
+        UiTree {
+            widgets: HashMap::new(),
+            roots: Vec::new(),
+            focus: None,
+            hovered: None,
+            pressed: None,
+            dragging: None,
+            mouse: [0.0, 0.0],
+            events: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Widget> {
+        self.widgets.get(id)
+    }
+
+    pub fn get_widget_mut(&mut self, id: &str) -> anyhow::Result<&mut Widget> {
+        self.widgets.get_mut(id).ok_or_else(|| anyhow!("Unknown UI widget {}", id))
+    }
+
+    /// Adds a widget at the end of its parent's children, or as a new top level widget.
+    pub fn add(&mut self, id: &str, kind: WidgetKind, parent: Option<&str>, owner: Option<ScriptId>)
+    -> anyhow::Result<()> {
+        if self.widgets.contains_key(id) {
+            return Err(anyhow!("UI widget {} already exists", id));
+        }
+        match parent {
+            Some(parent) => self.get_widget_mut(parent)?.children.push(String::from(id)),
+            None => self.roots.push(String::from(id)),
+        }
+        self.widgets.insert(String::from(id), Widget::new(kind, owner, parent.map(String::from)));
+        Ok(())
+    }
+
+    /// Removes a widget along with its children
+    pub fn remove(&mut self, id: &str) -> anyhow::Result<()> {
+        let widget = self.widgets.remove(id).ok_or_else(|| anyhow!("Unknown UI widget {}", id))?;
+        match &widget.parent {
+            Some(parent) => if let Some(parent) = self.widgets.get_mut(parent) {
+                parent.children.retain(|c| c != id);
+            },
+            None => self.roots.retain(|r| r != id),
+        }
+        for child in widget.children.iter() {
+            let _ = self.remove(child);
+        }
+
+        for state in vec![&mut self.focus, &mut self.hovered, &mut self.pressed, &mut self.dragging] {
+            if state.as_deref() == Some(id) {
+                *state = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes a widget's settings
+    pub fn update<F: FnOnce(&mut Widget)>(&mut self, id: &str, f: F) -> anyhow::Result<()> {
+        f(self.get_widget_mut(id)?);
+        Ok(())
+    }
+
+    /// The value a script reads back: whether a checkbox is checked, a slider's value or
+    /// the selected index of a dropdown or a list (-1 for none).
+    pub fn value(&self, id: &str) -> anyhow::Result<f64> {
+        let widget = self.get(id).ok_or_else(|| anyhow!("Unknown UI widget {}", id))?;
+        Ok(match &widget.kind {
+            WidgetKind::Checkbox { checked } => if *checked { 1.0 } else { 0.0 },
+            WidgetKind::Slider { value, .. } => *value,
+            WidgetKind::Dropdown { selected, .. } => *selected as f64,
+            WidgetKind::List { selected, .. } => selected.map_or(-1.0, |s| s as f64),
+            _ => return Err(anyhow!("UI widget {} has no value", id)),
+        })
+    }
+
+    /// Sets the value read by `value`. Out of range values are clamped.
+    pub fn set_value(&mut self, id: &str, new_value: f64) -> anyhow::Result<()> {
+        let widget = self.get_widget_mut(id)?;
+        match &mut widget.kind {
+            WidgetKind::Checkbox { checked } => *checked = new_value != 0.0,
+            WidgetKind::Slider { value, min, max, .. } => *value = utils::clamp(new_value, *min, *max),
+            WidgetKind::Dropdown { selected, options, .. } =>
+                *selected = utils::clamp(new_value as usize, 0, options.len().max(1) - 1),
+            WidgetKind::List { selected, items } => *selected = if new_value < 0.0 || items.is_empty() {
+                None
+            } else {
+                Some((new_value as usize).min(items.len() - 1))
+            },
+            _ => return Err(anyhow!("UI widget {} has no value", id)),
+        }
+        Ok(())
+    }
+
+    /// The options of a dropdown or the items of a list
+    pub fn set_options(&mut self, id: &str, new_options: Vec<String>) -> anyhow::Result<()> {
+        let widget = self.get_widget_mut(id)?;
+        match &mut widget.kind {
+            WidgetKind::Dropdown { options, selected, .. } => {
+                *selected = (*selected).min(new_options.len().max(1) - 1);
+                *options = new_options;
+            },
+            WidgetKind::List { items, selected } => {
+                *selected = selected.filter(|s| *s < new_options.len());
+                *items = new_options;
+            },
+            _ => return Err(anyhow!("UI widget {} has no options", id)),
+        }
+        Ok(())
+    }
+
+    pub fn focused(&self) -> Option<&str> {
+        self.focus.as_deref()
+    }
+
+    pub fn set_focus(&mut self, id: &str) -> anyhow::Result<()> {
+        if !self.is_shown(id) || !self.get(id).map_or(false, |w| w.is_interactive() && w.enabled) {
+            return Err(anyhow!("UI widget {} can't take focus", id));
+        }
+        self.focus = Some(String::from(id));
+        Ok(())
+    }
+
+    /// Whether the widget and all of its parents are visible
+    pub fn is_shown(&self, id: &str) -> bool {
+        match self.get(id) {
+            Some(w) => w.visible && w.parent.as_ref().map_or(true, |p| self.is_shown(p)),
+            None => false,
+        }
+    }
+
+    /// The visible widgets, parents before their children, in drawing order
+    fn shown_in_order(&self) -> Vec<String> {
+        fn visit(tree: &UiTree, id: &str, out: &mut Vec<String>) {
+            if let Some(w) = tree.get(id) {
+                if w.visible {
+                    out.push(String::from(id));
+                    for child in w.children.iter() {
+                        visit(tree, child, out);
+                    }
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        for root in self.roots.iter() {
+            visit(self, root, &mut out);
+        }
+        out
+    }
+
+    /// Places every widget for a window of the given size in logical pixels
+    pub fn layout(&mut self, size: [f32; 2]) {
+        let screen = Rect::new(0.0, 0.0, size[0], size[1]);
+        for root in self.roots.clone() {
+            let rect = self.place(&root, screen);
+            self.layout_widget(&root, rect);
+        }
+
+        // Hidden and disabled widgets lose the focus
+        if let Some(focus) = self.focus.clone() {
+            if !self.is_shown(&focus) || !self.get(&focus).map_or(false, |w| w.enabled) {
+                self.focus = None;
+            }
+        }
+    }
+
+    /// Where a widget goes in `area` by its own anchor
+    fn place(&self, id: &str, area: Rect) -> Rect {
+        let w = &self.widgets[id];
+        let size = [
+            if w.size[0] > 0.0 { w.size[0] } else { area.w },
+            if w.size[1] > 0.0 { w.size[1] } else { area.h },
+        ];
+        let f = w.anchor.factors();
+        Rect::new(
+            area.x + (area.w - size[0]) * f[0] + w.offset[0],
+            area.y + (area.h - size[1]) * f[1] + w.offset[1],
+            size[0],
+            size[1],
+        )
+    }
+
+    fn layout_widget(&mut self, id: &str, rect: Rect) {
+        let widget = self.widgets.get_mut(id).unwrap();
+        widget.rect = rect;
+        let inner = rect.shrink(widget.padding);
+        let (layout, spacing) = (widget.layout, widget.spacing);
+
+        let mut cursor = 0.0;
+        for child in widget.children.clone() {
+            if !self.widgets[&child].visible {
+                continue;
+            }
+            let child_rect = match layout {
+                Layout::Free => self.place(&child, inner),
+                Layout::Vertical => {
+                    let slot = Rect::new(inner.x, inner.y + cursor, inner.w, 0.0);
+                    let mut r = self.place(&child, slot);
+                    r.y = slot.y + self.widgets[&child].offset[1];
+                    cursor += r.h + spacing;
+                    r
+                },
+                Layout::Horizontal => {
+                    let slot = Rect::new(inner.x + cursor, inner.y, 0.0, inner.h);
+                    let mut r = self.place(&child, slot);
+                    r.x = slot.x + self.widgets[&child].offset[0];
+                    cursor += r.w + spacing;
+                    r
+                },
+            };
+            self.layout_widget(&child, child_rect);
+        }
+    }
+
+    /// The rects of an open dropdown's options, below it
+    fn option_rects(widget: &Widget) -> Vec<Rect> {
+        match &widget.kind {
+            WidgetKind::Dropdown { options, open: true, .. } => (0..options.len())
+                .map(|i| Rect::new(widget.rect.x, widget.rect.y + widget.rect.h * (i + 1) as f32,
+                    widget.rect.w, widget.rect.h))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The row of a list under a point
+    fn list_row(widget: &Widget, p: [f32; 2]) -> Option<usize> {
+        match &widget.kind {
+            WidgetKind::List { items, .. } => {
+                let row = ((p[1] - widget.rect.y) / consts::UI_LIST_ROW_HEIGHT).floor();
+                if row >= 0.0 && (row as usize) < items.len() { Some(row as usize) } else { None }
+            },
+            _ => None,
+        }
+    }
+
+    fn hit_test(&self, p: [f32; 2]) -> Option<Hit> {
+        let shown = self.shown_in_order();
+
+        // Open dropdowns cover everything else
+        for id in shown.iter() {
+            let widget = &self.widgets[id];
+            if let Some(i) = UiTree::option_rects(widget).iter().position(|r| r.contains(p)) {
+                return Some(Hit::Option(id.clone(), i));
+            }
+        }
+        shown.iter().rev()
+            .find(|id| self.widgets[*id].rect.contains(p))
+            .map(|id| Hit::Widget(id.clone()))
+    }
+
+    /// Whether the mouse is over a widget, so the scene shouldn't react to it
+    pub fn is_hovered(&self) -> bool {
+        self.hit_test(self.mouse).is_some() || self.dragging.is_some()
+    }
+
+    fn send(&mut self, id: &str, name: &str, value: Option<rhai::Dynamic>) {
+        let owner = match self.widgets.get(id).and_then(|w| w.owner) {
+            Some(owner) => owner,
+            None => return,
+        };
+        let mut args = rhai::Map::new();
+        args.insert("widget".into(), rhai::Dynamic::from(String::from(id)));
+        if let Some(value) = value {
+            args.insert("value".into(), value);
+        }
+        self.events.push(GameEvent {
+            name: String::from(name),
+            args,
+            target: Some(owner),
+        });
+    }
+
+    /// The events for the widgets' owners since the last call
+    pub fn take_events(&mut self) -> Vec<GameEvent> {
+        std::mem::replace(&mut self.events, Vec::new())
+    }
+
+    fn close_dropdowns(&mut self, except: Option<&str>) {
+        for (id, widget) in self.widgets.iter_mut() {
+            if let WidgetKind::Dropdown { open, .. } = &mut widget.kind {
+                if Some(id.as_str()) != except {
+                    *open = false;
+                }
+            }
+        }
+    }
+
+    fn set_slider_from_mouse(&mut self, id: &str) {
+        let x = self.mouse[0];
+        let widget = self.widgets.get_mut(id).unwrap();
+        let track = draw::slider_track(widget.rect);
+        if let WidgetKind::Slider { value, min, max, step } = &mut widget.kind {
+            let t = utils::clamp((x - track.x) / track.w.max(1.0), 0.0, 1.0) as f64;
+            let mut new_value = *min + (*max - *min) * t;
+            if *step > 0.0 {
+                new_value = *min + ((new_value - *min) / *step).round() * *step;
+            }
+            new_value = utils::clamp(new_value, *min, *max);
+            if new_value != *value {
+                *value = new_value;
+                self.send(id, "ui_changed", Some(rhai::Dynamic::from(new_value)));
+            }
+        }
+    }
+
+    pub fn mouse_moved(&mut self, position: [f32; 2]) {
+        self.mouse = position;
+        self.hovered = match self.hit_test(position) {
+            Some(Hit::Widget(id)) => Some(id),
+            _ => None,
+        };
+        if let Some(id) = self.dragging.clone() {
+            self.set_slider_from_mouse(&id);
+        }
+    }
+
+    /// Handles the left mouse button. Returns whether the UI used the click.
+    pub fn mouse_button(&mut self, is_down: bool) -> bool {
+        let hit = self.hit_test(self.mouse);
+        if !is_down {
+            let was_dragging = self.dragging.take().is_some();
+            let pressed = self.pressed.take();
+            if let (Some(Hit::Widget(id)), Some(pressed)) = (&hit, pressed) {
+                if *id == pressed {
+                    self.activate(id);
+                }
+            }
+            return hit.is_some() || was_dragging;
+        }
+
+        match &hit {
+            Some(Hit::Option(id, i)) => {
+                let (id, i) = (id.clone(), *i);
+                self.select(&id, i);
+                self.close_dropdowns(None);
+            },
+            Some(Hit::Widget(id)) => {
+                let id = id.clone();
+                self.close_dropdowns(Some(&id));
+                let widget = &self.widgets[&id];
+                if !widget.enabled {
+                    return true;
+                }
+                let is_slider = match widget.kind {
+                    WidgetKind::Slider { .. } => true,
+                    _ => false,
+                };
+                let row = UiTree::list_row(widget, self.mouse);
+                if widget.is_interactive() {
+                    self.focus = Some(id.clone());
+                }
+
+                if is_slider {
+                    self.dragging = Some(id.clone());
+                    self.set_slider_from_mouse(&id);
+                } else if let Some(row) = row {
+                    self.select(&id, row);
+                } else {
+                    self.pressed = Some(id);
+                }
+            },
+            None => self.close_dropdowns(None),
+        }
+        hit.is_some()
+    }
+
+    /// Clicks a widget, or presses Enter on it
+    fn activate(&mut self, id: &str) {
+        let widget = match self.widgets.get_mut(id) {
+            Some(w) if w.enabled => w,
+            _ => return,
+        };
+        match &mut widget.kind {
+            WidgetKind::Button => self.send(id, "ui_click", None),
+            WidgetKind::Checkbox { checked } => {
+                *checked = !*checked;
+                let checked = *checked;
+                self.send(id, "ui_changed", Some(rhai::Dynamic::from(checked)));
+            },
+            WidgetKind::Dropdown { open, .. } => *open = !*open,
+            _ => (),
+        }
+    }
+
+    /// Selects an option of a dropdown or an item of a list
+    fn select(&mut self, id: &str, index: usize) {
+        let changed = match self.widgets.get_mut(id).map(|w| &mut w.kind) {
+            Some(WidgetKind::Dropdown { selected, options, .. }) if index < options.len() =>
+                std::mem::replace(selected, index) != index,
+            Some(WidgetKind::List { selected, items }) if index < items.len() =>
+                std::mem::replace(selected, Some(index)) != Some(index),
+            _ => false,
+        };
+        if changed {
+            self.send(id, "ui_changed", Some(rhai::Dynamic::from(index as i64)));
+        }
+    }
+
+    /// Moves the focus to the next (or previous) widget that can take it, wrapping around
+    fn move_focus(&mut self, forward: bool) -> bool {
+        let focusable: Vec<String> = self.shown_in_order().into_iter()
+            .filter(|id| self.widgets[id].is_interactive() && self.widgets[id].enabled)
+            .collect();
+        if focusable.is_empty() {
+            return false;
+        }
+
+        let current = self.focus.as_ref().and_then(|f| focusable.iter().position(|id| id == f));
+        let next = match (current, forward) {
+            (None, true) => 0,
+            (None, false) => focusable.len() - 1,
+            (Some(i), true) => (i + 1) % focusable.len(),
+            (Some(i), false) => (i + focusable.len() - 1) % focusable.len(),
+        };
+        self.close_dropdowns(None);
+        self.focus = Some(focusable[next].clone());
+        true
+    }
+
+    /// Handles a key press. Returns whether the UI used it.
+    pub fn key(&mut self, key: &str) -> bool {
+        match key {
+            "Tab" => return self.move_focus(true),
+            "SHIFT+Tab" => return self.move_focus(false),
+            _ => (),
+        }
+
+        let id = match self.focus.clone() {
+            Some(id) => id,
+            None => return false,
+        };
+        let kind = self.widgets[&id].kind.clone();
+        let step = |forward: bool| if forward { 1 } else { -1 };
+        match (key, kind) {
+            ("Enter", WidgetKind::Dropdown { open: true, .. })
+            | ("Space", WidgetKind::Dropdown { open: true, .. })
+            | ("Escape", WidgetKind::Dropdown { open: true, .. }) => self.close_dropdowns(None),
+            ("Enter", _) | ("Space", _) => self.activate(&id),
+            ("LeftArrow", WidgetKind::Slider { value, step: s, .. })
+            | ("RightArrow", WidgetKind::Slider { value, step: s, .. }) => {
+                let new_value = value + s * step(key == "RightArrow") as f64;
+                let _ = self.set_value(&id, new_value);
+                if self.value(&id).ok() != Some(value) {
+                    let current = self.value(&id).unwrap();
+                    self.send(&id, "ui_changed", Some(rhai::Dynamic::from(current)));
+                }
+            },
+            ("LeftArrow", WidgetKind::Dropdown { selected, .. })
+            | ("RightArrow", WidgetKind::Dropdown { selected, .. })
+            | ("UpArrow", WidgetKind::Dropdown { selected, open: true, .. })
+            | ("DownArrow", WidgetKind::Dropdown { selected, open: true, .. }) => {
+                let forward = key == "RightArrow" || key == "DownArrow";
+                let index = selected as i64 + step(forward);
+                if index >= 0 {
+                    self.select(&id, index as usize);
+                }
+            },
+            ("UpArrow", WidgetKind::List { selected, .. }) | ("DownArrow", WidgetKind::List { selected, .. }) => {
+                let index = match selected {
+                    Some(s) => s as i64 + step(key == "DownArrow"),
+                    None => 0,
+                };
+                if index >= 0 {
+                    self.select(&id, index as usize);
+                }
+            },
+            ("UpArrow", _) => return self.move_focus(false),
+            ("DownArrow", _) => return self.move_focus(true),
+            _ => return false,
+        }
+        true
+    }
+
+    /// What to draw, bottom layer first: the widgets, then the open dropdowns over them
+    pub fn draw(&self) -> Vec<UiLayer> {
+        draw::build(self, &self.shown_in_order())
+    }
+}
+
+/// The UI shared between the scripts and the engine
+#[derive(Debug)]
+pub struct Ui {
+    tree: Mutex<UiTree>,
+}
+
+impl Ui {
+    pub fn new() -> Ui {
+        Ui {
+            tree: Mutex::new(UiTree::new()),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<UiTree> {
+        self.tree.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+fn menu() -> UiTree {
+    menu_owned_by(None)
+}
+
+/// The widgets of `menu`, owned by `owner` or by a script of a space of their own
+#[cfg(test)]
+fn menu_owned_by(owner: Option<ScriptId>) -> UiTree {
+    use specs::WorldExt;
+    use crate::engine::scripting::SpaceId;
+    let owner = owner.unwrap_or_else(|| ScriptId {
+        space: SpaceId::new(),
+        entity: specs::World::new().entities().create(),
+    });
+    let mut ui = UiTree::new();
+    ui.add("menu", WidgetKind::Panel, None, Some(owner)).unwrap();
+    ui.update("menu", |w| {
+        w.anchor = Anchor::Center;
+        w.size = [200.0, 300.0];
+        w.layout = Layout::Vertical;
+        w.spacing = 10.0;
+        w.padding = 20.0;
+    }).unwrap();
+    ui.add("play", WidgetKind::Button, Some("menu"), Some(owner)).unwrap();
+    ui.add("title", WidgetKind::Label, Some("menu"), Some(owner)).unwrap();
+    ui.add("sound", WidgetKind::Checkbox { checked: false }, Some("menu"), Some(owner)).unwrap();
+    for id in &["play", "title", "sound"] {
+        ui.update(id, |w| w.size = [0.0, 40.0]).unwrap();
+    }
+    ui.layout([800.0, 600.0]);
+    ui
+}
+
+#[test]
+fn vertical_layout_stacks_children() {
+    let ui = menu();
+    assert_eq!(ui.get("menu").unwrap().rect, Rect::new(300.0, 150.0, 200.0, 300.0));
+    assert_eq!(ui.get("play").unwrap().rect, Rect::new(320.0, 170.0, 160.0, 40.0));
+    assert_eq!(ui.get("title").unwrap().rect, Rect::new(320.0, 220.0, 160.0, 40.0));
+    assert_eq!(ui.get("sound").unwrap().rect, Rect::new(320.0, 270.0, 160.0, 40.0));
+}
+
+#[test]
+fn mouse_and_keyboard_drive_widgets() {
+    let mut ui = menu();
+
+    ui.mouse_moved([330.0, 180.0]);
+    assert!(ui.mouse_button(true));
+    assert!(ui.mouse_button(false));
+    let events = ui.take_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].name, "ui_click");
+    assert_eq!(events[0].args["widget"].clone().cast::<String>(), "play");
+
+    // Labels are skipped by keyboard navigation
+    assert_eq!(ui.focused(), Some("play"));
+    assert!(ui.key("Tab"));
+    assert_eq!(ui.focused(), Some("sound"));
+    assert!(ui.key("Space"));
+    assert_eq!(ui.value("sound").unwrap(), 1.0);
+    assert_eq!(ui.take_events()[0].name, "ui_changed");
+
+    ui.update("menu", |w| w.visible = false).unwrap();
+    ui.layout([800.0, 600.0]);
+    assert_eq!(ui.focused(), None);
+    assert!(!ui.key("Enter"));
+    ui.mouse_moved([330.0, 180.0]);
+    assert!(!ui.mouse_button(true));
+}
+
+#[test]
+fn widget_events_go_to_the_owner_space() {
+    use specs::WorldExt;
+    use crate::engine::scripting::SpaceId;
+
+    // Both spaces' first entity has the same id, so only the space tells them apart
+    let menu_space = specs::World::new();
+    let galaxy_space = specs::World::new();
+    let owner = ScriptId { space: SpaceId::new(), entity: galaxy_space.entities().create() };
+    assert_eq!(owner.entity, menu_space.entities().create());
+
+    let mut ui = menu_owned_by(Some(owner));
+    ui.mouse_moved([330.0, 180.0]);
+    ui.mouse_button(true);
+    ui.mouse_button(false);
+    let events = ui.take_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].target, Some(owner));
+}
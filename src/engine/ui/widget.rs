@@ -0,0 +1,226 @@
+use crate::engine::scripting::ScriptId;
+
+/// A rectangle in pixels from the top left of the window
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+impl Rect {
+    pub fn new(x: f32, y: f32, w: f32, h: f32) -> Rect {
+        Rect { x, y, w, h }
+    }
+
+    pub fn contains(&self, p: [f32; 2]) -> bool {
+        p[0] >= self.x && p[0] < self.x + self.w && p[1] >= self.y && p[1] < self.y + self.h
+    }
+
+    pub fn shrink(&self, by: f32) -> Rect {
+        Rect::new(self.x + by, self.y + by, (self.w - 2.0 * by).max(0.0), (self.h - 2.0 * by).max(0.0))
+    }
+
+    pub fn scaled(&self, by: f32) -> Rect {
+        Rect::new(self.x * by, self.y * by, self.w * by, self.h * by)
+    }
+}
+
+/// Which point of its parent a widget is placed relative to. The widget's own matching
+/// point goes there, so `BottomRight` puts it in the parent's bottom right corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// How far along the parent's width and height the anchor is, from 0 to 1
+    pub fn factors(self) -> [f32; 2] {
+        match self {
+            Anchor::TopLeft => [0.0, 0.0],
+            Anchor::Top => [0.5, 0.0],
+            Anchor::TopRight => [1.0, 0.0],
+            Anchor::Left => [0.0, 0.5],
+            Anchor::Center => [0.5, 0.5],
+            Anchor::Right => [1.0, 0.5],
+            Anchor::BottomLeft => [0.0, 1.0],
+            Anchor::Bottom => [0.5, 1.0],
+            Anchor::BottomRight => [1.0, 1.0],
+        }
+    }
+}
+
+impl std::str::FromStr for Anchor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "top_left" => Anchor::TopLeft,
+            "top" => Anchor::Top,
+            "top_right" => Anchor::TopRight,
+            "left" => Anchor::Left,
+            "center" => Anchor::Center,
+            "right" => Anchor::Right,
+            "bottom_left" => Anchor::BottomLeft,
+            "bottom" => Anchor::Bottom,
+            "bottom_right" => Anchor::BottomRight,
+            _ => return Err(anyhow::anyhow!("Unknown anchor {}", s)),
+        })
+    }
+}
+
+/// How a widget places its children
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// Each child by its own anchor
+    Free,
+
+    /// Stacked top to bottom. Anchors only align children horizontally.
+    Vertical,
+
+    /// Stacked left to right. Anchors only align children vertically.
+    Horizontal,
+}
+
+impl std::str::FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "free" => Layout::Free,
+            "vertical" => Layout::Vertical,
+            "horizontal" => Layout::Horizontal,
+            _ => return Err(anyhow::anyhow!("Unknown layout {}", s)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WidgetKind {
+    Panel,
+    Label,
+    Button,
+    Checkbox {
+        checked: bool,
+    },
+    Slider {
+        value: f64,
+        min: f64,
+        max: f64,
+        step: f64,
+    },
+    Dropdown {
+        options: Vec<String>,
+        selected: usize,
+        open: bool,
+    },
+    List {
+        items: Vec<String>,
+        selected: Option<usize>,
+    },
+}
+
+impl WidgetKind {
+    pub fn from_name(name: &str) -> Option<WidgetKind> {
+        Some(match name {
+            "panel" => WidgetKind::Panel,
+            "label" => WidgetKind::Label,
+            "button" => WidgetKind::Button,
+            "checkbox" => WidgetKind::Checkbox { checked: false },
+            "slider" => WidgetKind::Slider { value: 0.0, min: 0.0, max: 1.0, step: 0.1 },
+            "dropdown" => WidgetKind::Dropdown { options: Vec::new(), selected: 0, open: false },
+            "list" => WidgetKind::List { items: Vec::new(), selected: None },
+            _ => return None,
+        })
+    }
+
+    fn default_size(&self) -> [f32; 2] {
+        match self {
+            WidgetKind::Panel => [320.0, 240.0],
+            WidgetKind::Label => [240.0, 28.0],
+            WidgetKind::Button => [240.0, 40.0],
+            WidgetKind::Checkbox { .. } => [240.0, 32.0],
+            WidgetKind::Slider { .. } => [240.0, 32.0],
+            WidgetKind::Dropdown { .. } => [240.0, 36.0],
+            WidgetKind::List { .. } => [240.0, 160.0],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Widget {
+    pub kind: WidgetKind,
+
+    /// The script that created the widget, which gets its events
+    pub owner: Option<ScriptId>,
+    pub parent: Option<String>,
+    pub children: Vec<String>,
+
+    pub text: String,
+
+    /// `text` is a localization key, translated to the current language when drawn
+    pub localized: bool,
+
+    pub anchor: Anchor,
+    pub offset: [f32; 2],
+
+    /// Zero fills the parent along that axis
+    pub size: [f32; 2],
+
+    pub layout: Layout,
+    pub spacing: f32,
+    pub padding: f32,
+
+    pub visible: bool,
+    pub enabled: bool,
+
+    /// Where the last layout placed the widget
+    pub rect: Rect,
+}
+
+impl Widget {
+    pub fn new(kind: WidgetKind, owner: Option<ScriptId>, parent: Option<String>) -> Widget {
+        Widget {
+            size: kind.default_size(),
+            kind,
+            owner,
+            parent,
+            children: Vec::new(),
+            text: String::new(),
+            localized: false,
+            anchor: Anchor::TopLeft,
+            offset: [0.0, 0.0],
+            layout: Layout::Free,
+            spacing: 0.0,
+            padding: 0.0,
+            visible: true,
+            enabled: true,
+            rect: Rect::default(),
+        }
+    }
+
+    /// Whether keyboard focus can land on this kind of widget
+    pub fn is_interactive(&self) -> bool {
+        match self.kind {
+            WidgetKind::Panel | WidgetKind::Label => false,
+            _ => true,
+        }
+    }
+
+    pub fn display_text(&self) -> String {
+        if self.localized {
+            crate::engine::localization::tr(&self.text, &Default::default())
+        } else {
+            self.text.clone()
+        }
+    }
+}
@@ -46,7 +46,7 @@ pub fn load_image<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<image::D
 }
 
 pub fn get_engine_dependencies() -> Vec<String> {
-    let cargo_toml = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "\\Cargo.toml"));
+    let cargo_toml = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"));
     let mut result = Vec::<String>::new();
     let mut dependencies_found = false;
 
@@ -73,24 +73,111 @@ pub fn get_files_with_extension_from<P>(dir: P, extensions: Vec<&str>) -> Vec<st
         Ok(dir) => dir.filter_map(|p| p.ok())                                  // Entry successfully read?
                       .map(|p| p.path())                                       // DirEntry -> Path
                       .filter(|p| p.file_stem().is_some())                     // Remove extension
-                      .filter(|p| extensions.iter().any(|&e| e == p.extension().unwrap().to_string_lossy().as_ref()))   // Path is a json?
+                      .filter(|p| p.extension().map_or(false, |ext|            // Path is a json?
+                          extensions.iter().any(|&e| e == ext.to_string_lossy().as_ref())))
                       .collect()
     }
 }
 
-/// Checks if the given file has an up to date optimized cache.
-/// The engine uses this form on files that take a while to load, like "model.obj"->"model.cache".
-pub fn should_load_from_cache<P: AsRef<std::path::Path>>(filename: P) -> (bool, Option<std::path::PathBuf>) {
-    let mut filename_cache = filename.as_ref().to_string_lossy().into_owned();
-    filename_cache.push_str(".cache");
+/// Like `get_files_with_extension_from`, but also searches every subfolder.
+pub fn get_files_with_extension_recursive<P>(dir: P, extensions: Vec<&str>) -> Vec<std::path::PathBuf>
+        where P: AsRef<std::path::Path> {
+    let mut result = get_files_with_extension_from(dir.as_ref(), extensions.clone());
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for subdir in entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_dir()) {
+            result.extend(get_files_with_extension_recursive(subdir, extensions.clone()));
+        }
+    }
+    result
+}
+
+/// Written at the start of every cache file, after the cache type's magic bytes. A cache
+/// whose header doesn't match is rebuilt, so changes to the cached types, the engine or the
+/// source files never load stale data.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CacheHeader {
+    pub format_version: u32,
+    pub engine_version: String,
+    pub source_hash: u64,
+}
 
-    if let Ok(Ok(time_file)) = std::fs::metadata(filename).map(|md| md.modified()) {
-        if let Ok(Ok(time_cache)) = std::fs::metadata(filename_cache.clone()).map(|md| md.modified()) {
-            return (time_file < time_cache, Some(std::path::PathBuf::from(filename_cache)))
+impl CacheHeader {
+    pub fn new(format_version: u32, source_hash: u64) -> CacheHeader {
+        CacheHeader {
+            format_version,
+            engine_version: String::from(env!("CARGO_PKG_VERSION")),
+            source_hash,
         }
     }
 
-    (false, Some(std::path::PathBuf::from(filename_cache)))
+    /// Checks a header read from a cache against the one it would be written with now
+    pub fn validate(&self, expected: &CacheHeader) -> anyhow::Result<()> {
+        if self.format_version != expected.format_version {
+            return Err(anyhow::anyhow!("The cache has format version {}, not {}",
+                self.format_version, expected.format_version));
+        }
+        if self.engine_version != expected.engine_version {
+            return Err(anyhow::anyhow!("The cache was built by engine version {}, not {}",
+                self.engine_version, expected.engine_version));
+        }
+        if self.source_hash != expected.source_hash {
+            return Err(anyhow::anyhow!("The cache was built from a different source"));
+        }
+        Ok(())
+    }
+}
+
+/// Where a cache file goes: at `path`, or under the same relative path in `cache_dir`
+pub fn cache_location<P: AsRef<std::path::Path>>(path: P, cache_dir: Option<&std::path::Path>)
+-> std::path::PathBuf {
+    match cache_dir {
+        // Only the plain components, so ".." can't point outside the cache directory
+        Some(dir) => dir.join(path.as_ref().components()
+            .filter(|c| matches!(c, std::path::Component::Normal(_)))
+            .collect::<std::path::PathBuf>()),
+        None => path.as_ref().to_path_buf(),
+    }
+}
+
+/// Reads a cache written by `write_cache`, if its magic bytes and header match
+pub fn read_cache<T: serde::de::DeserializeOwned, P: AsRef<std::path::Path>>(path: P, magic: &[u8; 4],
+    header: &CacheHeader) -> anyhow::Result<T> {
+    use anyhow::Context;
+    use std::io::Read;
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path.as_ref()).context("Cache does not exist")?);
+
+    // Checked on its own first, so files that aren't caches aren't parsed any further
+    let mut found = [0u8; 4];
+    reader.read_exact(&mut found).context("The cache is empty")?;
+    if found != *magic {
+        return Err(anyhow::anyhow!("The cache has no header"));
+    }
+    let found: CacheHeader = bincode::deserialize_from(&mut reader).context("Failed loading cache header")?;
+    found.validate(header)?;
+    bincode::deserialize_from(&mut reader)
+        .context(format!("Failed loading cache {}", path.as_ref().to_string_lossy()))
+}
+
+pub fn write_cache<T: serde::Serialize, P: AsRef<std::path::Path>>(path: P, magic: &[u8; 4], header: &CacheHeader,
+    data: &T) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use std::io::Write;
+    if let Some(parent) = path.as_ref().parent() {
+        std::fs::create_dir_all(parent).context("Failed to create cache folder")?;
+    }
+    let file = std::fs::File::create(path.as_ref())
+        .context(format!("Error creating cache file {}", path.as_ref().to_string_lossy()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    writer.write_all(magic).context("Error writing cache header")?;
+    bincode::serialize_into(&mut writer, header).context("Error serializing")?;
+    bincode::serialize_into(&mut writer, data).context("Error serializing")?;
+    writer.flush().context("Error writing cache file")
+}
+
+/// A 64 bit FNV-1a hash, which unlike `DefaultHasher` is the same on every build, so it
+/// can name files that outlive the executable.
+pub fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
 }
 
 pub fn extend_filename<P: AsRef<std::path::Path>>(path: P, suffix: &str) -> std::path::PathBuf {
@@ -101,4 +188,21 @@ pub fn extend_filename<P: AsRef<std::path::Path>>(path: P, suffix: &str) -> std:
     extended.push_str(".");
     extended.push_str(extension);
     std::path::PathBuf::from(path.as_ref().with_file_name(extended))
-}
\ No newline at end of file
+}
+
+#[test]
+fn content_hashes_are_stable() {
+    // FNV-1a reference values, cache names depend on them
+    assert_eq!(content_hash(b""), 0xcbf29ce484222325);
+    assert_eq!(content_hash(b"a"), 0xaf63dc4c8601ec8c);
+}
+
+#[test]
+fn cache_headers_are_validated() {
+    let header = CacheHeader::new(1, 42);
+    assert!(header.validate(&CacheHeader::new(1, 42)).is_ok());
+    assert!(header.validate(&CacheHeader::new(1, 43)).is_err());
+    assert!(header.validate(&CacheHeader::new(2, 42)).is_err());
+    assert!(CacheHeader { engine_version: String::from("0.0.0"), ..CacheHeader::new(1, 42) }
+        .validate(&header).is_err());
+}
@@ -13,7 +13,7 @@ pub trait Level {
 type SpaceIterator<'a> = Box<dyn Iterator<Item=&'a mut specs::World> + 'a>;
 
 /// All the boilerplate of initializing a space
-fn create_space() -> specs::World {
+pub fn create_space() -> specs::World {
     use crate::engine::components;
     use specs::{WorldExt};
 
@@ -24,7 +24,10 @@ fn create_space() -> specs::World {
     world.register::<components::KeyboardComponent>();
     world.register::<components::ScriptingComponent>();
     world.register::<components::StaticSkyboxComponent>();
+    world.register::<components::LightComponent>();
+    world.register::<components::TextComponent>();
     world.insert(crate::engine::systems::KeyboardState {ctrl: false, shift: false, alt: false});
+    world.insert(crate::engine::scripting::SpaceId::new());
 
     world
 }
@@ -34,8 +34,20 @@ impl SpaceWarLevel {
         .with(components::KeyboardComponent::new(vec![String::from("Escape")]))
         .build();
 
-        for star in crate::gameplay::mapgen::apply_mask(crate::gameplay::mapgen::poisson_distribution(64), "./resources/spiral_mask.png").unwrap() {
-            self.galaxy_map_space.create_entity()
+        let names = utils::read_file_lines("./resources/starnames.txt").unwrap_or_else(|e| {
+            log::err(&e);
+            Vec::new()
+        });
+        let label_style = components::TextStyle {
+            size: 14.0,
+            color: [0.85, 0.85, 0.9, 1.0],
+            align: components::TextAlign::Center,
+            wrap_width: None,
+        };
+
+        let stars = crate::gameplay::mapgen::apply_mask(crate::gameplay::mapgen::poisson_distribution(64), "./resources/spiral_mask.png").unwrap();
+        for (i, star) in stars.into_iter().enumerate() {
+            let mut entity = self.galaxy_map_space.create_entity()
             .with(components::StaticMeshComponent::new(
                 "sphere.gltf", 
                 nalgebra::Matrix4::new_scaling(0.01)))
@@ -44,7 +56,13 @@ impl SpaceWarLevel {
                     &nalgebra::Vector3::new(star.0 as f32, -5.0, star.1 as f32)
                 )
             ))
-            .build();
+            // Lights up the neighbouring stars, and whatever orbits the star
+            .with(components::LightComponent::point([1.0, 0.85, 0.6], 0.01, 0.3));
+            if let Some(name) = names.get(i) {
+                entity = entity.with(components::TextComponent::world(name, [0.0, 0.03, 0.0])
+                    .with_style(label_style));
+            }
+            entity.build();
         }
     }
 }
@@ -81,6 +99,15 @@ impl SpaceWarLevel {
         .with(components::ScriptingComponent::new("test.rhai"))
         .build();
 
+        for x in &[0.4f32, -0.4] {
+            self.main_menu_space.create_entity()
+            .with(components::LightComponent::point([1.0, 1.0, 1.0], 400.0, std::f32::INFINITY).with_shadows())
+            .with(components::TransformComponent::from(
+                nalgebra::Matrix4::new_translation(&nalgebra::Vector3::new(*x, 0.7, 0.25))
+            ))
+            .build();
+        }
+
         self.main_menu_space.create_entity()
         .with(components::ScriptingComponent::new("mainmenu.rhai"))
         .with(components::KeyboardComponent::new(vec![String::from("Escape")]))
@@ -92,10 +119,11 @@ impl SpaceWarLevel {
         if let Ok(credits) = std::fs::read_to_string("./resources/credits.txt") {
             credits +
             "\n# Rust packages\n" +
-            &utils::get_engine_dependencies()
+            utils::get_engine_dependencies()
                 .iter()
                 .map(|s| format!("{}\n", s))
                 .collect::<String>()
+                .as_str()
         } else {
             String::from("Credits file not found")
         }
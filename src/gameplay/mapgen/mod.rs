@@ -1,6 +1,6 @@
 use crate::engine::prelude::*;
 use crate::gameplay::types::{StarSystem, Star};
-use rhai::{RegisterFn, Scope};
+use rhai::Scope;
 use rand::Rng;
 
 fn distance_sq(p1: &(f64, f64), p2: &(f64, f64)) -> f64 {
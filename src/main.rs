@@ -34,15 +34,28 @@ use engine::prelude::*;
 
 fn main()
 {
+    if std::env::args().any(|a| a == "--check-localization") {
+        std::process::exit(engine::localization_coverage::run());
+    }
+
     log::info("Starting Space War Supreme!");
 
     let eventloop = glium::glutin::event_loop::EventLoop::new();
-    let mut engine = engine::Engine::new(
+    let mut engine = match engine::Engine::new(
         &eventloop, 
         Box::new(spacewar::SpaceWarLevel::new())
-    );
-    // TODO expand this to include all monitor names+resolutions
-    println!("{:?}", engine.renderer.get_supported_resolutions());
+    ) {
+        Ok(engine) => engine,
+        Err(e) => {
+            log::err(&e);
+            engine::utils::error_msgbox(&format!("{:#}", e));
+            std::process::exit(1);
+        }
+    };
+    for (i, monitor) in engine.renderer.get_monitors().iter().enumerate() {
+        log::info(&format!("Monitor {}: {} at {}x{}, {} video modes", i, monitor.name,
+            monitor.size[0], monitor.size[1], monitor.video_modes.len()));
+    }
 
     eventloop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
@@ -51,12 +64,19 @@ fn main()
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 window_id,
-            } if window_id == engine.renderer.get_display().gl_window().window().id() => *control_flow = ControlFlow::Exit,
+            } if Some(window_id) == engine.renderer.window_id() => *control_flow = ControlFlow::Exit,
             Event::WindowEvent { event, .. } => {
+                match &event {
+                    WindowEvent::Resized(size) => engine.window_resized([size.width, size.height]),
+                    WindowEvent::ScaleFactorChanged { scale_factor, new_inner_size } =>
+                        engine.scale_factor_changed(*scale_factor, [new_inner_size.width, new_inner_size.height]),
+                    _ => (),
+                }
+                let resolution = engine.renderer.get_window_size();
                 engine.input.handle_window_event(
                     &event,
-                    engine.cfg.resolution_x,
-                    engine.cfg.resolution_y
+                    resolution[0],
+                    resolution[1]
                 );
             },
             Event::DeviceEvent { event, .. } => engine.input.handle_device_event(&event),
@@ -65,7 +85,7 @@ fn main()
                     *control_flow = ControlFlow::Exit;
                 }
 
-                engine.renderer.get_display().gl_window().window().request_redraw();
+                engine.renderer.request_redraw();
             },
             Event::RedrawRequested(_window_id) => {
                 engine.draw_frame();